@@ -0,0 +1,98 @@
+use full_search_lose2::config::{self, parse, var, DEFAULT_MAX_CHUNK_SIZE};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+/// Settings which may be left unspecified.
+///
+/// The same structure is read from a config file, environment variables and command line,
+/// which are merged in order of precedence.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PartialConfig {
+    pub data_root: Option<PathBuf>,
+    pub num_processes: Option<usize>,
+    pub max_chunk_size: Option<usize>,
+    pub split: Option<Vec<usize>>,
+    pub min_doves: Option<usize>,
+    pub max_doves: Option<usize>,
+    pub del_tmp_files: Option<bool>,
+}
+
+impl PartialConfig {
+    /// Reads the config file specified by `path`, `TOKYODOVES_CONFIG` or the default name
+    /// in this order of precedence.
+    /// If none of them is given and the default file does not exist, nothing is specified.
+    pub fn from_file_or_default(path: Option<&Path>) -> anyhow::Result<Self> {
+        config::from_file_or_default(path)
+    }
+
+    /// Reads environment variables `TOKYODOVES_*`.
+    ///
+    /// `TOKYODOVES_SPLIT` is a comma-separated list of numbers of doves.
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            data_root: var("TOKYODOVES_DATA_ROOT")?.map(PathBuf::from),
+            num_processes: parse("TOKYODOVES_NUM_PROCESSES")?,
+            max_chunk_size: parse("TOKYODOVES_MAX_CHUNK_SIZE")?,
+            split: config::parse_list("TOKYODOVES_SPLIT")?,
+            min_doves: parse("TOKYODOVES_MIN_DOVES")?,
+            max_doves: parse("TOKYODOVES_MAX_DOVES")?,
+            del_tmp_files: parse("TOKYODOVES_DEL_TMP_FILES")?,
+        })
+    }
+
+    /// Fills settings unspecified in `self` with those in `lower`.
+    pub fn or(self, lower: Self) -> Self {
+        Self {
+            data_root: self.data_root.or(lower.data_root),
+            num_processes: self.num_processes.or(lower.num_processes),
+            max_chunk_size: self.max_chunk_size.or(lower.max_chunk_size),
+            split: self.split.or(lower.split),
+            min_doves: self.min_doves.or(lower.min_doves),
+            max_doves: self.max_doves.or(lower.max_doves),
+            del_tmp_files: self.del_tmp_files.or(lower.del_tmp_files),
+        }
+    }
+
+    /// Fills unspecified settings with defaults and validates them.
+    pub fn resolve(self) -> anyhow::Result<Config> {
+        let data_root = config::resolve_data_root(self.data_root)?;
+        let num_processes = config::resolve_parallelism(self.num_processes, "number of processes")?;
+        let max_chunk_size = config::resolve_positive(
+            self.max_chunk_size,
+            DEFAULT_MAX_CHUNK_SIZE,
+            "max chunk size",
+        )?;
+        let doves = config::resolve_doves(self.min_doves, self.max_doves)?;
+        let split = config::resolve_split(self.split)?;
+
+        Ok(Config {
+            data_root,
+            num_processes,
+            max_chunk_size,
+            split,
+            min_doves: *doves.start(),
+            max_doves: *doves.end(),
+            del_tmp_files: self.del_tmp_files.unwrap_or(true),
+        })
+    }
+}
+
+/// Validated settings of the analysis
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub data_root: PathBuf,
+    pub num_processes: usize,
+    pub max_chunk_size: usize,
+    pub split: Vec<usize>,
+    pub min_doves: usize,
+    pub max_doves: usize,
+    pub del_tmp_files: bool,
+}
+
+impl Config {
+    /// Range of numbers of doves to analyze
+    pub fn doves(&self) -> RangeInclusive<usize> {
+        self.min_doves..=self.max_doves
+    }
+}
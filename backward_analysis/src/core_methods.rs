@@ -1,28 +1,40 @@
 pub(crate) mod filter_maker;
 pub(crate) mod hashutil;
 
-use std::{collections::HashMap, ffi::OsString, path::PathBuf, sync::Arc};
+use std::{collections::HashMap, ffi::OsString, ops::RangeInclusive, path::PathBuf, sync::Arc};
 
 use filter_maker::*;
-use tokyodoves::{collections::*, *};
+use full_search_lose2::{
+    error::{self, Context, Error, Phase, Result, ResultExt},
+    storage,
+};
+use tokyodoves::{collections::*, game::GameRule, *};
 
 use crate::{distributed_path, dove_dir};
 
 // =====================================================================
 //  Helper Functions
 // =====================================================================
+/// Loads a file after verifying its checksum.
+fn load_file(path: impl AsRef<std::path::Path>) -> std::io::Result<BoardSet> {
+    let mut set =
+        BoardSet::with_capacity(BoardSet::required_capacity(storage::open_verified(&path)?));
+    set.load(storage::open_verified(&path)?)?;
+    Ok(set)
+}
+
 fn load_files(paths: &[impl AsRef<std::path::Path>]) -> std::io::Result<BoardSet> {
     println!("Estimating required capacity ...");
     let mut capacity = Capacity::new();
     for path in paths.iter() {
-        capacity += BoardSet::required_capacity(std::fs::File::open(path)?);
+        capacity += BoardSet::required_capacity(storage::open_verified(path)?);
     }
     let mut set = BoardSet::with_capacity(capacity);
     println!("Prepared a set with required capacity");
 
     for path in paths.iter() {
         println!("Loading win at {:?} ...", path.as_ref());
-        set.load(std::fs::File::open(path)?)?;
+        set.load(storage::open_verified(path)?)?;
         println!("Loaded win at {:?}", path.as_ref());
     }
     Ok(set)
@@ -38,25 +50,25 @@ where
     println!("Estimating required capacity ...");
     let mut capacity = Capacity::new();
     for path in paths.iter() {
-        capacity += BoardSet::required_capacity_filter(std::fs::File::open(path)?, &filter);
+        capacity += BoardSet::required_capacity_filter(storage::open_verified(path)?, &filter);
     }
     let mut set = BoardSet::with_capacity(capacity);
     println!("Prepared a set with required capacity");
 
     for path in paths.iter() {
         println!("Loading win at {:?} ...", path.as_ref());
-        set.load_filter(std::fs::File::open(path)?, &filter)?;
+        set.load_filter(storage::open_verified(path)?, &filter)?;
         println!("Loaded win at {:?}", path.as_ref());
     }
     Ok(set)
 }
 
-fn is_win1_or_finished(board: Board, player: Color) -> bool {
+fn is_win1_or_finished(board: Board, player: Color, rule: GameRule) -> bool {
     if !matches!(board.surrounded_status(), SurroundedStatus::None) {
         return true;
     }
     board
-        .legal_actions(player, true, true, true)
+        .legal_actions(player, true, true, *rule.is_remove_accepted())
         .into_iter()
         .map(|a1| board.perform_unchecked_copied(a1))
         .any(|b1| matches!(b1.surrounded_status(), SurroundedStatus::OneSide(p) if p != player))
@@ -71,7 +83,9 @@ pub fn trim_simply(
     win_paths: Vec<PathBuf>,
     num_processes: usize,
     parallel_chunk: usize,
-) -> anyhow::Result<()> {
+    num_doves: usize,
+) -> Result<()> {
+    let context = Context::new(Phase::TrimSimply).doves(num_doves);
     let src_dir = Arc::new(src_dir.as_ref().to_owned());
     let dst_dir = Arc::new(dst_dir.as_ref().to_owned());
     let win_paths = Arc::new(win_paths);
@@ -83,21 +97,23 @@ pub fn trim_simply(
             let src_dir = src_dir.clone();
             let dst_dir = dst_dir.clone();
             let win_paths = win_paths.clone();
+            let context = context.clone().shard(i);
             handlers.push(std::thread::spawn(move || {
                 println!("[Thread {i}] started");
                 let src_path = distributed_path(src_dir.as_ref(), i);
                 let dst_path = distributed_path(dst_dir.as_ref(), i);
 
-                let mut target = BoardSet::new_from_file(src_path).expect("new from file error");
-                thin_out_set(&mut target, win_paths.as_ref()).expect("trim error");
+                let mut target =
+                    load_file(&src_path).with_context(|| context.clone().path(&src_path))?;
+                thin_out_set(&mut target, win_paths.as_ref(), &context)?;
                 target.shrink_to_fit();
-                target
-                    .save(std::fs::File::create(dst_path).expect("create error"))
-                    .expect("save error");
+                storage::save_set(&target, &dst_path)
+                    .with_context(|| context.clone().path(&dst_path))?;
                 println!("[Thread {i}] finished");
+                Ok(())
             }));
         }
-        handlers.into_iter().for_each(|x| x.join().unwrap());
+        error::join_all(handlers, |i_add| context.clone().shard(i0 + i_add))?;
     }
 
     Ok(())
@@ -106,15 +122,18 @@ pub fn trim_simply(
 fn thin_out_set(
     target: &mut BoardSet,
     win_paths: &[impl AsRef<std::path::Path>],
-) -> anyhow::Result<()> {
+    context: &Context,
+) -> Result<()> {
     for path in win_paths.iter() {
-        for hash in LazyRawBoardLoader::new(std::fs::File::open(path)?) {
+        let file = storage::open_verified(path).with_context(|| context.clone().path(path))?;
+        for hash in LazyRawBoardLoader::new(file) {
             target.raw_mut().remove(&hash);
         }
     }
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn trim_on_action(
     src_dir: impl AsRef<std::path::Path>,
     dst_dir: impl AsRef<std::path::Path>,
@@ -123,7 +142,9 @@ pub fn trim_on_action(
     win_paths: &[impl AsRef<std::path::Path>],
     num_processes: usize,
     split_win_if_possible: bool,
-) -> anyhow::Result<()> {
+    rule: GameRule,
+) -> Result<()> {
+    let context = Context::new(Phase::TrimOnAction).doves(num_doves_from);
     let src_paths: Vec<std::path::PathBuf> = (0..num_processes)
         .map(|i| distributed_path(src_dir.as_ref(), i))
         .collect();
@@ -133,13 +154,16 @@ pub fn trim_on_action(
         num_doves_from,
         num_doves_to,
         split_win_if_possible,
+        rule,
+        &context,
     )?;
 
     println!("Start saving");
     for (i, trimmed) in trimmed_sets.into_iter().enumerate() {
         let dst_path = distributed_path(dst_dir.as_ref(), i);
         println!("Saving to {dst_path:?} ...");
-        trimmed.save(std::fs::File::create(&dst_path).expect("create error"))?;
+        storage::save_set(&trimmed, &dst_path)
+            .with_context(|| context.clone().shard(i).path(&dst_path))?;
         println!("Saved to {dst_path:?}");
     }
     println!("Saved all");
@@ -152,23 +176,29 @@ pub fn create_thinned_set(
     num_doves_from: usize,
     num_doves_to: usize,
     split_win_if_possible: bool,
-) -> anyhow::Result<Vec<BoardSet>> {
+    rule: GameRule,
+    context: &Context,
+) -> Result<Vec<BoardSet>> {
     if !(2..=12).contains(&num_doves_from)
         || !(2..=12).contains(&num_doves_to)
         || num_doves_from.abs_diff(num_doves_to) >= 2
     {
-        return Err(anyhow::anyhow!("invalid argument"));
+        return Err(Error::InvalidArgument {
+            context: context.clone(),
+            message: format!("cannot trim {num_doves_from} doves by {num_doves_to} doves"),
+        });
     }
 
     let contains_put = num_doves_from < num_doves_to;
     let contains_move = num_doves_from == num_doves_to;
-    let contains_remove = num_doves_from > num_doves_to;
+    let contains_remove = num_doves_from > num_doves_to && *rule.is_remove_accepted();
 
     fn parallel_run(
-        target: &mut Vec<BoardSet>,
+        target: &mut [BoardSet],
         src_paths: &[impl AsRef<std::path::Path>],
-        core_process: impl Fn(std::path::PathBuf) -> BoardSet + Send + Sync + 'static,
-    ) {
+        context: &Context,
+        core_process: impl Fn(&std::path::Path) -> std::io::Result<BoardSet> + Send + Sync + 'static,
+    ) -> Result<()> {
         assert_eq!(target.len(), src_paths.len());
 
         let core_process = Arc::new(core_process);
@@ -176,20 +206,22 @@ pub fn create_thinned_set(
         for (i, src_path) in src_paths.iter().enumerate() {
             let core_process = core_process.clone();
             let src_path = src_path.as_ref().to_owned();
+            let context = context.clone().shard(i);
             handlers.push(std::thread::spawn(move || {
                 println!("[Thread {i}] start");
-                let result = core_process(src_path);
+                let result = core_process(&src_path).with_context(|| context.path(&src_path));
                 println!("[Thread {i}] finish");
                 result
             }));
         }
-        let new_sets = handlers.into_iter().map(|x| x.join().unwrap());
+        let new_sets = error::join_all(handlers, |i| context.clone().shard(i))?;
         for (n, (s, new)) in target.iter_mut().zip(new_sets).enumerate() {
             println!("[Thread Main] Absorbing {n} ...");
             s.reserve(new.capacity());
             s.absorb(new);
             println!("[Thread Main] Absorbed {n}");
         }
+        Ok(())
     }
 
     let num_processes = src_paths.len();
@@ -198,9 +230,9 @@ pub fn create_thinned_set(
     match num_doves_to {
         x if !split_win_if_possible || matches!(x, 2..=8 | 12) => {
             println!("* [{num_doves_from} -> {num_doves_to}] ...");
-            let wins = load_files(win_paths)?;
+            let wins = load_files(win_paths).with_context(|| context.clone())?;
 
-            parallel_run(&mut trimmed_sets, src_paths, move |src_path| {
+            parallel_run(&mut trimmed_sets, src_paths, context, move |src_path| {
                 create_thinned_set_core(
                     src_path,
                     |_| true,
@@ -208,16 +240,17 @@ pub fn create_thinned_set(
                     contains_put,
                     contains_move,
                     contains_remove,
+                    rule,
                 )
-                .expect("thinning error")
-            });
+            })?;
         }
         9 => {
             for level in 0..3 {
                 println!("* [{num_doves_from} -> 9] level = {level} (max = 2)");
-                let wins = load_files_with_filter(win_paths, make_win_filter_9(level))?;
+                let wins = load_files_with_filter(win_paths, make_win_filter_9(level))
+                    .with_context(|| context.clone())?;
 
-                parallel_run(&mut trimmed_sets, src_paths, move |src_path| {
+                parallel_run(&mut trimmed_sets, src_paths, context, move |src_path| {
                     create_thinned_set_core(
                         src_path,
                         make_target_filter_9(level),
@@ -225,17 +258,18 @@ pub fn create_thinned_set(
                         contains_put,
                         contains_move,
                         contains_remove,
+                        rule,
                     )
-                    .expect("thinning error")
-                });
+                })?;
             }
         }
         10 => {
             for level in 0..=3 {
                 println!("* [{num_doves_from} -> 10] level = {level} (max = 3)");
-                let wins = load_files_with_filter(win_paths, make_win_filter_10(level))?;
+                let wins = load_files_with_filter(win_paths, make_win_filter_10(level))
+                    .with_context(|| context.clone())?;
 
-                parallel_run(&mut trimmed_sets, src_paths, move |src_path| {
+                parallel_run(&mut trimmed_sets, src_paths, context, move |src_path| {
                     create_thinned_set_core(
                         src_path,
                         make_target_filter_10(level),
@@ -243,17 +277,18 @@ pub fn create_thinned_set(
                         contains_put,
                         contains_move,
                         contains_remove,
+                        rule,
                     )
-                    .expect("thinning error")
-                });
+                })?;
             }
         }
         11 => {
             for level in 0..4 {
                 println!("* [{num_doves_from} -> 11] level = {level} (max = 3)");
-                let wins = load_files_with_filter(win_paths, make_win_filter_11(level))?;
+                let wins = load_files_with_filter(win_paths, make_win_filter_11(level))
+                    .with_context(|| context.clone())?;
 
-                parallel_run(&mut trimmed_sets, src_paths, move |src_path| {
+                parallel_run(&mut trimmed_sets, src_paths, context, move |src_path| {
                     create_thinned_set_core(
                         src_path,
                         make_target_filter_11(level),
@@ -261,9 +296,9 @@ pub fn create_thinned_set(
                         contains_put,
                         contains_move,
                         contains_remove,
+                        rule,
                     )
-                    .expect("thinning error")
-                });
+                })?;
             }
         }
         _ => unreachable!(),
@@ -278,6 +313,7 @@ fn create_thinned_set_core<FT>(
     contains_put: bool,
     contains_move: bool,
     contains_remove: bool,
+    rule: GameRule,
 ) -> std::io::Result<BoardSet>
 where
     FT: Fn(&u64) -> bool,
@@ -290,7 +326,7 @@ where
         use Color::*;
         for a1 in b0.legal_actions(Red, contains_put, contains_move, contains_remove) {
             let b1 = b0.perform_unchecked_copied(a1);
-            if is_win1_or_finished(b1, Green) {
+            if is_win1_or_finished(b1, Green, rule) {
                 continue;
             }
 
@@ -302,12 +338,12 @@ where
     };
     let mut set = BoardSet::new();
     set.raw_mut()
-        .load_filter(std::fs::File::open(src_path)?, filter)?;
+        .load_filter(storage::open_verified(src_path)?, filter)?;
     Ok(set)
 }
 
 fn count_doves_in_file(path: impl AsRef<std::path::Path>) -> std::io::Result<usize> {
-    Ok(LazyBoardLoader::new(std::fs::File::open(path)?).count())
+    Ok(LazyBoardLoader::new(storage::open_verified(path)?).count())
 }
 
 fn count_doves_in_dir(root: impl AsRef<std::path::Path>) -> std::io::Result<usize> {
@@ -333,7 +369,7 @@ pub fn redistribute(
     num_result_files: usize,
 ) -> anyhow::Result<()> {
     let total = count_doves_in_dir(&src_dir)?;
-    let chunk = (total + num_result_files - 1) / num_result_files;
+    let chunk = total.div_ceil(num_result_files);
     println!("total = {total}");
     println!("chunk = {chunk}");
 
@@ -344,7 +380,7 @@ pub fn redistribute(
         () => {
             let dst_path = distributed_path(dst_dir.as_ref(), file_idx);
             println!("Saving to {dst_path:?} ...");
-            set.save(std::fs::File::create(&dst_path)?)?;
+            storage::save_set(&set, &dst_path)?;
             println!("Saved to {dst_path:?}");
             set.clear();
             file_idx += 1;
@@ -358,7 +394,7 @@ pub fn redistribute(
         }
 
         println!("Loading {path:?} ...");
-        let mut full_set = BoardSet::new_from_file(&path)?;
+        let mut full_set = load_file(&path)?;
         println!("Loaded {path:?}");
 
         while !full_set.is_empty() {
@@ -380,7 +416,7 @@ pub fn redistribute(
 }
 
 fn split_set_into(mut set: BoardSet, num: usize) -> Vec<BoardSet> {
-    let chunk = (set.len() + num - 1) / num;
+    let chunk = set.len().div_ceil(num);
     let mut set_vec = Vec::with_capacity(num);
     for _ in 0..num {
         let tmp: BoardSet;
@@ -396,10 +432,14 @@ pub fn backstep(
     num_doves: usize,
     num_processes: usize,
     max_chunk_size: usize,
-) -> anyhow::Result<()> {
-    println!("Loading {:?} ...", src_path.as_ref());
-    let mut full_set = BoardSet::new_from_file(&src_path)?;
-    println!("Loaded {:?}", src_path.as_ref());
+    doves: RangeInclusive<usize>,
+    rule: GameRule,
+) -> Result<()> {
+    let context = Context::new(Phase::Backstep).doves(num_doves);
+    let src_path = src_path.as_ref();
+    println!("Loading {src_path:?} ...");
+    let mut full_set = load_file(src_path).with_context(|| context.clone().path(src_path))?;
+    println!("Loaded {src_path:?}");
 
     let mut idx_chunk = 0;
     let mut load_next = true;
@@ -415,16 +455,17 @@ pub fn backstep(
         let mut handlers = Vec::new();
         for i in 0..num_processes {
             let set_vec = set_vec.clone();
+            let doves = doves.clone();
             handlers.push(std::thread::spawn(move || {
                 println!("[Thread {i}] started");
-                let num_to_set = backstep_core(set_vec[i].iter(), num_doves);
+                let num_to_set = backstep_core(set_vec[i].iter(), num_doves, doves, rule);
                 println!("[Thread {i}] finished");
-                num_to_set
+                Ok(num_to_set)
             }));
         }
 
         let vec_of_num_to_set: Vec<HashMap<usize, BoardSet>> =
-            handlers.into_iter().map(|x| x.join().unwrap()).collect();
+            error::join_all(handlers, |i| context.clone().shard(i))?;
 
         println!("[Thread Main] calculating capacity ...");
         let mut capacity_map = HashMap::new();
@@ -450,7 +491,7 @@ pub fn backstep(
         for (num, set) in num_to_set_all {
             let dst_path = dove_dir(dst_dir.as_ref(), num)
                 .join(format!("from_{num_doves:0>2}_{idx_chunk:0>4}.tdl"));
-            set.save(std::fs::File::create(dst_path)?)?;
+            storage::save_set(&set, &dst_path).with_context(|| context.clone().path(&dst_path))?;
         }
         idx_chunk += 1;
     }
@@ -460,31 +501,38 @@ pub fn backstep(
 fn backstep_core(
     original: impl Iterator<Item = Board>,
     num_doves: usize,
+    doves: RangeInclusive<usize>,
+    rule: GameRule,
 ) -> HashMap<usize, BoardSet> {
     use Color::*;
     let mut num_to_set = HashMap::new();
     for n in (num_doves - 1).max(2)..=(num_doves + 1).min(12) {
-        num_to_set.insert(n, BoardSet::new());
+        if doves.contains(&n) {
+            num_to_set.insert(n, BoardSet::new());
+        }
     }
 
     for b0 in original {
-        for a1 in b0.legal_actions_bwd(Green, true, true, true) {
+        for a1 in b0.legal_actions_bwd(Green, true, true, *rule.is_remove_accepted()) {
             let b1 = b0.perform_unchecked_copied(a1);
-            if is_win1_or_finished(b1, Green) {
+            if is_win1_or_finished(b1, Green, rule) {
                 continue;
             }
             let n1 = b1.count_doves_on_field();
-            let h1 = b1.to_invariant_u64(Green);
-            num_to_set.get_mut(&n1).unwrap().raw_mut().insert(h1);
+            let Some(set) = num_to_set.get_mut(&n1) else {
+                continue;
+            };
+            set.raw_mut().insert(b1.to_invariant_u64(Green));
         }
     }
     num_to_set
 }
 
+/// Gathers all boards in files at `src_dir` into `dst_path` and returns the number of them.
 pub fn gather(
     src_dir: impl AsRef<std::path::Path>,
     dst_path: impl AsRef<std::path::Path>,
-) -> std::io::Result<()> {
+) -> std::io::Result<usize> {
     let entries = std::fs::read_dir(src_dir)?;
     let mut paths = Vec::new();
     for entry in entries {
@@ -496,16 +544,87 @@ pub fn gather(
     }
     let mut capacity = Capacity::new();
     for path in paths.iter() {
-        capacity += BoardSet::required_capacity(std::fs::File::open(path)?);
+        capacity += BoardSet::required_capacity(storage::open_verified(path)?);
     }
     let mut set = BoardSet::with_capacity(capacity);
     for path in paths {
         println!("Loading {path:?} ...");
-        set.load(std::fs::File::open(&path)?)?;
+        set.load(storage::open_verified(&path)?)?;
         println!("Loaded {path:?}");
     }
     println!("Saving to {:?} ...", dst_path.as_ref());
-    set.save(std::fs::File::create(&dst_path)?)?;
+    storage::save_set(&set, &dst_path)?;
     println!("Saved to {:?}", dst_path.as_ref());
-    Ok(())
+    Ok(set.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use full_search_lose2::testing::{sample_set, TempDir};
+    use std::collections::HashSet;
+
+    fn hashes(set: &BoardSet) -> HashSet<u64> {
+        set.raw().iter().collect()
+    }
+
+    #[test]
+    fn gathered_boards_are_loaded_back() {
+        let dir = TempDir::new();
+        let src_dir = dir.join("src");
+        std::fs::create_dir_all(&src_dir).unwrap();
+        let first = sample_set();
+        let mut second = BoardSet::new();
+        second.insert(Board::new());
+        second.insert(
+            BoardBuilder::from_u16_bits([[1, 0, 0, 0, 0, 0], [2, 0, 0, 0, 0, 0]])
+                .build()
+                .unwrap(),
+        );
+        storage::save_set(&first, distributed_path(&src_dir, 0)).unwrap();
+        storage::save_set(&second, distributed_path(&src_dir, 1)).unwrap();
+
+        let dst_path = dir.join("gathered.tdl");
+        let num_boards = gather(&src_dir, &dst_path).unwrap();
+
+        let expected: HashSet<u64> = hashes(&first).union(&hashes(&second)).copied().collect();
+        assert_eq!(num_boards, expected.len());
+        assert_eq!(hashes(&load_file(&dst_path).unwrap()), expected);
+    }
+
+    #[test]
+    fn corrupted_file_is_not_gathered() {
+        let dir = TempDir::new();
+        let src_dir = dir.join("src");
+        std::fs::create_dir_all(&src_dir).unwrap();
+        let path = distributed_path(&src_dir, 0);
+        storage::save_set(&sample_set(), &path).unwrap();
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[0] ^= 1;
+        std::fs::write(&path, bytes).unwrap();
+
+        assert!(gather(&src_dir, dir.join("gathered.tdl")).is_err());
+    }
+
+    #[test]
+    fn missing_shard_is_reported_with_its_context() {
+        let dir = TempDir::new();
+        let src_dir = dir.join("src");
+        let dst_dir = dir.join("dst");
+        std::fs::create_dir_all(&src_dir).unwrap();
+        std::fs::create_dir_all(&dst_dir).unwrap();
+        storage::save_set(&sample_set(), distributed_path(&src_dir, 0)).unwrap();
+
+        let err = trim_simply(&src_dir, &dst_dir, Vec::new(), 2, 2, 5).unwrap_err();
+        let Error::Io { context, .. } = err else {
+            panic!("unexpected error: {err}");
+        };
+        assert_eq!(
+            context,
+            Context::new(Phase::TrimSimply)
+                .doves(5)
+                .shard(1)
+                .path(distributed_path(&src_dir, 1))
+        );
+    }
 }
@@ -37,7 +37,7 @@ pub fn make_win_filter_10(level: u32) -> impl Fn(&u64) -> bool {
             }
             aligned
         };
-        (right_aligned.trailing_ones() + 1) / 2 == level
+        right_aligned.trailing_ones().div_ceil(2) == level
     }
 }
 
@@ -57,7 +57,7 @@ pub fn make_target_filter_10(level: u32) -> impl Fn(&u64) -> bool {
             }
             aligned
         };
-        (right_aligned.trailing_ones() + 1) / 2 == level
+        right_aligned.trailing_ones().div_ceil(2) == level
     }
 }
 
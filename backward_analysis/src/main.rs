@@ -1,28 +1,47 @@
+use config::{Config, PartialConfig};
+use full_search_lose2::{
+    manifest::Manifest,
+    rule::{Namespace, RuleVariant},
+    storage,
+};
 use std::{
     ffi::OsString,
     path::{Path, PathBuf},
 };
+use tokyodoves::game::GameRule;
+pub mod config;
 pub mod core_methods;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 struct PathFactory<P: AsRef<Path>> {
     root: P,
+    namespace: Namespace,
 }
 
 impl<P> PathFactory<P>
 where
     P: AsRef<Path>,
 {
-    fn new(root: P) -> Self {
-        Self { root }
+    fn new(root: P, namespace: Namespace) -> Self {
+        Self { root, namespace }
     }
 
-    fn num_dir(&self, num_step: usize) -> PathBuf {
+    /// Directory where all the steps in the namespace are stored
+    fn namespace_dir(&self) -> PathBuf {
+        self.root.as_ref().join(self.namespace.to_string())
+    }
+
+    /// Directory of a step in the layout before namespaces are introduced
+    fn legacy_num_dir(&self, num_step: usize) -> PathBuf {
         self.root.as_ref().join(format!("{num_step:0>4}"))
     }
 
+    fn num_dir(&self, num_step: usize) -> PathBuf {
+        self.namespace_dir().join(format!("{num_step:0>4}"))
+    }
+
     fn num_tmp_dir(&self, num_step: usize) -> PathBuf {
-        self.root.as_ref().join(format!("{num_step:0>4}_tmp"))
+        self.namespace_dir().join(format!("{num_step:0>4}_tmp"))
     }
 
     fn backstepped(&self, num_step: usize) -> PathBuf {
@@ -49,10 +68,35 @@ where
         self.num_tmp_dir(num_step).join("trimmed_remove")
     }
 
-    fn win_paths(&self, num_step_ceil: usize, num_doves: usize) -> Vec<PathBuf> {
+    fn table_path(&self, num_step: usize, num_doves: usize) -> PathBuf {
+        self.num_dir(num_step).join(format!("{num_doves:0>2}.tdl"))
+    }
+
+    /// Saves the manifest of a table, which must be already saved.
+    fn save_manifest(
+        &self,
+        num_step: usize,
+        num_doves: usize,
+        num_boards: usize,
+    ) -> anyhow::Result<()> {
+        let path = self.table_path(num_step, num_doves);
+        Manifest::describe(&path, num_step, num_doves, self.namespace, num_boards)?.save(&path)
+    }
+
+    /// Returns the path of a table after checking its manifest.
+    ///
+    /// Step 2 is imported from the full search, which has no namespace.
+    fn verified_table_path(&self, num_step: usize, num_doves: usize) -> anyhow::Result<PathBuf> {
+        let path = self.table_path(num_step, num_doves);
+        let namespace = (num_step != 2).then_some(self.namespace);
+        Manifest::verify(&path, num_step, num_doves, self.namespace.rule, namespace)?;
+        Ok(path)
+    }
+
+    fn win_paths(&self, num_step_ceil: usize, num_doves: usize) -> anyhow::Result<Vec<PathBuf>> {
         (3..=num_step_ceil)
             .step_by(2)
-            .map(|n| self.num_dir(n).join(format!("{num_doves:0>2}.tdl")))
+            .map(|n| self.verified_table_path(n, num_doves))
             .collect()
     }
 }
@@ -68,32 +112,40 @@ fn distributed_path(parent: impl AsRef<Path>, file_idx: usize) -> PathBuf {
 fn x_to_x_common<P>(
     factory: &PathFactory<P>,
     num_from: usize,
-    num_processes: usize,
-    del_tmp_files: bool,
+    config: &Config,
+    rule: GameRule,
 ) -> anyhow::Result<()>
 where
     P: AsRef<Path>,
 {
     let num_to = num_from + 1;
+    let num_processes = config.num_processes;
+    let del_tmp_files = config.del_tmp_files;
 
     // --- BackStep ---
     println!("### PHASE: BACKSTEP ###");
-    for num_doves in 2..=12 {
+    for num_doves in config.doves() {
         std::fs::create_dir_all(dove_dir(factory.backstepped(num_to), num_doves))?;
     }
 
-    for num_doves in 2..=12 {
+    for num_doves in config.doves() {
         println!("=== num_doves={num_doves} ===");
-        let src_path = factory
-            .num_dir(num_from)
-            .join(format!("{num_doves:0>2}.tdl"));
+        let src_path = factory.verified_table_path(num_from, num_doves)?;
         let dst_dir = factory.backstepped(num_to);
-        core_methods::backstep(src_path, dst_dir, num_doves, num_processes, 400_000_000)?;
+        core_methods::backstep(
+            src_path,
+            dst_dir,
+            num_doves,
+            num_processes,
+            config.max_chunk_size,
+            config.doves(),
+            rule,
+        )?;
     }
 
     // --- Redistribute ---
     println!("### PHASE: REDISTRIBUTE ###");
-    for num_doves in 2..=12 {
+    for num_doves in config.doves() {
         println!("=== num_doves={num_doves} ===");
         let src_dir = dove_dir(factory.backstepped(num_to), num_doves);
         let dst_dir = dove_dir(factory.redistributed(num_to), num_doves);
@@ -109,13 +161,20 @@ where
 
     // --- Trim Simple ---
     println!("### PHASE: TRIM SIMPLE ###");
-    for num_doves in 2..=12 {
+    for num_doves in config.doves() {
         println!("=== num_doves={num_doves} ===");
         let src_dir = dove_dir(factory.redistributed(num_to), num_doves);
         let dst_dir = dove_dir(factory.trimmed_simply(num_to), num_doves);
         std::fs::create_dir_all(&dst_dir)?;
-        let win_paths = factory.win_paths(num_from, num_doves);
-        core_methods::trim_simply(&src_dir, dst_dir, win_paths, num_processes, num_processes)?;
+        let win_paths = factory.win_paths(num_from, num_doves)?;
+        core_methods::trim_simply(
+            &src_dir,
+            dst_dir,
+            win_paths,
+            num_processes,
+            num_processes,
+            num_doves,
+        )?;
         if del_tmp_files {
             std::fs::remove_dir_all(src_dir)?;
         }
@@ -130,17 +189,19 @@ where
 fn win_to_lose<P>(
     factory: &PathFactory<P>,
     num_from: usize,
-    num_processes: usize,
-    del_tmp_files: bool,
-    nums_doves_to_split_win_if_possible: &[usize],
+    config: &Config,
+    rule: GameRule,
 ) -> anyhow::Result<()>
 where
     P: AsRef<Path>,
 {
     let num_to = num_from + 1;
+    let num_processes = config.num_processes;
+    let del_tmp_files = config.del_tmp_files;
+    let nums_doves_to_split_win_if_possible = &config.split;
 
     // x_to_x_common
-    // x_to_x_common(factory, num_from, num_processes, del_tmp_files)?;
+    // x_to_x_common(factory, num_from, config, rule)?;
 
     // Trim Move
     println!("### PHASE: TRIM MOVE ###");
@@ -158,6 +219,7 @@ where
     //         &win_paths,
     //         num_processes,
     //         nums_doves_to_split_win_if_possible.contains(&num_doves),
+    //         rule,
     //     )?;
     // }
     // if del_tmp_files {
@@ -166,7 +228,7 @@ where
 
     // Trim Put
     println!("### PHASE: TRIM PUT ###");
-    for num_doves in 2..=12 {
+    for num_doves in config.doves() {
         println!("=== num_doves={num_doves} ===");
         if num_doves <= 7 {
             println!("Skipped");
@@ -183,12 +245,16 @@ where
                     continue;
                 }
                 let dst_path = dst_dir.join(src_path.file_name().unwrap());
-                std::fs::copy(src_path, dst_path)?;
+                storage::copy(src_path, dst_path)?;
             }
             continue;
         }
 
-        let win_paths = factory.win_paths(num_to, num_doves + 1);
+        // Wins out of the range are not computed and regarded as nonexistent
+        let win_paths = match config.doves().contains(&(num_doves + 1)) {
+            true => factory.win_paths(num_to, num_doves + 1)?,
+            false => Vec::new(),
+        };
         core_methods::trim_on_action(
             src_dir,
             dst_dir,
@@ -197,6 +263,7 @@ where
             &win_paths,
             num_processes,
             nums_doves_to_split_win_if_possible.contains(&(num_doves + 1)),
+            rule,
         )?;
     }
     if del_tmp_files {
@@ -205,7 +272,7 @@ where
 
     // Trim Remove
     println!("### PHASE: TRIM REMOVE ###");
-    for num_doves in 2..=12 {
+    for num_doves in config.doves() {
         println!("=== num_doves={num_doves} ===");
         let src_dir = dove_dir(factory.trimmed_put(num_to), num_doves);
         let dst_dir = dove_dir(factory.trimmed_remove(num_to), num_doves);
@@ -218,12 +285,15 @@ where
                     continue;
                 }
                 let dst_path = dst_dir.join(src_path.file_name().unwrap());
-                std::fs::copy(src_path, dst_path)?;
+                storage::copy(src_path, dst_path)?;
             }
             continue;
         }
 
-        let win_paths = factory.win_paths(num_to, num_doves - 1);
+        let win_paths = match config.doves().contains(&(num_doves - 1)) {
+            true => factory.win_paths(num_to, num_doves - 1)?,
+            false => Vec::new(),
+        };
         core_methods::trim_on_action(
             src_dir,
             dst_dir,
@@ -232,6 +302,7 @@ where
             &win_paths,
             num_processes,
             nums_doves_to_split_win_if_possible.contains(&(num_doves - 1)),
+            rule,
         )?;
     }
     if del_tmp_files {
@@ -241,12 +312,13 @@ where
     // Gather
     println!("### PHASE: GATHER ###");
     std::fs::create_dir_all(factory.num_dir(num_to))?;
-    for num_doves in 2..=12 {
+    for num_doves in config.doves() {
         println!("=== num_doves={num_doves} ===");
-        core_methods::gather(
+        let num_boards = core_methods::gather(
             dove_dir(factory.trimmed_remove(num_to), num_doves),
-            factory.num_dir(num_to).join(format!("{num_doves:0>2}.tdl")),
+            factory.table_path(num_to, num_doves),
         )?;
+        factory.save_manifest(num_to, num_doves, num_boards)?;
     }
     if del_tmp_files {
         std::fs::remove_dir_all(factory.num_tmp_dir(num_to))?;
@@ -257,26 +329,28 @@ where
 fn lose_to_win<P>(
     factory: &PathFactory<P>,
     num_from: usize,
-    num_processes: usize,
-    del_tmp_files: bool,
+    config: &Config,
+    rule: GameRule,
 ) -> anyhow::Result<()>
 where
     P: AsRef<Path>,
 {
     let num_to = num_from + 1;
+    let del_tmp_files = config.del_tmp_files;
 
     // x_to_x_common
-    x_to_x_common(factory, num_from, num_processes, del_tmp_files)?;
+    x_to_x_common(factory, num_from, config, rule)?;
 
     // Gather
     println!("### PHASE: GATHER ###");
     std::fs::create_dir_all(factory.num_dir(num_to))?;
-    for num_doves in 2..=12 {
+    for num_doves in config.doves() {
         println!("=== num_doves={num_doves} ===");
-        core_methods::gather(
+        let num_boards = core_methods::gather(
             dove_dir(factory.trimmed_simply(num_to), num_doves),
-            factory.num_dir(num_to).join(format!("{num_doves:0>2}.tdl")),
+            factory.table_path(num_to, num_doves),
         )?;
+        factory.save_manifest(num_to, num_doves, num_boards)?;
     }
 
     if del_tmp_files {
@@ -286,27 +360,41 @@ where
 }
 
 fn advance_one_step(
-    root: impl AsRef<Path>,
     num_from: usize,
-    num_processes: usize,
-    del_tmp_files: bool,
-    nums_doves_to_split_win_if_possible: &[usize],
+    config: &Config,
+    rule_variant: RuleVariant,
 ) -> anyhow::Result<()> {
-    let factory = PathFactory::new(root);
+    let namespace = Namespace::new(rule_variant);
+    let factory = PathFactory::new(&config.data_root, namespace);
+    if num_from < 2 {
+        return Err(anyhow::anyhow!("invalid num_from"));
+    }
+    if !factory.num_dir(num_from).exists() && factory.legacy_num_dir(num_from).exists() {
+        return Err(anyhow::anyhow!(
+            "{:?} is in the layout without namespaces; move the steps into {:?}",
+            factory.legacy_num_dir(num_from),
+            factory.namespace_dir()
+        ));
+    }
+
+    // Step 2 is imported from the full search, which records only the rule.
+    // All the other steps read in this step must be in the same namespace.
     match num_from {
-        0 | 1 => return Err(anyhow::anyhow!("invalid num_from")),
-        n => match n % 2 {
-            0 => lose_to_win(&factory, num_from, num_processes, del_tmp_files)?,
-            1 => win_to_lose(
-                &factory,
-                num_from,
-                num_processes,
-                del_tmp_files,
-                nums_doves_to_split_win_if_possible,
-            )?,
-            _ => unreachable!(),
-        },
+        2 => rule_variant.check(factory.num_dir(num_from))?,
+        _ => namespace.check(factory.num_dir(num_from))?,
+    }
+    for num_step in (3..num_from).step_by(2) {
+        namespace.check(factory.num_dir(num_step))?;
+    }
+
+    let rule = rule_variant.to_game_rule();
+    match num_from % 2 {
+        0 => lose_to_win(&factory, num_from, config, rule)?,
+        1 => win_to_lose(&factory, num_from, config, rule)?,
+        _ => unreachable!(),
     }
+    rule_variant.record(factory.num_dir(num_from + 1))?;
+    namespace.record(factory.num_dir(num_from + 1))?;
     println!("Finished all process!");
     Ok(())
 }
@@ -319,31 +407,70 @@ fn advance_one_step(
     about = "Analyze the Tokyodoves Boards"
 )]
 struct Args {
+    /// Config file in TOML [default: $TOKYODOVES_CONFIG or ./backward_analysis.toml if exists]
+    #[clap(short = 'c', long)]
+    config: Option<PathBuf>,
+
+    /// Root directory of data, overriding `data_root` in config
     #[clap(short = 's', long)]
-    src_dir: Option<String>,
+    src_dir: Option<PathBuf>,
 
     #[clap(short = 'n', long)]
     num_doves: usize,
 
     #[clap(short = 'p', long)]
-    num_processes: usize,
+    num_processes: Option<usize>,
+
+    /// Maximum number of boards backstepped at once
+    #[clap(long)]
+    max_chunk_size: Option<usize>,
 
     #[clap(long = "split", num_args = 0..=11)]
-    split_nums_doves: Vec<usize>,
+    split_nums_doves: Option<Vec<usize>>,
+
+    /// Minimum number of doves to analyze
+    #[clap(long)]
+    min_doves: Option<usize>,
+
+    /// Maximum number of doves to analyze
+    #[clap(long)]
+    max_doves: Option<usize>,
 
     #[clap(long = "del_tmp_files")]
     del_tmp_files: Option<bool>,
+
+    /// Rule of the game, which must agree with the one recorded in the source steps
+    #[clap(long, value_enum, default_value_t = RuleVariant::Remove)]
+    rule: RuleVariant,
 }
 
 fn main() -> anyhow::Result<()> {
     use clap::Parser;
 
     let arg: Args = Args::parse();
-    let root = PathBuf::from(arg.src_dir.unwrap_or(r"...".to_owned()));
-    let num_from = arg.num_doves;
-    let num_processes = arg.num_processes;
-    let split = arg.split_nums_doves;
-    let del_tmp_files = arg.del_tmp_files.unwrap_or(true);
-    advance_one_step(root, num_from, num_processes, del_tmp_files, &split)?;
+
+    // Command line > environment variables > config file > defaults
+    let from_args = PartialConfig {
+        data_root: arg.src_dir,
+        num_processes: arg.num_processes,
+        max_chunk_size: arg.max_chunk_size,
+        split: arg.split_nums_doves,
+        min_doves: arg.min_doves,
+        max_doves: arg.max_doves,
+        del_tmp_files: arg.del_tmp_files,
+    };
+    let config = from_args
+        .or(PartialConfig::from_env()?)
+        .or(PartialConfig::from_file_or_default(arg.config.as_deref())?)
+        .resolve()?;
+    if config.doves() != (2..=12) {
+        println!(
+            "Warning: boards with doves out of {:?} are regarded as nonexistent, \
+             so some results near the bounds may be missed",
+            config.doves()
+        );
+    }
+
+    advance_one_step(arg.num_doves, &config, arg.rule)?;
     Ok(())
 }
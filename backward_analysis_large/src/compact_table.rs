@@ -0,0 +1,495 @@
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use memmap2::Mmap;
+
+use full_search_lose2::storage;
+
+/// Magic number at the head of a compact table
+const MAGIC: &[u8; 8] = b"TDCOMPCT";
+
+/// Bytes of the magic number, the number of hashes and the length of blocks
+const HEADER_LEN: usize = 24;
+
+/// Number of hashes in a block, which is decoded at once to look up a hash
+const BLOCK_LEN: usize = 1024;
+
+// **********************************************************
+//  Encoding
+// **********************************************************
+fn write_varint(w: &mut impl Write, mut value: u64) -> std::io::Result<()> {
+    while value >= 0x80 {
+        w.write_all(&[(value as u8) | 0x80])?;
+        value >>= 7;
+    }
+    w.write_all(&[value as u8])
+}
+
+fn read_varint(r: &mut impl Read) -> std::io::Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0];
+        r.read_exact(&mut byte)?;
+        value |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] < 0x80 {
+            return Ok(value);
+        }
+    }
+    Err(invalid_data("too long varint"))
+}
+
+fn invalid_data(message: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("broken compact table: {message}"),
+    )
+}
+
+/// Position of the flags telling which of 12 doves are on the field in a hash of a board,
+/// below which the position of each dove takes 4 bits
+const FLAGS_SHIFT: u32 = 48;
+
+/// Packs the positions of doves on the field in `hash` into its lowest bits keeping their order,
+/// or returns `None` if `hash` is not laid out as a hash of a board.
+fn pack(hash: u64) -> Option<u64> {
+    let flags = hash >> FLAGS_SHIFT;
+    if flags >> 12 != 0 {
+        return None;
+    }
+    let mut packed = 0;
+    for i in (0..12).rev() {
+        let position = (hash >> (4 * i)) & 0xf;
+        if (flags >> i) & 1 == 1 {
+            packed = (packed << 4) | position;
+        } else if position != 0 {
+            return None;
+        }
+    }
+    Some(packed)
+}
+
+/// Inverse of [`pack`] for the doves on the field told by `flags`
+fn unpack(flags: u64, mut packed: u64) -> Option<u64> {
+    let mut hash = flags << FLAGS_SHIFT;
+    for i in 0..12 {
+        if (flags >> i) & 1 == 1 {
+            hash |= (packed & 0xf) << (4 * i);
+            packed >>= 4;
+        }
+    }
+    (packed == 0).then_some(hash)
+}
+
+/// Writes the difference of `hash` from `prev` which is less than it.
+///
+/// If both are hashes of boards with the same doves on the field,
+/// the difference of their packed positions is written, which is never 0.
+/// Otherwise 0 is written followed by the difference of themselves.
+fn write_delta(w: &mut impl Write, prev: u64, hash: u64) -> std::io::Result<()> {
+    match (pack(prev), pack(hash)) {
+        (Some(prev_packed), Some(packed)) if prev >> FLAGS_SHIFT == hash >> FLAGS_SHIFT => {
+            write_varint(w, packed - prev_packed)
+        }
+        _ => {
+            write_varint(w, 0)?;
+            write_varint(w, hash - prev)
+        }
+    }
+}
+
+/// Reads the hash following `prev` written by [`write_delta`].
+fn read_delta(r: &mut impl Read, prev: u64) -> std::io::Result<u64> {
+    let hash = match read_varint(r)? {
+        0 => prev.checked_add(read_varint(r)?),
+        delta => pack(prev)
+            .and_then(|packed| packed.checked_add(delta))
+            .and_then(|packed| unpack(prev >> FLAGS_SHIFT, packed)),
+    };
+    hash.ok_or_else(|| invalid_data("wrong difference"))
+}
+
+/// Saves `len` hashes given by `next` in ascending order as a compact table
+/// atomically with its checksum, which needs no hashes in memory but the index of blocks.
+///
+/// The file consists of the header, blocks and the index of blocks, all of which are in little endian.
+/// Each block holds [`BLOCK_LEN`] hashes, the first of which is stored as is,
+/// followed by the differences from the previous ones in LEB128 by [`write_delta`].
+/// The index holds the first hash and the offset of each block,
+/// and the offset of the index itself is at the end of the file.
+///
+/// Hashes of boards with the same doves on the field are next to each other,
+/// and the differences of their positions packed without absent doves mostly take a byte,
+/// so tables of steps with 6 doves and hundreds of boards or more are 3.0 to 3.7 times smaller than in `.tdl`.
+pub fn save_stream<F>(len: usize, mut next: F, path: impl AsRef<Path>) -> std::io::Result<()>
+where
+    F: FnMut() -> std::io::Result<Option<u64>>,
+{
+    storage::save_with(path, |w| {
+        let mut w = CountingWriter {
+            inner: BufWriter::new(w),
+            count: 0,
+        };
+        w.write_all(MAGIC)?;
+        w.write_all(&(len as u64).to_le_bytes())?;
+        w.write_all(&(BLOCK_LEN as u64).to_le_bytes())?;
+
+        let mut index = Vec::new();
+        let mut count = 0;
+        let mut prev = 0;
+        while let Some(hash) = next()? {
+            if count > 0 && hash <= prev {
+                return Err(invalid_data("hashes are not in ascending order"));
+            }
+            if count % BLOCK_LEN == 0 {
+                index.push((hash, w.count));
+                w.write_all(&hash.to_le_bytes())?;
+            } else {
+                write_delta(&mut w, prev, hash)?;
+            }
+            prev = hash;
+            count += 1;
+        }
+        if count != len {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{count} hashes are given to a compact table of {len}"),
+            ));
+        }
+
+        let index_offset = w.count;
+        for (first, offset) in index {
+            w.write_all(&first.to_le_bytes())?;
+            w.write_all(&offset.to_le_bytes())?;
+        }
+        w.write_all(&index_offset.to_le_bytes())?;
+        w.inner.flush()
+    })
+}
+
+/// Writer counting bytes written through it
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.count += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// **********************************************************
+//  Streaming
+// **********************************************************
+/// Reader of hashes in a compact table in ascending order
+#[derive(Debug)]
+pub struct CompactReader<R> {
+    reader: R,
+    remaining: usize,
+    block_len: usize,
+    pos_in_block: usize,
+    prev: u64,
+}
+
+impl<R: Read> CompactReader<R> {
+    pub fn new(mut reader: R) -> std::io::Result<Self> {
+        let mut header = [0; HEADER_LEN];
+        reader.read_exact(&mut header)?;
+        if &header[..8] != MAGIC {
+            return Err(invalid_data("wrong header"));
+        }
+        let len = u64::from_le_bytes(header[8..16].try_into().unwrap()) as usize;
+        let block_len = u64::from_le_bytes(header[16..24].try_into().unwrap()) as usize;
+        if block_len == 0 {
+            return Err(invalid_data("empty blocks"));
+        }
+        Ok(Self {
+            reader,
+            remaining: len,
+            block_len,
+            pos_in_block: 0,
+            prev: 0,
+        })
+    }
+
+    /// Number of hashes not read yet
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    pub fn try_next(&mut self) -> std::io::Result<Option<u64>> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        let hash = if self.pos_in_block == 0 {
+            let mut buf = [0; 8];
+            self.reader.read_exact(&mut buf)?;
+            u64::from_le_bytes(buf)
+        } else {
+            read_delta(&mut self.reader, self.prev)?
+        };
+        self.prev = hash;
+        self.pos_in_block = (self.pos_in_block + 1) % self.block_len;
+        self.remaining -= 1;
+        Ok(Some(hash))
+    }
+}
+
+/// Opens a compact table for streaming after verifying its checksum.
+pub fn open_reader(
+    path: impl AsRef<Path>,
+) -> std::io::Result<CompactReader<BufReader<std::fs::File>>> {
+    CompactReader::new(BufReader::new(storage::open_verified(path)?))
+}
+
+// **********************************************************
+//  Lookup
+// **********************************************************
+/// Compact table memory-mapped for lookups,
+/// where only the index of blocks is kept in memory.
+#[derive(Debug)]
+pub struct CompactTable {
+    mmap: Mmap,
+    len: usize,
+    block_len: usize,
+    /// First hash and offset of each block
+    index: Vec<(u64, usize)>,
+}
+
+impl CompactTable {
+    /// Maps a table into memory after verifying its checksum.
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let file = storage::open_verified(path)?;
+        // SAFETY: tables are never modified in place, but replaced by renaming.
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        let reader = CompactReader::new(&mmap[..])?;
+        let (len, block_len) = (reader.remaining(), reader.block_len);
+        let read_u64 = |start: usize| -> std::io::Result<u64> {
+            mmap.get(start..start + 8)
+                .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+                .ok_or_else(|| invalid_data("truncated"))
+        };
+        let index_offset = read_u64(mmap.len().saturating_sub(8))? as usize;
+        let index = (0..len.div_ceil(block_len))
+            .map(|i| {
+                let start = index_offset + 16 * i;
+                Ok((read_u64(start)?, read_u64(start + 8)? as usize))
+            })
+            .collect::<std::io::Result<_>>()?;
+        Ok(Self {
+            mmap,
+            len,
+            block_len,
+            index,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Checks if `hash` is in the table by decoding the block which may contain it.
+    pub fn contains(&self, hash: u64) -> bool {
+        let num_blocks_before = self.index.partition_point(|&(first, _)| first <= hash);
+        let Some(block) = num_blocks_before.checked_sub(1) else {
+            return false;
+        };
+        let (first, offset) = self.index[block];
+        let block_len = self.block_len.min(self.len - block * self.block_len);
+        let mut reader = &self.mmap[offset + 8..];
+        let mut current = first;
+        for _ in 1..block_len {
+            if current >= hash {
+                break;
+            }
+            match read_delta(&mut reader, current) {
+                Ok(next) => current = next,
+                Err(_) => return false,
+            }
+        }
+        current == hash
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use full_search_lose2::testing::TempDir;
+
+    /// Distinct odd hashes in ascending order whose differences vary in length of varints
+    fn hashes(len: usize) -> Vec<u64> {
+        let mut hash = 1u64;
+        (0..len)
+            .map(|i| {
+                let current = hash;
+                hash += 2 << (7 * (i % 8));
+                current
+            })
+            .collect()
+    }
+
+    /// Distinct hashes laid out as those of boards in ascending order,
+    /// where doves on the field change every `run` hashes
+    fn board_hashes(len: usize, run: usize) -> Vec<u64> {
+        let patterns = [0b110011_000000, 0b111100_110001, 0b110000_111100];
+        (0..len)
+            .map(|i| {
+                let flags = patterns[i / run % patterns.len()];
+                let packed = (3 * (i % run) + i / run) as u64;
+                unpack(flags, packed).unwrap()
+            })
+            .collect::<std::collections::BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    fn save_in(dir: &TempDir, hashes: &[u64]) -> std::path::PathBuf {
+        let path = dir.join("table.tdc");
+        let mut iter = hashes.iter().copied();
+        save_stream(hashes.len(), || Ok(iter.next()), &path).unwrap();
+        path
+    }
+
+    fn read_all(path: &Path) -> Vec<u64> {
+        let mut reader = open_reader(path).unwrap();
+        let mut hashes = Vec::new();
+        while let Some(hash) = reader.try_next().unwrap() {
+            hashes.push(hash);
+        }
+        assert_eq!(reader.remaining(), 0);
+        hashes
+    }
+
+    #[test]
+    fn saved_hashes_are_read_in_order() {
+        let dir = TempDir::new();
+        for len in [
+            1,
+            BLOCK_LEN - 1,
+            BLOCK_LEN,
+            BLOCK_LEN + 1,
+            2 * BLOCK_LEN + 100,
+        ] {
+            let hashes = hashes(len);
+            let path = save_in(&dir, &hashes);
+            assert_eq!(read_all(&path), hashes, "len={len}");
+        }
+    }
+
+    #[test]
+    fn saved_hashes_are_found() {
+        let dir = TempDir::new();
+        // Over two blocks, so that the last block is partial
+        let hashes = hashes(2 * BLOCK_LEN + 100);
+        let path = save_in(&dir, &hashes);
+
+        let table = CompactTable::open(&path).unwrap();
+        assert_eq!(table.len(), hashes.len());
+        assert!(hashes.iter().all(|&hash| table.contains(hash)));
+        // Hashes are odd, so their neighbours are not in the table
+        for &hash in hashes.iter() {
+            assert!(!table.contains(hash - 1));
+            assert!(!table.contains(hash + 1));
+        }
+        assert!(!table.contains(0));
+        assert!(!table.contains(u64::MAX));
+    }
+
+    #[test]
+    fn first_and_last_hashes_of_blocks_are_found() {
+        let dir = TempDir::new();
+        let hashes = hashes(3 * BLOCK_LEN);
+        let path = save_in(&dir, &hashes);
+
+        let table = CompactTable::open(&path).unwrap();
+        for i in [
+            0,
+            BLOCK_LEN - 1,
+            BLOCK_LEN,
+            2 * BLOCK_LEN - 1,
+            2 * BLOCK_LEN,
+            3 * BLOCK_LEN - 1,
+        ] {
+            assert!(table.contains(hashes[i]), "hash at {i}");
+        }
+        assert!(!table.contains(hashes[0] - 1));
+        assert!(!table.contains(hashes[3 * BLOCK_LEN - 1] + 1));
+    }
+
+    #[test]
+    fn extreme_hashes_are_found() {
+        let dir = TempDir::new();
+        let hashes = [0, 1, u64::MAX - 1, u64::MAX];
+        let path = save_in(&dir, &hashes);
+
+        let table = CompactTable::open(&path).unwrap();
+        assert!(hashes.iter().all(|&hash| table.contains(hash)));
+        assert!(!table.contains(2));
+        assert!(!table.contains(u64::MAX - 2));
+        assert_eq!(read_all(&path), hashes);
+    }
+
+    #[test]
+    fn positions_of_doves_are_packed_in_order() {
+        let hash = 0x0c53_0200_0405_00bf;
+        let packed = pack(hash).unwrap();
+        assert_eq!(packed, 0x02_45bf);
+        assert_eq!(unpack(hash >> FLAGS_SHIFT, packed), Some(hash));
+        // A position of an absent dove
+        assert_eq!(pack(hash | 0xf000), None);
+        assert_eq!(pack(u64::MAX), None);
+    }
+
+    #[test]
+    fn hashes_of_boards_are_packed_into_small_differences() {
+        let dir = TempDir::new();
+        let hashes = board_hashes(3 * BLOCK_LEN, 500);
+        let path = save_in(&dir, &hashes);
+        assert!(std::fs::metadata(&path).unwrap().len() < 2 * hashes.len() as u64);
+
+        assert_eq!(read_all(&path), hashes);
+        let table = CompactTable::open(&path).unwrap();
+        assert!(hashes.iter().all(|&hash| table.contains(hash)));
+        assert!(hashes.iter().all(|&hash| !table.contains(hash + 1)));
+    }
+
+    #[test]
+    fn hashes_not_in_ascending_order_are_rejected() {
+        let dir = TempDir::new();
+        let path = dir.join("table.tdc");
+        for hashes in [[1, 3, 2], [1, 3, 3]] {
+            let mut iter = hashes.into_iter();
+            assert!(save_stream(3, || Ok(iter.next()), &path).is_err());
+        }
+        let mut iter = [1, 2].into_iter();
+        assert!(save_stream(3, || Ok(iter.next()), &path).is_err());
+    }
+
+    #[test]
+    fn empty_table_has_nothing() {
+        let dir = TempDir::new();
+        let path = save_in(&dir, &[]);
+
+        let table = CompactTable::open(&path).unwrap();
+        assert_eq!(table.len(), 0);
+        assert!(!table.contains(0));
+        assert!(!table.contains(u64::MAX));
+        assert!(read_all(&path).is_empty());
+    }
+
+    #[test]
+    fn other_files_are_rejected() {
+        let dir = TempDir::new();
+        let path = dir.join("table.tdc");
+        storage::save_with(&path, |w| w.write_all(&[0; HEADER_LEN + 8])).unwrap();
+        assert!(CompactTable::open(&path).is_err());
+        assert!(open_reader(&path).is_err());
+    }
+}
@@ -0,0 +1,176 @@
+use full_search_lose2::config::{self, parse, var, DEFAULT_MAX_CHUNK_SIZE};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+/// Algorithms finding loses from wins
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Algorithm {
+    /// Backstep wins and trim the candidates by checking all their children
+    #[default]
+    Trim,
+    /// Keep for each candidate the number of children not known as wins
+    Retrograde,
+}
+
+impl std::str::FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        <Self as clap::ValueEnum>::from_str(s, true)
+    }
+}
+
+const DEFAULT_SPILL_THRESHOLD: usize = 400_000_000;
+
+/// Settings which may be left unspecified.
+///
+/// The same structure is read from a config file, environment variables and command line,
+/// which are merged in order of precedence.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PartialConfig {
+    pub data_root: Option<PathBuf>,
+    pub num_processes: Option<usize>,
+    pub num_threads: Option<usize>,
+    pub max_tasks: Option<usize>,
+    pub memory_budget: Option<u64>,
+    pub max_chunk_size: Option<usize>,
+    pub spill_threshold: Option<usize>,
+    pub split: Option<Vec<usize>>,
+    pub min_doves: Option<usize>,
+    pub max_doves: Option<usize>,
+    pub del_tmp_files: Option<bool>,
+    pub prefilter: Option<bool>,
+    pub merge_trim: Option<bool>,
+    pub external_sort: Option<bool>,
+    pub algorithm: Option<Algorithm>,
+}
+
+impl PartialConfig {
+    /// Reads the config file specified by `path`, `TOKYODOVES_CONFIG` or the default name
+    /// in this order of precedence.
+    /// If none of them is given and the default file does not exist, nothing is specified.
+    pub fn from_file_or_default(path: Option<&Path>) -> anyhow::Result<Self> {
+        config::from_file_or_default(path)
+    }
+
+    /// Reads environment variables `TOKYODOVES_*`.
+    ///
+    /// `TOKYODOVES_SPLIT` is a comma-separated list of numbers of doves.
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            data_root: var("TOKYODOVES_DATA_ROOT")?.map(PathBuf::from),
+            num_processes: parse("TOKYODOVES_NUM_PROCESSES")?,
+            num_threads: parse("TOKYODOVES_NUM_THREADS")?,
+            max_tasks: parse("TOKYODOVES_MAX_TASKS")?,
+            memory_budget: parse("TOKYODOVES_MEMORY_BUDGET")?,
+            max_chunk_size: parse("TOKYODOVES_MAX_CHUNK_SIZE")?,
+            spill_threshold: parse("TOKYODOVES_SPILL_THRESHOLD")?,
+            split: config::parse_list("TOKYODOVES_SPLIT")?,
+            min_doves: parse("TOKYODOVES_MIN_DOVES")?,
+            max_doves: parse("TOKYODOVES_MAX_DOVES")?,
+            del_tmp_files: parse("TOKYODOVES_DEL_TMP_FILES")?,
+            prefilter: parse("TOKYODOVES_PREFILTER")?,
+            merge_trim: parse("TOKYODOVES_MERGE_TRIM")?,
+            external_sort: parse("TOKYODOVES_EXTERNAL_SORT")?,
+            algorithm: parse("TOKYODOVES_ALGORITHM")?,
+        })
+    }
+
+    /// Fills settings unspecified in `self` with those in `lower`.
+    pub fn or(self, lower: Self) -> Self {
+        Self {
+            data_root: self.data_root.or(lower.data_root),
+            num_processes: self.num_processes.or(lower.num_processes),
+            num_threads: self.num_threads.or(lower.num_threads),
+            max_tasks: self.max_tasks.or(lower.max_tasks),
+            memory_budget: self.memory_budget.or(lower.memory_budget),
+            max_chunk_size: self.max_chunk_size.or(lower.max_chunk_size),
+            spill_threshold: self.spill_threshold.or(lower.spill_threshold),
+            split: self.split.or(lower.split),
+            min_doves: self.min_doves.or(lower.min_doves),
+            max_doves: self.max_doves.or(lower.max_doves),
+            del_tmp_files: self.del_tmp_files.or(lower.del_tmp_files),
+            prefilter: self.prefilter.or(lower.prefilter),
+            merge_trim: self.merge_trim.or(lower.merge_trim),
+            external_sort: self.external_sort.or(lower.external_sort),
+            algorithm: self.algorithm.or(lower.algorithm),
+        }
+    }
+
+    /// Fills unspecified settings with defaults and validates them.
+    pub fn resolve(self) -> anyhow::Result<Config> {
+        let data_root = config::resolve_data_root(self.data_root)?;
+        let num_processes = config::resolve_parallelism(self.num_processes, "number of processes")?;
+        let num_threads = config::resolve_parallelism(self.num_threads, "number of threads")?;
+        let max_tasks = config::resolve_positive(self.max_tasks, 1, "number of tasks")?;
+        let max_chunk_size = config::resolve_positive(
+            self.max_chunk_size,
+            DEFAULT_MAX_CHUNK_SIZE,
+            "max chunk size",
+        )?;
+        let spill_threshold = config::resolve_positive(
+            self.spill_threshold,
+            DEFAULT_SPILL_THRESHOLD,
+            "spill threshold",
+        )?;
+        let doves = config::resolve_doves(self.min_doves, self.max_doves)?;
+        let split = config::resolve_split(self.split)?;
+
+        Ok(Config {
+            data_root,
+            num_processes,
+            num_threads,
+            max_tasks,
+            memory_budget: self.memory_budget,
+            max_chunk_size,
+            spill_threshold,
+            split,
+            min_doves: *doves.start(),
+            max_doves: *doves.end(),
+            del_tmp_files: self.del_tmp_files.unwrap_or(true),
+            prefilter: self.prefilter.unwrap_or(false),
+            merge_trim: self.merge_trim.unwrap_or(false),
+            external_sort: self.external_sort.unwrap_or(false),
+            algorithm: self.algorithm.unwrap_or_default(),
+        })
+    }
+}
+
+/// Validated settings of the analysis
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub data_root: PathBuf,
+    /// Number of files into which boards with each number of doves are distributed
+    pub num_processes: usize,
+    /// Number of worker threads, which may be less than `num_processes`
+    pub num_threads: usize,
+    /// Number of tasks running at once, each of which uses up to `num_threads` threads
+    pub max_tasks: usize,
+    /// Bytes of memory which running tasks are estimated to use at most, unlimited if `None`
+    pub memory_budget: Option<u64>,
+    /// Number of boards read at once by backstep
+    pub max_chunk_size: usize,
+    /// Number of backstepped boards with a number of doves kept in memory before saved
+    pub spill_threshold: usize,
+    pub split: Vec<usize>,
+    pub min_doves: usize,
+    pub max_doves: usize,
+    pub del_tmp_files: bool,
+    /// Whether wins loaded in memory are looked up through a Bloom filter
+    pub prefilter: bool,
+    /// Whether backstepped boards are saved sorted and trimmed simply by merging with sorted wins
+    pub merge_trim: bool,
+    /// Whether backstepped boards are deduplicated by sorting runs on disk instead of sets in memory
+    pub external_sort: bool,
+    /// Algorithm of win-to-lose steps
+    pub algorithm: Algorithm,
+}
+
+impl Config {
+    /// Range of numbers of doves to analyze
+    pub fn doves(&self) -> RangeInclusive<usize> {
+        self.min_doves..=self.max_doves
+    }
+}
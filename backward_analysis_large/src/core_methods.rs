@@ -1,27 +1,46 @@
 pub(crate) mod filter_maker;
 pub(crate) mod hashutil;
+pub(crate) mod merge;
+pub(crate) mod prefilter;
+pub(crate) mod retrograde;
+pub(crate) mod solver;
 
 use std::{
     collections::HashMap,
-    ffi::OsString,
+    ops::RangeInclusive,
     path::{Path, PathBuf},
-    sync::Arc,
 };
 
 use filter_maker::*;
-use tokyodoves::{collections::*, *};
+use hashutil::shard_of;
+use prefilter::Prefiltered;
+use tokyodoves::{collections::*, game::GameRule, *};
 
-use crate::path_factory::*;
+use full_search_lose2::{
+    error::{Context, Error, Phase, Result, ResultExt},
+    storage,
+};
+
+use crate::{
+    compact_table::CompactTable,
+    config::Config,
+    path_factory::*,
+    pool,
+    sorted_table::{self, SortedTable},
+    table_reader::{self, Encoding, TableReader, SORTED_EXTENSION},
+    win_lookup::WinLookup,
+};
 
 // =====================================================================
 //  Helper Functions
 // =====================================================================
-fn load_files(paths: &[impl AsRef<std::path::Path>]) -> std::io::Result<BoardSet> {
+fn load_files(paths: &[impl AsRef<std::path::Path>], context: &Context) -> Result<BoardSet> {
     println!("Estimating required capacity ...");
     let mut capacity = Capacity::new();
     for path in paths.iter() {
         println!("Searching win at {:?} ...", path.as_ref());
-        capacity += BoardSet::required_capacity(std::fs::File::open(path)?);
+        capacity += table_reader::required_capacity(path, |_| true)
+            .with_context(|| context.clone().path(path))?;
         println!("Searched win at {:?}", path.as_ref());
     }
     let mut set = BoardSet::with_capacity(capacity);
@@ -29,7 +48,8 @@ fn load_files(paths: &[impl AsRef<std::path::Path>]) -> std::io::Result<BoardSet
 
     for path in paths.iter() {
         println!("Loading win at {:?} ...", path.as_ref());
-        set.load(std::fs::File::open(path).expect("open error!"))?;
+        table_reader::load_filter(&mut set, path, |_| true)
+            .with_context(|| context.clone().path(path))?;
         println!("Loaded win at {:?}", path.as_ref());
     }
     Ok(set)
@@ -38,7 +58,8 @@ fn load_files(paths: &[impl AsRef<std::path::Path>]) -> std::io::Result<BoardSet
 fn load_files_with_filter<F>(
     paths: &[impl AsRef<std::path::Path>],
     filter: F,
-) -> std::io::Result<BoardSet>
+    context: &Context,
+) -> Result<BoardSet>
 where
     F: Fn(&u64) -> bool,
 {
@@ -46,7 +67,8 @@ where
     let mut capacity = Capacity::new();
     for path in paths.iter() {
         println!("Searching win at {:?} ...", path.as_ref());
-        capacity += BoardSet::required_capacity_filter(std::fs::File::open(path)?, &filter);
+        capacity += table_reader::required_capacity(path, &filter)
+            .with_context(|| context.clone().path(path))?;
         println!("Searched win at {:?}", path.as_ref());
     }
     let mut set = BoardSet::with_capacity(capacity);
@@ -54,251 +76,393 @@ where
 
     for path in paths.iter() {
         println!("Loading win at {:?} ...", path.as_ref());
-        set.load_filter(std::fs::File::open(path)?, &filter)?;
+        table_reader::load_filter(&mut set, path, &filter)
+            .with_context(|| context.clone().path(path))?;
         println!("Loaded win at {:?}", path.as_ref());
     }
     Ok(set)
 }
 
-fn is_win1_or_finished(board: Board, player: Color) -> bool {
+fn is_win1_or_finished(board: Board, player: Color, rule: GameRule) -> bool {
     if !matches!(board.surrounded_status(), SurroundedStatus::None) {
         return true;
     }
     board
-        .legal_actions(player, true, true, true)
+        .legal_actions(player, true, true, *rule.is_remove_accepted())
         .into_iter()
         .map(|a1| board.perform_unchecked_copied(a1))
         .any(|b1| matches!(b1.surrounded_status(), SurroundedStatus::OneSide(p) if p != player))
 }
 
+/// Backward actions of green on `board`, which are generated kind by kind,
+/// since tokyodoves panics when there are more than 100 of them at once,
+/// as with few doves on the field.
+fn legal_actions_bwd(board: Board, rule: GameRule) -> impl Iterator<Item = Action> {
+    let kinds = [
+        (true, false, false),
+        (false, true, false),
+        (false, false, *rule.is_remove_accepted()),
+    ];
+    kinds.into_iter().flat_map(move |(put, move_, remove)| {
+        board.legal_actions_bwd(Color::Green, put, move_, remove)
+    })
+}
+
+/// Paths of tables of any format in `dir`
+fn table_paths_in(dir: impl AsRef<std::path::Path>) -> std::io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if Encoding::of(&path).is_none() {
+            continue;
+        }
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+/// Loads all tables in `dir` into a set.
+fn load_dir(dir: impl AsRef<std::path::Path>, context: &Context) -> Result<BoardSet> {
+    let dir = dir.as_ref();
+    let paths = table_paths_in(dir).with_context(|| context.clone().path(dir))?;
+    let mut capacity = Capacity::new();
+    for path in paths.iter() {
+        capacity += table_reader::required_capacity(path, |_| true)
+            .with_context(|| context.clone().path(path))?;
+    }
+    let mut set = BoardSet::with_capacity(capacity);
+    for path in paths {
+        println!("Loading {path:?} ...");
+        table_reader::load_filter(&mut set, &path, |_| true)
+            .with_context(|| context.clone().path(&path))?;
+        println!("Loaded {path:?}");
+    }
+    Ok(set)
+}
+
 // =====================================================================
 //  Backstep
 // =====================================================================
-fn split_set_into(mut set: BoardSet, num: usize) -> Vec<BoardSet> {
-    let chunk = (set.len() + num - 1) / num;
-    let mut set_vec = Vec::with_capacity(num);
-    for _ in 0..num {
-        let tmp: BoardSet;
-        (tmp, set) = set.split(chunk);
-        set_vec.push(tmp);
-    }
-    set_vec
+/// Buffer of backstepped boards in a shard until they are saved
+trait ShardBuffer: Default + Send {
+    fn insert(&mut self, hash: u64);
+
+    fn absorb(&mut self, other: Self);
+
+    /// Number of boards in the buffer, which may be counted more than once
+    fn len(&self) -> usize;
+
+    /// Saves the boards into `path`, whose extension tells the format, and clears the buffer.
+    fn spill(&mut self, path: &Path) -> std::io::Result<()>;
+}
+
+/// Deduplicates boards as they are inserted
+impl ShardBuffer for BoardSet {
+    fn insert(&mut self, hash: u64) {
+        self.raw_mut().insert(hash);
+    }
+
+    fn absorb(&mut self, other: Self) {
+        self.reserve(other.capacity());
+        BoardSet::absorb(self, other);
+    }
+
+    fn len(&self) -> usize {
+        BoardSet::len(self)
+    }
+
+    fn spill(&mut self, path: &Path) -> std::io::Result<()> {
+        match Encoding::of(path) {
+            Some(Encoding::Sorted) => SortedTable::save(self, path)?,
+            _ => storage::save_set(self, path)?,
+        }
+        *self = BoardSet::new();
+        Ok(())
+    }
+}
+
+/// Keeps duplicates as they are inserted, which take only 8 bytes each,
+/// and saves a sorted run of distinct boards
+impl ShardBuffer for Vec<u64> {
+    fn insert(&mut self, hash: u64) {
+        self.push(hash);
+    }
+
+    fn absorb(&mut self, mut other: Self) {
+        self.append(&mut other);
+    }
+
+    fn len(&self) -> usize {
+        Vec::len(self)
+    }
+
+    fn spill(&mut self, path: &Path) -> std::io::Result<()> {
+        self.sort_unstable();
+        self.dedup();
+        let mut iter = self.iter().copied();
+        sorted_table::save_stream(self.len(), || Ok(iter.next()), path)?;
+        *self = Vec::new();
+        Ok(())
+    }
 }
 
+/// Backsteps boards in `src_path` streamed in batches of `config.max_chunk_size` boards.
+///
+/// Backstepped boards are sorted by number of doves and by [`shard_of`] their hashes
+/// into `config.num_processes` shards, whose files are saved into [`shard_dir`]s in `dst_dir`.
+/// Only backstepped boards are kept in memory,
+/// which are saved whenever those with a number of doves reach `config.spill_threshold` boards.
+///
+/// If `config.external_sort`, boards are kept in plain vectors rather than sets
+/// and saved as sorted runs, which are merged into a sorted file of distinct boards per shard at the end.
 pub fn backstep(
     src_path: impl AsRef<std::path::Path>,
     dst_dir: impl AsRef<std::path::Path>,
     num_doves: usize,
-    num_processes: usize,
-    max_chunk_size: usize,
-) -> anyhow::Result<()> {
-    println!("Loading {:?} ...", src_path.as_ref());
-    let mut full_set = BoardSet::new_from_file(&src_path)?;
-    println!("Loaded {:?}", src_path.as_ref());
+    config: &Config,
+    rule: GameRule,
+) -> Result<()> {
+    let context = Context::new(Phase::Backstep).doves(num_doves);
+    let dst_dir = dst_dir.as_ref();
+    if !config.external_sort {
+        backstep_with::<BoardSet>(src_path, dst_dir, num_doves, config, rule)?;
+        return Ok(());
+    }
 
-    let mut idx_chunk = 0;
-    let mut load_next = true;
-    while load_next {
-        println!("*** idx_chunk = {idx_chunk} ***");
-        let set: BoardSet;
-        (set, full_set) = full_set.split(max_chunk_size);
-        if full_set.is_empty() {
-            load_next = false;
+    let num_runs = backstep_with::<Vec<u64>>(src_path, dst_dir, num_doves, config, rule)?;
+    for (num, num_runs) in num_runs {
+        if num_runs < 2 {
+            continue;
         }
+        let dirs: Vec<PathBuf> = (0..config.num_processes)
+            .map(|shard| shard_dir(dove_dir(dst_dir, num), shard))
+            .collect();
+        let run_paths_of = |dir: &Path| -> Vec<PathBuf> {
+            (0..num_runs)
+                .map(|idx| dir.join(run_file_name(num_doves, idx, SORTED_EXTENSION)))
+                .collect()
+        };
+        let sizes: Vec<u64> = dirs
+            .iter()
+            .map(|dir| pool::file_sizes(&run_paths_of(dir)).iter().sum())
+            .collect();
+        pool::run(
+            &sizes,
+            config.num_threads,
+            |shard| context.clone().shard(shard),
+            |shard| {
+                let dir = &dirs[shard];
+                let context = || context.clone().shard(shard).path(dir);
+                let run_paths = run_paths_of(dir);
+                let dst_path = dir.join(format!("from_{num_doves:0>2}.{SORTED_EXTENSION}"));
+                let len = merge::merge_runs(&run_paths, &dst_path).with_context(context)?;
+                println!("Merged {num_runs} runs into {len} boards in {dst_path:?}");
+                for path in run_paths {
+                    std::fs::remove_file(&path).with_context(context)?;
+                    std::fs::remove_file(storage::sum_path(&path)).with_context(context)?;
+                }
+                Ok(())
+            },
+        )?;
+    }
+    Ok(())
+}
 
-        let set_vec = Arc::new(split_set_into(set, num_processes));
-        let mut handlers = Vec::new();
-        for i in 0..num_processes {
-            let set_vec = set_vec.clone();
-            handlers.push(std::thread::spawn(move || {
-                println!("[Thread {i}] started");
-                let num_to_set = backstep_core(set_vec[i].iter(), num_doves);
-                println!("[Thread {i}] finished");
-                num_to_set
-            }));
-        }
+fn run_file_name(num_doves: usize, idx: usize, extension: &str) -> String {
+    format!("from_{num_doves:0>2}_{idx:0>4}.{extension}")
+}
 
-        let vec_of_num_to_set: Vec<HashMap<usize, BoardSet>> =
-            handlers.into_iter().map(|x| x.join().unwrap()).collect();
+/// Backsteps boards buffered in `S` as [`backstep`] does
+/// and returns the number of files saved for each number of doves.
+fn backstep_with<S: ShardBuffer>(
+    src_path: impl AsRef<std::path::Path>,
+    dst_dir: &Path,
+    num_doves: usize,
+    config: &Config,
+    rule: GameRule,
+) -> Result<HashMap<usize, usize>> {
+    let context = Context::new(Phase::Backstep).doves(num_doves);
+    let src_path = src_path.as_ref();
+    let src_context = || context.clone().path(src_path);
+    println!("Streaming {src_path:?} ...");
+    let mut reader = TableReader::open(src_path).with_context(src_context)?;
 
-        println!("[Thread Main] calculating capacity ...");
-        let mut capacity_map = HashMap::new();
-        for num_to_set in vec_of_num_to_set.iter() {
-            for (num, set) in num_to_set.iter() {
-                *capacity_map.entry(*num).or_insert_with(Capacity::new) += set.capacity();
+    // Shards are saved sorted to be trimmed by merging or merged later
+    let extension = match config.merge_trim || config.external_sort {
+        true => SORTED_EXTENSION,
+        false => table_reader::PLAIN_EXTENSION,
+    };
+    let mut num_to_shards_all: HashMap<usize, Vec<S>> = HashMap::new();
+    let mut idx_batch = 0;
+    let mut num_files: HashMap<usize, usize> = HashMap::new();
+    let mut load_next = true;
+    while load_next {
+        println!("*** idx_batch = {idx_batch} ***");
+        let mut batch = Vec::new();
+        while batch.len() < config.max_chunk_size {
+            match reader.try_next().with_context(src_context)? {
+                Some(hash) => batch.push(BoardBuilder::from(hash).build_unchecked()),
+                None => {
+                    load_next = false;
+                    break;
+                }
             }
         }
-        println!("[Thread Main] calculated capacity");
 
-        let mut num_to_set_all = HashMap::new();
-        for (i, num_to_set) in vec_of_num_to_set.into_iter().enumerate() {
-            for (num, set) in num_to_set {
-                num_to_set_all
+        let pieces: Vec<&[Board]> = batch
+            .chunks(batch.len().div_ceil(config.num_processes).max(1))
+            .collect();
+        let sizes: Vec<u64> = pieces.iter().map(|piece| piece.len() as u64).collect();
+        let vec_of_num_to_shards: Vec<HashMap<usize, Vec<S>>> = pool::run(
+            &sizes,
+            config.num_threads,
+            |i| context.clone().shard(i),
+            |i| {
+                Ok(backstep_core(
+                    pieces[i].iter().copied(),
+                    num_doves,
+                    config.doves(),
+                    config.num_processes,
+                    rule,
+                ))
+            },
+        )?;
+        drop(pieces);
+        drop(batch);
+
+        for (i, num_to_shards) in vec_of_num_to_shards.into_iter().enumerate() {
+            for (num, shards) in num_to_shards {
+                let shards_all = num_to_shards_all
                     .entry(num)
-                    .or_insert_with(|| BoardSet::with_capacity(capacity_map[&num].clone()))
-                    .absorb(set);
+                    .or_insert_with(|| shards.iter().map(|_| S::default()).collect());
+                for (set_all, set) in shards_all.iter_mut().zip(shards) {
+                    set_all.absorb(set);
+                }
             }
             println!("[Thread Main] concatenated {i}");
         }
         println!("[Thread Main] concatenated all");
 
-        for (num, set) in num_to_set_all {
-            let dst_path = dove_dir(dst_dir.as_ref(), num)
-                .join(format!("from_{num_doves:0>2}_{idx_chunk:0>4}.tdl"));
-            set.save(std::fs::File::create(dst_path)?)?;
+        // Everything left is saved at the end
+        for (num, shards) in num_to_shards_all.iter_mut() {
+            let len: usize = shards.iter().map(S::len).sum();
+            if load_next && len < config.spill_threshold {
+                continue;
+            }
+            let idx_file = num_files.entry(*num).or_default();
+            println!("Spilling {len} boards with {num} doves");
+            for (shard, set) in shards.iter_mut().enumerate() {
+                let dst_path = shard_dir(dove_dir(dst_dir, *num), shard)
+                    .join(run_file_name(num_doves, *idx_file, extension));
+                set.spill(&dst_path)
+                    .with_context(|| context.clone().shard(shard).path(&dst_path))?;
+            }
+            *idx_file += 1;
         }
-        idx_chunk += 1;
+        idx_batch += 1;
     }
-    Ok(())
+    Ok(num_files)
 }
 
-fn backstep_core(
+fn backstep_core<S: ShardBuffer>(
     original: impl Iterator<Item = Board>,
     num_doves: usize,
-) -> HashMap<usize, BoardSet> {
+    doves: RangeInclusive<usize>,
+    num_shards: usize,
+    rule: GameRule,
+) -> HashMap<usize, Vec<S>> {
     use Color::*;
-    let mut num_to_set = HashMap::new();
+    let mut num_to_shards = HashMap::new();
     for n in (num_doves - 1).max(2)..=(num_doves + 1).min(12) {
-        num_to_set.insert(n, BoardSet::new());
+        if doves.contains(&n) {
+            let shards: Vec<S> = (0..num_shards).map(|_| S::default()).collect();
+            num_to_shards.insert(n, shards);
+        }
     }
 
     for b0 in original {
-        for a1 in b0.legal_actions_bwd(Green, true, true, true) {
+        for a1 in legal_actions_bwd(b0, rule) {
             let b1 = b0.perform_unchecked_copied(a1);
-            if is_win1_or_finished(b1, Green) {
+            if is_win1_or_finished(b1, Green, rule) {
                 continue;
             }
             let n1 = b1.count_doves_on_field();
-            let h1 = b1.to_invariant_u64(Green);
-            num_to_set.get_mut(&n1).unwrap().raw_mut().insert(h1);
-        }
-    }
-    num_to_set
-}
-
-// =====================================================================
-//  Redistribute
-// =====================================================================
-fn count_doves_in_file(path: impl AsRef<std::path::Path>) -> std::io::Result<usize> {
-    Ok(LazyBoardLoader::new(std::fs::File::open(path)?).count())
-}
-
-fn count_doves_in_dir(root: impl AsRef<std::path::Path>) -> std::io::Result<usize> {
-    let mut count = 0;
-    for entry in std::fs::read_dir(root)? {
-        let path = entry?.path();
-        if path.extension() != Some(&OsString::from("tdl")) {
-            continue;
-        }
-        println!("Loading {path:?}");
-        let count_local = count_doves_in_file(path)?;
-        println!("Count = {count_local}");
-        count += count_local;
-    }
-    Ok(count)
-}
-
-/// Gather all boards in files at `src_dir` and redistribute into `num_result_files` files
-/// in `dst_dir`.
-pub fn redistribute(
-    src_dir: impl AsRef<std::path::Path>,
-    dst_dir: impl AsRef<std::path::Path>,
-    num_result_files: usize,
-) -> anyhow::Result<()> {
-    let total = count_doves_in_dir(&src_dir)?;
-    let chunk = (total + num_result_files - 1) / num_result_files;
-    println!("total = {total}");
-    println!("chunk = {chunk}");
-
-    let mut file_idx = 0;
-    let mut set = BoardSet::new();
-
-    macro_rules! save_set {
-        () => {
-            let dst_path = distributed_path(dst_dir.as_ref(), file_idx);
-            println!("Saving to {dst_path:?} ...");
-            set.save(std::fs::File::create(&dst_path)?)?;
-            println!("Saved to {dst_path:?}");
-            set.clear();
-            file_idx += 1;
-        };
-    }
-
-    for entry in std::fs::read_dir(&src_dir)? {
-        let path = entry?.path();
-        if path.extension() != Some(&OsString::from("tdl")) {
-            continue;
-        }
-
-        println!("Loading {path:?} ...");
-        let mut full_set = BoardSet::new_from_file(&path)?;
-        println!("Loaded {path:?}");
-
-        while !full_set.is_empty() {
-            let tmp_set: BoardSet;
-            (tmp_set, full_set) = full_set.split(chunk - set.len());
-            set.reserve(tmp_set.capacity());
-            set.absorb(tmp_set);
-            if set.len() >= chunk {
-                save_set!();
-            }
+            let Some(shards) = num_to_shards.get_mut(&n1) else {
+                continue;
+            };
+            let hash = b1.to_invariant_u64(Green);
+            shards[shard_of(hash, num_shards)].insert(hash);
         }
     }
-
-    while file_idx < num_result_files {
-        save_set!();
-    }
-
-    Ok(())
+    num_to_shards
 }
 
 // =====================================================================
 //  Trim Simply
 // =====================================================================
+/// Removes wins from each shard of boards in [`shard_dir`]s in `src_dir`
+/// and saves them into `dst_dir`.
+///
+/// A shard is merged with `sorted_win_paths` by [`merge::merge_trim`] if they are given
+/// and all files of the shard are sorted, otherwise it is loaded into memory.
 pub fn trim_simply(
     src_dir: impl AsRef<std::path::Path>,
     dst_dir: impl AsRef<std::path::Path>,
     win_paths: Vec<PathBuf>,
+    sorted_win_paths: Option<Vec<PathBuf>>,
     num_processes: usize,
-    parallel_chunk: usize,
-) -> anyhow::Result<()> {
-    let src_dir = Arc::new(src_dir.as_ref().to_owned());
-    let dst_dir = Arc::new(dst_dir.as_ref().to_owned());
-    let win_paths = Arc::new(win_paths);
-
-    for i0 in (0..num_processes).step_by(parallel_chunk) {
-        let mut handlers = Vec::new();
-        for i_add in 0..parallel_chunk {
-            let i = i0 + i_add;
-            let src_dir = src_dir.clone();
-            let dst_dir = dst_dir.clone();
-            let win_paths = win_paths.clone();
-            handlers.push(std::thread::spawn(move || {
-                println!("[Thread {i}] started");
-                let src_path = distributed_path(src_dir.as_ref(), i);
-                let dst_path = distributed_path(dst_dir.as_ref(), i);
-
-                let mut target = BoardSet::new_from_file(src_path).expect("new from file error");
-                thin_out_set(&mut target, win_paths.as_ref()).expect("trim error");
-                target.shrink_to_fit();
-                target
-                    .save(std::fs::File::create(dst_path).expect("create error"))
-                    .expect("save error");
-                println!("[Thread {i}] finished");
-            }));
-        }
-        handlers.into_iter().for_each(|x| x.join().unwrap());
-    }
+    num_threads: usize,
+    num_doves: usize,
+) -> Result<()> {
+    let context = Context::new(Phase::TrimSimply).doves(num_doves);
+    let dst_dir = dst_dir.as_ref();
+    let src_dirs: Vec<PathBuf> = (0..num_processes)
+        .map(|i| shard_dir(src_dir.as_ref(), i))
+        .collect();
+    let sizes: Vec<u64> = src_dirs
+        .iter()
+        .map(|dir| table_paths_in(dir).map_or(0, |paths| pool::file_sizes(&paths).iter().sum()))
+        .collect();
+
+    pool::run(
+        &sizes,
+        num_threads,
+        |i| context.clone().shard(i),
+        |i| {
+            let context = context.clone().shard(i);
+            let dst_path = distributed_path(dst_dir, i);
+
+            let src_paths =
+                table_paths_in(&src_dirs[i]).with_context(|| context.clone().path(&src_dirs[i]))?;
+            let sorted = src_paths
+                .iter()
+                .all(|path| Encoding::of(path).is_some_and(Encoding::is_sorted));
+            if let (true, Some(sorted_win_paths)) = (sorted, &sorted_win_paths) {
+                println!("Merging {:?} with wins ...", src_dirs[i]);
+                let num_boards = merge::merge_trim(&src_paths, sorted_win_paths, &dst_path)
+                    .with_context(|| context.path(&src_dirs[i]))?;
+                println!("Merged {num_boards} boards into {dst_path:?}");
+                return Ok(());
+            }
 
+            let mut target = load_dir(&src_dirs[i], &context)?;
+            thin_out_set(&mut target, &win_paths, &context)?;
+            target.shrink_to_fit();
+            storage::save_set(&target, &dst_path).with_context(|| context.path(&dst_path))
+        },
+    )?;
     Ok(())
 }
 
 fn thin_out_set(
     target: &mut BoardSet,
     win_paths: &[impl AsRef<std::path::Path>],
-) -> anyhow::Result<()> {
+    context: &Context,
+) -> Result<()> {
     for path in win_paths.iter() {
-        for hash in LazyRawBoardLoader::new(std::fs::File::open(path)?) {
+        let context = || context.clone().path(path);
+        let mut reader = TableReader::open(path).with_context(context)?;
+        while let Some(hash) = reader.try_next().with_context(context)? {
             target.raw_mut().remove(&hash);
         }
     }
@@ -312,35 +476,102 @@ pub fn trim_on_action<P>(
     num_doves_win: usize,
     num_step_to: usize,
     factory: &PathFactory<P>,
-    num_processes: usize,
-    nums_doves_to_split_win_if_possible: &[usize],
-) -> anyhow::Result<()>
+    config: &Config,
+    rule: GameRule,
+) -> Result<()>
 where
     P: AsRef<Path>,
 {
+    let context = Context::new(Phase::TrimOnAction).doves(num_doves_win);
     if !(2..=12).contains(&num_doves_win) {
-        return Err(anyhow::anyhow!("invalid num_doves_win"));
+        return Err(Error::InvalidArgument {
+            context,
+            message: "number of doves of wins must be in 2..=12".to_owned(),
+        });
     }
+    let num_processes = config.num_processes;
+    let doves = config.doves();
 
-    std::fs::create_dir_all(factory.trimmed_remove(num_step_to))?;
-    std::fs::create_dir_all(factory.trimmed_move(num_step_to))?;
-    std::fs::create_dir_all(factory.trimmed_put(num_step_to))?;
+    for dir in [
+        factory.trimmed_remove(num_step_to),
+        factory.trimmed_move(num_step_to),
+        factory.trimmed_put(num_step_to),
+    ] {
+        std::fs::create_dir_all(&dir).with_context(|| context.clone().path(&dir))?;
+    }
+    let win_paths = || {
+        factory
+            .win_paths(num_step_to - 1, num_doves_win)
+            .with_context(|| context.clone())
+    };
+    // Wins out of the range are not computed, so no table of them is looked for
+    let win_table = match doves.contains(&num_doves_win) {
+        false => None,
+        true => match factory
+            .verified_win_table(num_step_to - 1, num_doves_win)
+            .with_context(|| context.clone())?
+        {
+            Some(path) => Some(path),
+            // A single table of wins in the compact format can be probed on disk as well
+            None => match win_paths()?.as_slice() {
+                [path] if Encoding::of(path) == Some(Encoding::Compact) => Some(path.clone()),
+                _ => None,
+            },
+        },
+    };
 
-    let (sets_array, dst_dirs) = match num_doves_win {
-        x if !nums_doves_to_split_win_if_possible.contains(&x) || matches!(x, 2..=9 | 12) => {
-            let win_paths = factory.win_paths(num_step_to - 1, num_doves_win);
-            let wins = load_files(&win_paths)?;
+    let (sets_array, dst_dirs) = match (num_doves_win, win_table) {
+        // Wins out of the range are not computed and regarded as nonexistent
+        (x, _) if !doves.contains(&x) => create_three_thinned_sets(
+            factory,
+            |_| true,
+            BoardSet::new(),
+            num_doves_win,
+            num_step_to,
+            config,
+            rule,
+        )?,
+        // Wins are probed on disk, so they need not be split however many they are
+        (_, Some(path)) => {
+            println!("Mapping win table {path:?} ...");
+            let wins: Box<dyn WinLookup + Sync> = match Encoding::of(&path) {
+                Some(Encoding::Compact) => {
+                    let table =
+                        CompactTable::open(&path).with_context(|| context.clone().path(&path))?;
+                    println!("Mapped {} wins", table.len());
+                    Box::new(table)
+                }
+                _ => {
+                    let table =
+                        SortedTable::open(&path).with_context(|| context.clone().path(&path))?;
+                    println!("Mapped {} wins", table.len());
+                    Box::new(table)
+                }
+            };
+            create_three_thinned_sets(
+                factory,
+                |_| true,
+                wins,
+                num_doves_win,
+                num_step_to,
+                config,
+                rule,
+            )?
+        }
+        (x, None) if !config.split.contains(&x) || matches!(x, 2..=9 | 12) => {
+            let wins = Prefiltered::new(load_files(&win_paths()?, &context)?, config.prefilter);
             create_three_thinned_sets(
                 factory,
                 |_| true,
                 wins,
                 num_doves_win,
                 num_step_to,
-                num_processes,
+                config,
+                rule,
             )?
         }
-        10 => {
-            let win_paths = factory.win_paths(num_step_to - 1, num_doves_win);
+        (10, None) => {
+            let win_paths = win_paths()?;
             let mut sets_array_tmp = {
                 let mut array: [Vec<BoardSet>; 3] = Default::default();
                 for elem in array.iter_mut() {
@@ -350,17 +581,19 @@ where
             };
             let mut dst_dirs_tmp: [PathBuf; 3] = Default::default();
             for level in 0..=1 {
-                let wins = load_files_with_filter(&win_paths, make_win_filter_10(level))?;
+                let wins = load_files_with_filter(&win_paths, make_win_filter_10(level), &context)?;
+                let wins = Prefiltered::new(wins, config.prefilter);
                 let (sets_array_new, dst_dirs) = create_three_thinned_sets(
                     factory,
                     make_target_filter_10(level),
                     wins,
                     num_doves_win,
                     num_step_to,
-                    num_processes,
+                    config,
+                    rule,
                 )?;
-                for (tmp, new) in sets_array_tmp.iter_mut().zip(sets_array_new.into_iter()) {
-                    for (tmp_elem, new_elem) in tmp.iter_mut().zip(new.into_iter()) {
+                for (tmp, new) in sets_array_tmp.iter_mut().zip(sets_array_new) {
+                    for (tmp_elem, new_elem) in tmp.iter_mut().zip(new) {
                         tmp_elem.absorb(new_elem);
                     }
                 }
@@ -368,8 +601,8 @@ where
             }
             (sets_array_tmp, dst_dirs_tmp)
         }
-        11 => {
-            let win_paths = factory.win_paths(num_step_to - 1, num_doves_win);
+        (11, None) => {
+            let win_paths = win_paths()?;
             let mut sets_array_tmp: [Vec<BoardSet>; 3] = {
                 let mut array: [Vec<BoardSet>; 3] = Default::default();
                 for elem in array.iter_mut() {
@@ -379,17 +612,19 @@ where
             };
             let mut dst_dirs_tmp: [PathBuf; 3] = Default::default();
             for level in 0..=1 {
-                let wins = load_files_with_filter(&win_paths, make_win_filter_11(level))?;
+                let wins = load_files_with_filter(&win_paths, make_win_filter_11(level), &context)?;
+                let wins = Prefiltered::new(wins, config.prefilter);
                 let (sets_array_new, dst_dirs) = create_three_thinned_sets(
                     factory,
                     make_target_filter_11(level),
                     wins,
                     num_doves_win,
                     num_step_to,
-                    num_processes,
+                    config,
+                    rule,
                 )?;
-                for (tmp, new) in sets_array_tmp.iter_mut().zip(sets_array_new.into_iter()) {
-                    for (tmp_elem, new_elem) in tmp.iter_mut().zip(new.into_iter()) {
+                for (tmp, new) in sets_array_tmp.iter_mut().zip(sets_array_new) {
+                    for (tmp_elem, new_elem) in tmp.iter_mut().zip(new) {
                         tmp_elem.absorb(new_elem);
                     }
                 }
@@ -401,30 +636,40 @@ where
     };
 
     println!("Saving ...");
-    for (n, (sets, dst_dir)) in sets_array.into_iter().zip(dst_dirs.into_iter()).enumerate() {
+    for (n, (sets, dst_dir)) in sets_array.into_iter().zip(dst_dirs).enumerate() {
+        let num_doves_target = num_doves_win + n - 1;
+        let context = Context::new(Phase::TrimOnAction).doves(num_doves_target);
         match (n, num_doves_win) {
             (0, 2) => {
                 println!("Skipped");
                 continue;
             }
-            (2, 12) => {
+            (2, 12) if doves.contains(&12) => {
                 let src_dir = dove_dir(factory.trimmed_move(num_step_to), 12);
                 let dst_dir = dove_dir(factory.trimmed_put(num_step_to), 12);
-                std::fs::create_dir_all(&dst_dir)?;
+                std::fs::create_dir_all(&dst_dir)
+                    .with_context(|| context.clone().path(&dst_dir))?;
 
                 for i in 0..num_processes {
                     let src_path = distributed_path(&src_dir, i);
                     let dst_path = distributed_path(&dst_dir, i);
-                    std::fs::copy(src_path, dst_path)?;
+                    storage::copy(&src_path, dst_path)
+                        .with_context(|| context.clone().shard(i).path(&src_path))?;
                 }
                 continue;
             }
+            _ if !doves.contains(&num_doves_target) => {
+                println!("Skipped");
+                continue;
+            }
             _ => {
-                std::fs::create_dir_all(&dst_dir)?;
+                std::fs::create_dir_all(&dst_dir)
+                    .with_context(|| context.clone().path(&dst_dir))?;
                 for (i, set) in sets.into_iter().enumerate() {
                     let dst_path = distributed_path(&dst_dir, i);
                     println!("Saving to {dst_path:?} ...");
-                    set.save(std::fs::File::create(&dst_path)?)?;
+                    storage::save_set(&set, &dst_path)
+                        .with_context(|| context.clone().shard(i).path(&dst_path))?;
                     println!("Saved to {dst_path:?}");
                 }
             }
@@ -434,34 +679,43 @@ where
     Ok(())
 }
 
-fn create_three_thinned_sets<P, FT>(
+fn create_three_thinned_sets<P, FT, W>(
     factory: &PathFactory<P>,
     target_filter: FT,
-    wins: BoardSet,
+    wins: W,
     num_doves_win: usize,
     num_target_step: usize,
-    num_processes: usize,
-) -> std::io::Result<([Vec<BoardSet>; 3], [PathBuf; 3])>
+    config: &Config,
+    rule: GameRule,
+) -> Result<([Vec<BoardSet>; 3], [PathBuf; 3])>
 where
     P: AsRef<Path>,
-    FT: Fn(&u64) -> bool + Send + Sync + 'static + Clone,
+    FT: Fn(&u64) -> bool + Sync + Clone,
+    W: WinLookup + Sync,
 {
-    let wins = Arc::new(wins);
+    let doves = config.doves();
+    let num_processes = config.num_processes;
 
     // move -> put
     println!("*** move -> put ***");
     let (sets0, dst_dir0) = if num_doves_win == 2 {
         // copy 2 as start -> remove
-        let src_dir = dove_dir(factory.trimmed_simply(num_target_step), num_doves_win);
-        let dst_dir = dove_dir(factory.trimmed_remove(num_target_step), num_doves_win);
-        std::fs::create_dir_all(&dst_dir)?;
-
-        for i in 0..num_processes {
-            let src_path = distributed_path(&src_dir, i);
-            let dst_path = distributed_path(&dst_dir, i);
-            std::fs::copy(src_path, dst_path)?;
+        if doves.contains(&2) {
+            let src_dir = dove_dir(factory.trimmed_simply(num_target_step), num_doves_win);
+            let dst_dir = dove_dir(factory.trimmed_remove(num_target_step), num_doves_win);
+            let context = Context::new(Phase::TrimOnAction).doves(num_doves_win);
+            std::fs::create_dir_all(&dst_dir).with_context(|| context.clone().path(&dst_dir))?;
+
+            for i in 0..num_processes {
+                let src_path = distributed_path(&src_dir, i);
+                let dst_path = distributed_path(&dst_dir, i);
+                storage::copy(&src_path, dst_path)
+                    .with_context(|| context.clone().shard(i).path(&src_path))?;
+            }
         }
 
+        (Vec::new(), PathBuf::default()) // dummy
+    } else if !doves.contains(&(num_doves_win - 1)) {
         (Vec::new(), PathBuf::default()) // dummy
     } else {
         let num_doves_target = num_doves_win - 1;
@@ -472,11 +726,13 @@ where
             create_thinned_set_parallel(
                 &src_dir,
                 target_filter.clone(),
-                wins.clone(),
+                &wins,
                 true,
                 false,
                 false,
-                num_processes,
+                config,
+                rule,
+                Context::new(Phase::TrimOnAction).doves(num_doves_target),
             )?,
             dst_dir,
         )
@@ -484,7 +740,9 @@ where
 
     // remove -> move
     println!("*** remove -> move ***");
-    let (sets1, dst_dir1) = {
+    let (sets1, dst_dir1) = if !doves.contains(&num_doves_win) {
+        (Vec::new(), PathBuf::default()) // dummy
+    } else {
         let num_doves_target = num_doves_win;
         let src_dir = dove_dir(factory.trimmed_remove(num_target_step), num_doves_target);
         let dst_dir = dove_dir(factory.trimmed_move(num_target_step), num_doves_target);
@@ -493,11 +751,13 @@ where
             create_thinned_set_parallel(
                 &src_dir,
                 target_filter.clone(),
-                wins.clone(),
+                &wins,
                 false,
                 true,
                 false,
-                num_processes,
+                config,
+                rule,
+                Context::new(Phase::TrimOnAction).doves(num_doves_target),
             )?,
             dst_dir,
         )
@@ -505,7 +765,7 @@ where
 
     // start -> remove
     println!("*** start -> remove ***");
-    let (sets2, dst_dir2) = if num_doves_win == 12 {
+    let (sets2, dst_dir2) = if num_doves_win == 12 || !doves.contains(&(num_doves_win + 1)) {
         (Vec::new(), PathBuf::default()) // dummy
     } else {
         let num_doves_target = num_doves_win + 1;
@@ -516,11 +776,13 @@ where
             create_thinned_set_parallel(
                 &src_dir,
                 target_filter,
-                wins.clone(),
+                &wins,
                 false,
                 false,
                 true,
-                num_processes,
+                config,
+                rule,
+                Context::new(Phase::TrimOnAction).doves(num_doves_target),
             )?,
             dst_dir,
         )
@@ -529,73 +791,86 @@ where
     Ok(([sets0, sets1, sets2], [dst_dir0, dst_dir1, dst_dir2]))
 }
 
-fn create_thinned_set_parallel<FT>(
+#[allow(clippy::too_many_arguments)]
+fn create_thinned_set_parallel<FT, W>(
     src_dir: impl AsRef<Path>,
     target_filter: FT,
-    wins: Arc<BoardSet>,
+    wins: &W,
     contains_put: bool,
     contains_move: bool,
     contains_remove: bool,
-    num_processes: usize,
-) -> std::io::Result<Vec<BoardSet>>
+    config: &Config,
+    rule: GameRule,
+    context: Context,
+) -> Result<Vec<BoardSet>>
 where
-    FT: Fn(&u64) -> bool + Send + Sync + 'static + Clone,
+    FT: Fn(&u64) -> bool + Sync,
+    W: WinLookup + Sync,
 {
     fn parallel_run(
-        target: &mut Vec<BoardSet>,
-        src_paths: &[impl AsRef<std::path::Path>],
-        core_process: impl Fn(std::path::PathBuf) -> BoardSet + Send + Sync + 'static,
-    ) {
-        let core_process = Arc::new(core_process);
-        let mut handlers = Vec::new();
-        for (i, src_path) in src_paths.iter().enumerate() {
-            let core_process = core_process.clone();
-            let src_path = src_path.as_ref().to_owned();
-            handlers.push(std::thread::spawn(move || {
-                println!("[Thread {i}] start");
-                let result = core_process(src_path);
-                println!("[Thread {i}] finish");
-                result
-            }));
-        }
-        let new_sets = handlers.into_iter().map(|x| x.join().unwrap());
+        target: &mut [BoardSet],
+        src_paths: &[PathBuf],
+        num_threads: usize,
+        context: &Context,
+        core_process: impl Fn(&std::path::Path) -> std::io::Result<BoardSet> + Sync,
+    ) -> Result<()> {
+        let new_sets = pool::run(
+            &pool::file_sizes(src_paths),
+            num_threads,
+            |i| context.clone().shard(i),
+            |i| {
+                let src_path = &src_paths[i];
+                core_process(src_path).with_context(|| context.clone().shard(i).path(src_path))
+            },
+        )?;
         for (n, (s, new)) in target.iter_mut().zip(new_sets).enumerate() {
             println!("[Thread Main] Absorbing {n} ...");
             s.reserve(new.capacity());
             s.absorb(new);
             println!("[Thread Main] Absorbed {n}");
         }
+        Ok(())
     }
 
-    let mut trimmed_sets: Vec<BoardSet> = (0..num_processes).map(|_| BoardSet::new()).collect();
-    let src_paths: Vec<PathBuf> = (0..num_processes)
+    let mut trimmed_sets: Vec<BoardSet> =
+        (0..config.num_processes).map(|_| BoardSet::new()).collect();
+    let src_paths: Vec<PathBuf> = (0..config.num_processes)
         .map(|i| distributed_path(src_dir.as_ref(), i))
         .collect();
 
-    parallel_run(&mut trimmed_sets, &src_paths, move |src_path| {
-        create_thinned_set_core(
-            src_path,
-            target_filter.clone(),
-            &wins,
-            contains_put,
-            contains_move,
-            contains_remove,
-        )
-        .expect("thinning error")
-    });
+    let num_threads = config.num_threads;
+    parallel_run(
+        &mut trimmed_sets,
+        &src_paths,
+        num_threads,
+        &context,
+        |src_path| {
+            create_thinned_set_core(
+                src_path,
+                &target_filter,
+                wins,
+                contains_put,
+                contains_move,
+                contains_remove,
+                rule,
+            )
+        },
+    )?;
     Ok(trimmed_sets)
 }
 
-fn create_thinned_set_core<FT>(
+fn create_thinned_set_core<FT, W>(
     src_path: impl AsRef<std::path::Path>,
     target_filter: FT,
-    wins: &BoardSet,
+    wins: &W,
     contains_put: bool,
     contains_move: bool,
     contains_remove: bool,
+    rule: GameRule,
 ) -> std::io::Result<BoardSet>
 where
     FT: Fn(&u64) -> bool,
+    W: WinLookup,
 {
     let filter = |&h0: &u64| {
         if !target_filter(&h0) {
@@ -603,13 +878,14 @@ where
         }
         let b0 = BoardBuilder::from_u64(h0).build_unchecked();
         use Color::*;
+        let contains_remove = contains_remove && *rule.is_remove_accepted();
         for a1 in b0.legal_actions(Red, contains_put, contains_move, contains_remove) {
             let b1 = b0.perform_unchecked_copied(a1);
-            if is_win1_or_finished(b1, Green) {
+            if is_win1_or_finished(b1, Green, rule) {
                 continue;
             }
 
-            if !wins.raw().contains(&b1.to_invariant_u64(Green)) {
+            if !wins.contains_hash(b1.to_invariant_u64(Green)) {
                 return false;
             }
         }
@@ -617,38 +893,221 @@ where
     };
     let mut set = BoardSet::new();
     set.raw_mut()
-        .load_filter(std::fs::File::open(src_path)?, filter)?;
+        .load_filter(storage::open_verified(src_path)?, filter)?;
     Ok(set)
 }
 
 // =====================================================================
 //  Gather
 // =====================================================================
+/// Gathers sets in `src_dir` into `dst_path` and returns the number of boards.
 pub fn gather(
     src_dir: impl AsRef<std::path::Path>,
     dst_path: impl AsRef<std::path::Path>,
-) -> std::io::Result<()> {
-    let entries = std::fs::read_dir(src_dir)?;
-    let mut paths = Vec::new();
-    for entry in entries {
-        let path = entry?.path();
-        if path.extension() != Some(&OsString::from("tdl")) {
-            continue;
+    num_doves: usize,
+) -> Result<usize> {
+    let context = Context::new(Phase::Gather).doves(num_doves);
+    let dst_path = dst_path.as_ref();
+    let set = load_dir(src_dir, &context)?;
+    println!("Saving to {dst_path:?} ...");
+    storage::save_set(&set, dst_path).with_context(|| context.clone().path(dst_path))?;
+    println!("Saved to {dst_path:?}");
+    Ok(set.len())
+}
+
+/// Adds wins in the table of `num_step` to the win index of `num_doves`
+/// and returns the number of wins in it.
+///
+/// The win index is a sorted table, which is merged with the new wins by streaming.
+/// Only tables not sorted, usually the new one alone, are sorted in memory.
+/// The win index up to the previous win step is removed after the new one is saved.
+pub fn update_win_index<P>(
+    factory: &PathFactory<P>,
+    num_step: usize,
+    num_doves: usize,
+) -> Result<usize>
+where
+    P: AsRef<Path>,
+{
+    let context = Context::new(Phase::Gather).doves(num_doves);
+    let mut paths = factory
+        .win_paths(num_step - 2, num_doves)
+        .with_context(|| context.clone())?;
+    paths.push(
+        factory
+            .verified_table_path(num_step, num_doves)
+            .with_context(|| context.clone())?,
+    );
+    let (sorted_paths, plain_paths): (Vec<_>, Vec<_>) = paths
+        .into_iter()
+        .partition(|path| Encoding::of(path).is_some_and(Encoding::is_sorted));
+
+    let mut hashes = Vec::new();
+    for path in plain_paths.iter() {
+        println!("Sorting win at {path:?} ...");
+        let context = || context.clone().path(path);
+        let mut reader = TableReader::open(path).with_context(context)?;
+        while let Some(hash) = reader.try_next().with_context(context)? {
+            hashes.push(hash);
         }
-        paths.push(path);
     }
-    let mut capacity = Capacity::new();
-    for path in paths.iter() {
-        capacity += BoardSet::required_capacity(std::fs::File::open(path)?);
+    hashes.sort_unstable();
+    hashes.dedup();
+
+    let table = factory.win_table_path(num_step, num_doves);
+    let dir = table.parent().unwrap();
+    std::fs::create_dir_all(dir).with_context(|| context.clone().path(dir))?;
+    println!("Saving win table to {table:?} ...");
+    let num_wins = merge::merge_runs_with(&sorted_paths, &hashes, &table)
+        .with_context(|| context.clone().path(&table))?;
+    factory
+        .save_win_table_manifest(num_step, num_doves, num_wins)
+        .with_context(|| context.clone().path(&table))?;
+    println!("Saved win table to {table:?}");
+
+    if num_step >= 5 {
+        factory
+            .remove_win_table(num_step - 2, num_doves)
+            .with_context(|| context.clone().path(factory.win_table_dir()))?;
     }
-    let mut set = BoardSet::with_capacity(capacity);
-    for path in paths {
-        println!("Loading {path:?} ...");
-        set.load(std::fs::File::open(&path)?)?;
-        println!("Loaded {path:?}");
+    Ok(num_wins)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PartialConfig;
+    use full_search_lose2::testing::{TempDir, LOSE2_6};
+    use std::str::FromStr;
+
+    const NUM_SHARDS: usize = 2;
+
+    /// Config backstepping boards with `num_doves` into all numbers of doves reached
+    fn config(root: &Path, num_doves: usize, external_sort: bool, max_chunk_size: usize) -> Config {
+        PartialConfig {
+            data_root: Some(root.to_owned()),
+            num_processes: Some(NUM_SHARDS),
+            num_threads: Some(1),
+            max_chunk_size: Some(max_chunk_size),
+            spill_threshold: Some(1),
+            min_doves: Some(num_doves - 1),
+            max_doves: Some(num_doves + 1),
+            external_sort: Some(external_sort),
+            ..Default::default()
+        }
+        .resolve()
+        .unwrap()
+    }
+
+    /// Makes directories of shards in `dir` as the pipeline does before backstep.
+    fn shard_dirs(dir: &Path, config: &Config) -> PathBuf {
+        for num in config.doves() {
+            for shard in 0..config.num_processes {
+                std::fs::create_dir_all(shard_dir(dove_dir(dir, num), shard)).unwrap();
+            }
+        }
+        dir.to_owned()
+    }
+
+    /// Reads hashes in all tables of each shard with `num` doves backstepped into `dir`.
+    fn read_shards(dir: &Path, num: usize) -> Vec<Vec<u64>> {
+        (0..NUM_SHARDS)
+            .map(|shard| {
+                let mut hashes = Vec::new();
+                for path in table_paths_in(shard_dir(dove_dir(dir, num), shard)).unwrap() {
+                    let mut reader = TableReader::open(&path).unwrap();
+                    while let Some(hash) = reader.try_next().unwrap() {
+                        hashes.push(hash);
+                    }
+                }
+                hashes
+            })
+            .collect()
+    }
+
+    #[test]
+    fn external_sort_is_same_as_backstep_in_memory() {
+        let dir = TempDir::new();
+        let rule = GameRule::new(true);
+
+        // Ancestors of some boards of lose in 2 with doves put three times
+        let mut src = BoardSet::new();
+        src.raw_mut().extend(LOSE2_6);
+        let src_path = dir.join("src.tdl");
+        let mut num_doves = 6;
+        for _ in 0..3 {
+            storage::save_set(&src, &src_path).unwrap();
+            let config = config(dir.path(), num_doves, false, usize::MAX);
+            let seed_dir = shard_dirs(&dir.join(format!("seed{num_doves}")), &config);
+            backstep(&src_path, &seed_dir, num_doves, &config, rule).unwrap();
+            num_doves += 1;
+            src = BoardSet::new();
+            for hashes in read_shards(&seed_dir, num_doves) {
+                src.raw_mut().extend(hashes);
+            }
+        }
+        storage::save_set(&src, &src_path).unwrap();
+
+        // Every batch is spilled as a run
+        let external = config(dir.path(), num_doves, true, src.len().div_ceil(4));
+        let runs_dir = shard_dirs(&dir.join("runs"), &external);
+        let num_runs =
+            backstep_with::<Vec<u64>>(&src_path, &runs_dir, num_doves, &external, rule).unwrap();
+        assert!(num_runs.values().all(|&n| n >= 4), "{num_runs:?}");
+
+        let in_memory = config(dir.path(), num_doves, false, usize::MAX);
+        let in_memory_dir = shard_dirs(&dir.join("in_memory"), &in_memory);
+        let external_dir = shard_dirs(&dir.join("external"), &external);
+        backstep(&src_path, &in_memory_dir, num_doves, &in_memory, rule).unwrap();
+        backstep(&src_path, &external_dir, num_doves, &external, rule).unwrap();
+
+        let len = |shards: &[Vec<u64>]| shards.iter().map(Vec::len).sum::<usize>();
+        let (mut len_runs, mut len_merged) = (0, 0);
+        for num in external.doves() {
+            let in_memory = read_shards(&in_memory_dir, num);
+            let external = read_shards(&external_dir, num);
+            len_runs += len(&read_shards(&runs_dir, num));
+            len_merged += len(&external);
+            for (mut in_memory, external) in in_memory.into_iter().zip(external) {
+                assert!(external.windows(2).all(|pair| pair[0] < pair[1]));
+                in_memory.sort_unstable();
+                assert_eq!(external, in_memory, "doves={num}");
+            }
+        }
+        // Runs share boards, which are merged into one
+        assert!(
+            len_runs > len_merged,
+            "{len_runs} in runs, {len_merged} merged"
+        );
+    }
+
+    #[test]
+    fn board_with_many_backward_actions_is_backstepped() {
+        let dir = TempDir::new();
+        let rule = GameRule::new(true);
+        // Green has only its boss on the field among doves of red spread over the field,
+        // which gives more backward actions than tokyodoves holds at once
+        let board = BoardBuilder::from_str("M Y; A;b B")
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut src = BoardSet::new();
+        src.insert(board);
+        let src_path = dir.join("src.tdl");
+        storage::save_set(&src, &src_path).unwrap();
+
+        let config = config(dir.path(), 5, false, usize::MAX);
+        let dst_dir = shard_dirs(&dir.join("dst"), &config);
+        backstep(&src_path, &dst_dir, 5, &config, rule).unwrap();
+        let num_parents: usize = config
+            .doves()
+            .map(|num| {
+                read_shards(&dst_dir, num)
+                    .iter()
+                    .map(Vec::len)
+                    .sum::<usize>()
+            })
+            .sum();
+        assert!(num_parents > 100, "{num_parents} parents");
     }
-    println!("Saving to {:?} ...", dst_path.as_ref());
-    set.save(std::fs::File::create(&dst_path)?)?;
-    println!("Saved to {:?}", dst_path.as_ref());
-    Ok(())
 }
@@ -76,3 +76,17 @@ pub fn distance_a(hash: u64, color: Color) -> u64 {
         (boss % 4).abs_diff(aniki % 4) + (boss / 4).abs_diff(aniki / 4)
     }
 }
+
+/// Scrambles bits of `hash` by SplitMix64,
+/// since bits of hashes of boards are far from uniform.
+pub fn mix(hash: u64) -> u64 {
+    let mut z = hash.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// Index of the shard, out of `num_shards`, which a board with `hash` belongs to
+pub fn shard_of(hash: u64, num_shards: usize) -> usize {
+    (mix(hash) % num_shards as u64) as usize
+}
@@ -0,0 +1,354 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::sorted_table;
+use crate::table_reader::{Encoding, TableReader};
+use full_search_lose2::storage;
+
+/// Reader of distinct hashes in sorted tables in ascending order
+#[derive(Debug)]
+pub struct MergedReader {
+    paths: Vec<PathBuf>,
+    readers: Vec<TableReader>,
+    /// Next hash of each reader
+    heads: BinaryHeap<Reverse<(u64, usize)>>,
+    last: Option<u64>,
+}
+
+impl MergedReader {
+    /// Opens sorted tables, which fails if any of them is not in a sorted format.
+    pub fn open(paths: &[impl AsRef<Path>]) -> std::io::Result<Self> {
+        let mut merged = Self {
+            paths: Vec::new(),
+            readers: Vec::new(),
+            heads: BinaryHeap::new(),
+            last: None,
+        };
+        for path in paths.iter() {
+            let path = path.as_ref();
+            if !Encoding::of(path).is_some_and(Encoding::is_sorted) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("{path:?} is not a sorted table"),
+                ));
+            }
+            let mut reader = TableReader::open(path)?;
+            if let Some(hash) = reader.try_next()? {
+                merged.heads.push(Reverse((hash, merged.readers.len())));
+            }
+            merged.paths.push(path.to_owned());
+            merged.readers.push(reader);
+        }
+        Ok(merged)
+    }
+
+    pub fn try_next(&mut self) -> std::io::Result<Option<u64>> {
+        while let Some(Reverse((hash, i))) = self.heads.pop() {
+            if let Some(next) = self.readers[i].try_next()? {
+                if next <= hash {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("{:?} is not sorted", self.paths[i]),
+                    ));
+                }
+                self.heads.push(Reverse((next, i)));
+            }
+            if self.last != Some(hash) {
+                self.last = Some(hash);
+                return Ok(Some(hash));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Writer of hashes given in ascending order in the format of tokyodoves,
+/// which needs no set in memory
+struct PlainWriter<W: Write> {
+    writer: W,
+    top: Option<u32>,
+}
+
+impl<W: Write> PlainWriter<W> {
+    fn push(&mut self, hash: u64) -> std::io::Result<()> {
+        let (top, bottom) = ((hash >> 32) as u32, hash as u32);
+        if self.top != Some(top) {
+            self.finish_top()?;
+            self.writer.write_all(&top.to_be_bytes())?;
+            self.top = Some(top);
+        }
+        self.writer.write_all(&bottom.to_be_bytes())
+    }
+
+    fn finish_top(&mut self) -> std::io::Result<()> {
+        if self.top.take().is_some() {
+            self.writer.write_all(&u32::MAX.to_be_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// Saves hashes in sorted tables `target_paths` except for those in sorted tables `win_paths`
+/// into `dst_path` in the format of tokyodoves, and returns the number of hashes saved.
+///
+/// All tables are streamed and merged at once,
+/// so memory is used only for buffers however large they are.
+pub fn merge_trim(
+    target_paths: &[impl AsRef<Path>],
+    win_paths: &[impl AsRef<Path>],
+    dst_path: impl AsRef<Path>,
+) -> std::io::Result<usize> {
+    let mut targets = MergedReader::open(target_paths)?;
+    let mut wins = MergedReader::open(win_paths)?;
+    let mut win = wins.try_next()?;
+    let next = || {
+        while let Some(hash) = targets.try_next()? {
+            while win.is_some_and(|win| win < hash) {
+                win = wins.try_next()?;
+            }
+            if win != Some(hash) {
+                return Ok(Some(hash));
+            }
+        }
+        Ok(None)
+    };
+    save_plain_stream(next, dst_path)
+}
+
+/// Saves hashes given by `next` in ascending order into `dst_path` in the format of tokyodoves
+/// and returns the number of them, which needs no set in memory.
+pub fn save_plain_stream<F>(mut next: F, dst_path: impl AsRef<Path>) -> std::io::Result<usize>
+where
+    F: FnMut() -> std::io::Result<Option<u64>>,
+{
+    let mut count = 0;
+    storage::save_with(dst_path, |w| {
+        let mut writer = PlainWriter {
+            writer: std::io::BufWriter::new(w),
+            top: None,
+        };
+        while let Some(hash) = next()? {
+            writer.push(hash)?;
+            count += 1;
+        }
+        writer.finish_top()?;
+        writer.writer.flush()
+    })?;
+    Ok(count)
+}
+
+/// Reader of distinct hashes in sorted tables and in a sorted slice in ascending order
+struct Union<'a> {
+    tables: MergedReader,
+    table_head: Option<u64>,
+    hashes: &'a [u64],
+}
+
+impl<'a> Union<'a> {
+    fn open(paths: &[impl AsRef<Path>], hashes: &'a [u64]) -> std::io::Result<Self> {
+        let mut tables = MergedReader::open(paths)?;
+        let table_head = tables.try_next()?;
+        Ok(Self {
+            tables,
+            table_head,
+            hashes,
+        })
+    }
+
+    fn try_next(&mut self) -> std::io::Result<Option<u64>> {
+        let next = match (self.table_head, self.hashes.first().copied()) {
+            (None, None) => return Ok(None),
+            (Some(head), Some(hash)) if hash < head => hash,
+            (Some(head), _) => {
+                self.table_head = self.tables.try_next()?;
+                head
+            }
+            (None, Some(hash)) => hash,
+        };
+        while self.hashes.first() == Some(&next) {
+            self.hashes = &self.hashes[1..];
+        }
+        Ok(Some(next))
+    }
+}
+
+/// Merges sorted runs into a sorted table of distinct hashes at `dst_path`
+/// and returns the number of them.
+///
+/// The runs are read twice, first to count the hashes and then to save them,
+/// so memory is used only for buffers.
+pub fn merge_runs(
+    run_paths: &[impl AsRef<Path>],
+    dst_path: impl AsRef<Path>,
+) -> std::io::Result<usize> {
+    merge_runs_with(run_paths, &[], dst_path)
+}
+
+/// Merges sorted runs and `hashes` in ascending order into a sorted table of distinct hashes
+/// at `dst_path` like [`merge_runs`], and returns the number of them.
+pub fn merge_runs_with(
+    run_paths: &[impl AsRef<Path>],
+    hashes: &[u64],
+    dst_path: impl AsRef<Path>,
+) -> std::io::Result<usize> {
+    let mut union = Union::open(run_paths, hashes)?;
+    let mut len = 0;
+    while union.try_next()?.is_some() {
+        len += 1;
+    }
+    let mut union = Union::open(run_paths, hashes)?;
+    sorted_table::save_stream(len, || union.try_next(), dst_path)?;
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compact_table;
+    use crate::error::{Context, Phase};
+    use full_search_lose2::testing::TempDir;
+    use tokyodoves::collections::BoardSet;
+
+    /// Saves `hashes` as a sorted table, or a compact one if `name` says so.
+    fn save(dir: &TempDir, name: &str, hashes: &[u64]) -> PathBuf {
+        let path = dir.join(name);
+        let mut iter = hashes.iter().copied();
+        if Encoding::of(&path) == Some(Encoding::Compact) {
+            compact_table::save_stream(hashes.len(), || Ok(iter.next()), &path).unwrap();
+        } else {
+            sorted_table::save_stream(hashes.len(), || Ok(iter.next()), &path).unwrap();
+        }
+        path
+    }
+
+    fn read_all(path: &Path) -> Vec<u64> {
+        let mut reader = TableReader::open(path).unwrap();
+        let mut hashes = Vec::new();
+        while let Some(hash) = reader.try_next().unwrap() {
+            hashes.push(hash);
+        }
+        hashes
+    }
+
+    /// Hashes whose upper halves are shared by some of them, as in the format of tokyodoves
+    fn hashes(range: std::ops::Range<u64>, step: u64) -> Vec<u64> {
+        range
+            .step_by(step as usize)
+            .map(|i| (i / 4) << 32 | i)
+            .collect()
+    }
+
+    #[test]
+    fn duplicates_across_runs_are_merged_once() {
+        let dir = TempDir::new();
+        let runs = [
+            save(&dir, "0.tds", &[1, 3, 5]),
+            save(&dir, "1.tdc", &[3, 4, 5, 9]),
+            save(&dir, "2.tds", &[]),
+            save(&dir, "3.tds", &[0, 9, 10]),
+        ];
+        let dst = dir.join("merged.tds");
+        assert_eq!(merge_runs(&runs, &dst).unwrap(), 7);
+        assert_eq!(read_all(&dst), [0, 1, 3, 4, 5, 9, 10]);
+    }
+
+    #[test]
+    fn hashes_are_merged_with_runs() {
+        let dir = TempDir::new();
+        let runs = [
+            save(&dir, "0.tds", &[1, 3, 5]),
+            save(&dir, "1.tds", &[3, 8]),
+        ];
+        let dst = dir.join("merged.tds");
+        assert_eq!(merge_runs_with(&runs, &[0, 3, 3, 8, 11], &dst).unwrap(), 6);
+        assert_eq!(read_all(&dst), [0, 1, 3, 5, 8, 11]);
+
+        let no_runs: [PathBuf; 0] = [];
+        assert_eq!(merge_runs_with(&no_runs, &[2, 2, 7], &dst).unwrap(), 2);
+        assert_eq!(read_all(&dst), [2, 7]);
+        assert_eq!(merge_runs_with(&no_runs, &[], &dst).unwrap(), 0);
+        assert!(read_all(&dst).is_empty());
+    }
+
+    #[test]
+    fn unsorted_tables_are_rejected() {
+        let dir = TempDir::new();
+        let plain = dir.join("0.tdl");
+        storage::save_set(&BoardSet::new(), &plain).unwrap();
+        assert!(merge_runs(&[plain], dir.join("merged.tds")).is_err());
+
+        let unsorted = save(&dir, "1.tds", &[5, 3]);
+        assert!(merge_runs(&[unsorted], dir.join("merged.tds")).is_err());
+    }
+
+    #[test]
+    fn wins_are_removed_from_targets() {
+        let dir = TempDir::new();
+        let targets = [
+            save(&dir, "0.tds", &hashes(0..100, 2)),
+            save(&dir, "1.tdc", &hashes(50..150, 3)),
+        ];
+        let wins = [
+            save(&dir, "win0.tds", &hashes(0..200, 5)),
+            save(&dir, "win1.tdc", &hashes(0..200, 7)),
+        ];
+        let dst = dir.join("trimmed.tdl");
+        let num_boards = merge_trim(&targets, &wins, &dst).unwrap();
+
+        let mut expected: Vec<u64> = hashes(0..100, 2);
+        expected.extend(hashes(50..150, 3));
+        expected.sort_unstable();
+        expected.dedup();
+        expected.retain(|hash| !wins.iter().any(|path| read_all(path).contains(hash)));
+        assert_eq!(num_boards, expected.len());
+        assert_eq!(read_all(&dst), expected);
+    }
+
+    #[test]
+    fn empty_shards_and_wins_are_merged() {
+        let dir = TempDir::new();
+        let empty = save(&dir, "empty.tds", &[]);
+        let targets = save(&dir, "0.tds", &hashes(0..10, 1));
+        let dst = dir.join("trimmed.tdl");
+        let no_paths: [PathBuf; 0] = [];
+
+        // Shards with no tables or only empty ones
+        assert_eq!(merge_trim(&no_paths, &[&targets], &dst).unwrap(), 0);
+        assert!(read_all(&dst).is_empty());
+        assert_eq!(merge_trim(&[&empty], &[&targets], &dst).unwrap(), 0);
+        assert!(read_all(&dst).is_empty());
+
+        // No wins or only empty ones
+        assert_eq!(merge_trim(&[&targets], &no_paths, &dst).unwrap(), 10);
+        assert_eq!(read_all(&dst), hashes(0..10, 1));
+        assert_eq!(merge_trim(&[&targets], &[&empty], &dst).unwrap(), 10);
+        assert_eq!(read_all(&dst), hashes(0..10, 1));
+    }
+
+    #[test]
+    fn merge_trim_is_same_as_thin_out_set() {
+        let dir = TempDir::new();
+        let targets = [
+            save(&dir, "0.tds", &hashes(0..1000, 3)),
+            save(&dir, "1.tdc", &hashes(500..1500, 4)),
+        ];
+        let wins = [save(&dir, "win.tds", &hashes(0..2000, 5))];
+
+        let merged = dir.join("merged.tdl");
+        merge_trim(&targets, &wins, &merged).unwrap();
+
+        let mut set = BoardSet::new();
+        for path in targets.iter() {
+            set.raw_mut().extend(read_all(path));
+        }
+        super::super::thin_out_set(&mut set, &wins, &Context::new(Phase::TrimSimply)).unwrap();
+        let thinned = dir.join("thinned.tdl");
+        storage::save_set(&set, &thinned).unwrap();
+
+        let mut thinned = read_all(&thinned);
+        thinned.sort_unstable();
+        assert_eq!(read_all(&merged), thinned);
+    }
+}
@@ -0,0 +1,130 @@
+use tokyodoves::collections::BoardSet;
+
+use super::hashutil::mix;
+use crate::win_lookup::WinLookup;
+
+/// Bits of a filter per win, with which about 1% of hashes not inserted pass it
+const BITS_PER_WIN: usize = 10;
+
+/// Number of bits set for a hash, all of which are in a block
+const NUM_PROBES: usize = 7;
+
+/// Number of words in a block, which fits in a cache line
+const BLOCK_WORDS: usize = 8;
+
+/// Blocked Bloom filter of hashes,
+/// which never rejects hashes inserted but may accept others.
+#[derive(Debug, Clone)]
+pub struct BloomFilter {
+    blocks: Vec<[u64; BLOCK_WORDS]>,
+}
+
+impl BloomFilter {
+    pub fn new(len: usize, hashes: impl IntoIterator<Item = u64>) -> Self {
+        let num_blocks = (len * BITS_PER_WIN).div_ceil(64 * BLOCK_WORDS).max(1);
+        let mut filter = Self {
+            blocks: vec![[0; BLOCK_WORDS]; num_blocks],
+        };
+        for hash in hashes {
+            let (block, probes) = filter.probes(hash);
+            for (word, bit) in probes {
+                filter.blocks[block][word] |= bit;
+            }
+        }
+        filter
+    }
+
+    /// Returns the block for `hash` and the words and bits in it.
+    fn probes(&self, hash: u64) -> (usize, impl Iterator<Item = (usize, u64)>) {
+        let h1 = mix(hash);
+        let block = ((h1 as u128 * self.blocks.len() as u128) >> 64) as usize;
+        let h2 = mix(h1);
+        let probes = (0..NUM_PROBES).map(move |i| {
+            let pos = (h2 >> (9 * i)) as usize % (64 * BLOCK_WORDS);
+            (pos / 64, 1 << (pos % 64))
+        });
+        (block, probes)
+    }
+
+    /// Returns `false` only if `hash` is not inserted.
+    pub fn may_contain(&self, hash: u64) -> bool {
+        let (block, mut probes) = self.probes(hash);
+        probes.all(|(word, bit)| self.blocks[block][word] & bit != 0)
+    }
+}
+
+/// Wins rejecting most hashes not in them by a [`BloomFilter`]
+/// before they are looked up exactly
+#[derive(Debug)]
+pub struct Prefiltered<W> {
+    filter: Option<BloomFilter>,
+    wins: W,
+}
+
+impl Prefiltered<BoardSet> {
+    /// Builds a filter of `wins` if `enabled`, otherwise looks up `wins` as is.
+    pub fn new(wins: BoardSet, enabled: bool) -> Self {
+        let filter = enabled.then(|| {
+            println!("Building a prefilter of {} wins ...", wins.len());
+            BloomFilter::new(wins.len(), wins.raw().iter())
+        });
+        Self { filter, wins }
+    }
+}
+
+impl<W: WinLookup> WinLookup for Prefiltered<W> {
+    fn contains_hash(&self, hash: u64) -> bool {
+        if let Some(filter) = &self.filter {
+            if !filter.may_contain(hash) {
+                return false;
+            }
+        }
+        self.wins.contains_hash(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEN: usize = 100_000;
+
+    /// Hashes close to each other, as those of boards share most bits
+    fn inserted() -> impl Iterator<Item = u64> {
+        (0..LEN as u64).map(|i| i << 1)
+    }
+
+    #[test]
+    fn inserted_hashes_are_never_rejected() {
+        let filter = BloomFilter::new(LEN, inserted());
+        assert!(inserted().all(|hash| filter.may_contain(hash)));
+    }
+
+    #[test]
+    fn most_other_hashes_are_rejected() {
+        let filter = BloomFilter::new(LEN, inserted());
+        let num_passed = inserted()
+            .map(|hash| hash | 1)
+            .filter(|&hash| filter.may_contain(hash))
+            .count();
+        // About 1% is expected, with a margin for blocks filled unevenly
+        assert!(num_passed < LEN / 50, "{num_passed} of {LEN} passed");
+    }
+
+    #[test]
+    fn empty_filter_rejects_everything() {
+        let filter = BloomFilter::new(0, []);
+        assert!(inserted().all(|hash| !filter.may_contain(hash)));
+    }
+
+    #[test]
+    fn prefiltered_wins_are_looked_up_exactly() {
+        let wins = full_search_lose2::testing::sample_set();
+        let hashes: Vec<u64> = wins.raw().iter().collect();
+        for enabled in [false, true] {
+            let prefiltered = Prefiltered::new(wins.clone(), enabled);
+            assert!(hashes.iter().all(|&hash| prefiltered.contains_hash(hash)));
+            assert!(!prefiltered.contains_hash(0));
+        }
+    }
+}
@@ -0,0 +1,290 @@
+use std::collections::HashMap;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use tokyodoves::{collections::BoardSet, game::GameRule, *};
+
+use super::{is_win1_or_finished, legal_actions_bwd, load_files, prefilter::Prefiltered};
+use full_search_lose2::{
+    error::{Context, Error, Phase, Result, ResultExt},
+    storage,
+};
+
+use crate::{
+    config::Config, path_factory::PathFactory, pool, table_reader::TableReader,
+    win_lookup::WinLookup,
+};
+
+/// Magic number at the head of a file of counters
+const MAGIC: &[u8; 8] = b"TDCOUNTS";
+
+/// Number of children of a board which are not known as wins yet
+pub(super) type Counter = u16;
+
+// =====================================================================
+//  Counters
+// =====================================================================
+/// Counters of boards with a number of doves, which are valid after `step` is computed
+/// with the numbers of doves in `doves`
+#[derive(Debug, Default)]
+struct Counters {
+    step: usize,
+    doves: (usize, usize),
+    remaining: HashMap<u64, Counter>,
+}
+
+impl Counters {
+    fn save(&self, path: &Path) -> std::io::Result<()> {
+        storage::save_with(path, |w| {
+            let mut w = BufWriter::new(w);
+            w.write_all(MAGIC)?;
+            for value in [self.step, self.doves.0, self.doves.1, self.remaining.len()] {
+                w.write_all(&(value as u64).to_le_bytes())?;
+            }
+            for (hash, remaining) in self.remaining.iter() {
+                w.write_all(&hash.to_le_bytes())?;
+                w.write_all(&remaining.to_le_bytes())?;
+            }
+            w.flush()
+        })
+    }
+
+    fn load(path: &Path) -> std::io::Result<Self> {
+        let mut r = BufReader::new(storage::open_verified(path)?);
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{path:?} is not a file of counters"),
+            ));
+        }
+        fn read_u64(r: &mut impl Read) -> std::io::Result<u64> {
+            let mut buf = [0; 8];
+            r.read_exact(&mut buf)?;
+            Ok(u64::from_le_bytes(buf))
+        }
+        let step = read_u64(&mut r)? as usize;
+        let doves = (read_u64(&mut r)? as usize, read_u64(&mut r)? as usize);
+        let len = read_u64(&mut r)? as usize;
+        let mut remaining = HashMap::with_capacity(len);
+        for _ in 0..len {
+            let hash = read_u64(&mut r)?;
+            let mut buf = [0; 2];
+            r.read_exact(&mut buf)?;
+            remaining.insert(hash, Counter::from_le_bytes(buf));
+        }
+        Ok(Self {
+            step,
+            doves,
+            remaining,
+        })
+    }
+}
+
+// =====================================================================
+//  Retrograde Analysis
+// =====================================================================
+/// Distinct parents with numbers of doves in `doves` of the board with `hash`,
+/// which are found by backstep as well
+pub(super) fn parents_of(hash: u64, doves: &RangeInclusive<usize>, rule: GameRule) -> Vec<u64> {
+    use Color::*;
+    let b0 = BoardBuilder::from_u64(hash).build_unchecked();
+    let mut parents = Vec::new();
+    for a1 in legal_actions_bwd(b0, rule) {
+        let b1 = b0.perform_unchecked_copied(a1);
+        if is_win1_or_finished(b1, Green, rule) || !doves.contains(&b1.count_doves_on_field()) {
+            continue;
+        }
+        parents.push(b1.to_invariant_u64(Green));
+    }
+    parents.sort_unstable();
+    parents.dedup();
+    parents
+}
+
+/// Number of distinct children of the board with `hash` which are not in `wins`,
+/// where children finished or winning at once are not counted as trimming on action does
+pub(super) fn count_remaining(hash: u64, wins: &impl WinLookup, rule: GameRule) -> usize {
+    use Color::*;
+    let b0 = BoardBuilder::from_u64(hash).build_unchecked();
+    let mut children = Vec::new();
+    for a1 in b0.legal_actions(Red, true, true, *rule.is_remove_accepted()) {
+        let b1 = b0.perform_unchecked_copied(a1);
+        if is_win1_or_finished(b1, Green, rule) {
+            continue;
+        }
+        let child = b1.to_invariant_u64(Green);
+        if !wins.contains_hash(child) {
+            children.push(child);
+        }
+    }
+    children.sort_unstable();
+    children.dedup();
+    children.len()
+}
+
+/// Finds loses with `num_doves` doves of step `num_step_to` by counters of children,
+/// saves them as the table and returns the number of them.
+///
+/// Each parent of new wins of the previous step has a counter of children not known as wins,
+/// which is decremented once for each of its children among the new wins,
+/// and it is a lose when the counter reaches zero.
+/// A parent seen for the first time is counted by generating its children once,
+/// and the counters are saved for the next win-to-lose step.
+/// Counters saved under other conditions are discarded, which only makes this slower.
+pub fn retrograde<P>(
+    factory: &PathFactory<P>,
+    num_step_to: usize,
+    num_doves: usize,
+    config: &Config,
+    rule: GameRule,
+) -> Result<usize>
+where
+    P: AsRef<Path>,
+{
+    let context = Context::new(Phase::Retrograde).doves(num_doves);
+    let num_from = num_step_to - 1;
+    let doves = config.doves();
+    let around: Vec<usize> = ((num_doves - 1).max(2)..=(num_doves + 1).min(12))
+        .filter(|n| doves.contains(n))
+        .collect();
+
+    // Wins so far, by which new parents are counted
+    let mut win_paths = Vec::new();
+    for n in around.iter() {
+        let paths = factory
+            .win_paths(num_from, *n)
+            .with_context(|| context.clone())?;
+        win_paths.extend(paths);
+    }
+    let wins = Prefiltered::new(load_files(&win_paths, &context)?, config.prefilter);
+
+    let counters_path = factory.counters_path(num_doves);
+    let expected = (num_step_to - 2, (config.min_doves, config.max_doves));
+    let mut counters = match Counters::load(&counters_path) {
+        Ok(counters) if (counters.step, counters.doves) == expected => counters,
+        _ => {
+            println!("No counters valid for step {num_step_to}, so all parents are counted");
+            Counters::default()
+        }
+    };
+    println!("Loaded {} counters", counters.remaining.len());
+
+    // Parents of new wins, each of which appears once for each of its children among them
+    let mut parents = Vec::new();
+    for n in around.iter() {
+        let path = factory
+            .verified_table_path(num_from, *n)
+            .with_context(|| context.clone())?;
+        let path_context = || context.clone().path(&path);
+        let mut reader = TableReader::open(&path).with_context(path_context)?;
+        let mut load_next = true;
+        while load_next {
+            let mut batch = Vec::new();
+            while batch.len() < config.max_chunk_size {
+                match reader.try_next().with_context(path_context)? {
+                    Some(hash) => batch.push(hash),
+                    None => {
+                        load_next = false;
+                        break;
+                    }
+                }
+            }
+            let pieces: Vec<&[u64]> = batch
+                .chunks(batch.len().div_ceil(config.num_processes).max(1))
+                .collect();
+            let sizes: Vec<u64> = pieces.iter().map(|piece| piece.len() as u64).collect();
+            let found = pool::run(
+                &sizes,
+                config.num_threads,
+                |i| context.clone().shard(i),
+                |i| {
+                    Ok(pieces[i]
+                        .iter()
+                        .flat_map(|hash| parents_of(*hash, &(num_doves..=num_doves), rule))
+                        .collect::<Vec<u64>>())
+                },
+            )?;
+            parents.extend(found.into_iter().flatten());
+        }
+    }
+    parents.sort_unstable();
+    let mut decrements: Vec<(u64, usize)> = Vec::new();
+    for parent in parents {
+        match decrements.last_mut() {
+            Some((last, count)) if *last == parent => *count += 1,
+            _ => decrements.push((parent, 1)),
+        }
+    }
+    println!("Found {} parents of new wins", decrements.len());
+
+    // `None` for parents which are wins themselves
+    let pieces: Vec<&[(u64, usize)]> = decrements
+        .chunks(decrements.len().div_ceil(config.num_processes).max(1))
+        .collect();
+    let sizes: Vec<u64> = pieces.iter().map(|piece| piece.len() as u64).collect();
+    let updates = pool::run(
+        &sizes,
+        config.num_threads,
+        |i| context.clone().shard(i),
+        |i| {
+            let mut updates = Vec::with_capacity(pieces[i].len());
+            for &(parent, count) in pieces[i] {
+                if wins.contains_hash(parent) {
+                    updates.push((parent, None));
+                    continue;
+                }
+                let remaining = match counters.remaining.get(&parent) {
+                    Some(&remaining) => {
+                        (remaining as usize).checked_sub(count).ok_or_else(|| {
+                            Error::InvalidInput {
+                                context: context.clone().shard(i),
+                                source: anyhow::anyhow!(
+                                "counter of {parent:#x} is {remaining}, less than {count} new wins"
+                            ),
+                            }
+                        })?
+                    }
+                    None => count_remaining(parent, &wins, rule),
+                };
+                updates.push((parent, Some(remaining)));
+            }
+            Ok(updates)
+        },
+    )?;
+
+    let mut loses = BoardSet::new();
+    for (parent, remaining) in updates.into_iter().flatten() {
+        match remaining {
+            Some(0) => {
+                counters.remaining.remove(&parent);
+                loses.raw_mut().insert(parent);
+            }
+            Some(remaining) => {
+                let remaining = Counter::try_from(remaining).map_err(|_| Error::InvalidInput {
+                    context: context.clone(),
+                    source: anyhow::anyhow!("{parent:#x} has too many children: {remaining}"),
+                })?;
+                counters.remaining.insert(parent, remaining);
+            }
+            None => {
+                counters.remaining.remove(&parent);
+            }
+        }
+    }
+
+    let dst_path = factory.table_path(num_step_to, num_doves);
+    println!("Saving {} loses to {dst_path:?} ...", loses.len());
+    storage::save_set(&loses, &dst_path).with_context(|| context.clone().path(&dst_path))?;
+
+    let dir = factory.counters_dir();
+    std::fs::create_dir_all(&dir).with_context(|| context.clone().path(&dir))?;
+    (counters.step, counters.doves) = (num_step_to, (config.min_doves, config.max_doves));
+    println!("Saving {} counters ...", counters.remaining.len());
+    counters
+        .save(&counters_path)
+        .with_context(|| context.clone().path(&counters_path))?;
+    Ok(loses.len())
+}
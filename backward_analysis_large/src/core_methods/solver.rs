@@ -0,0 +1,386 @@
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use full_search_lose2::bitboard::{
+    canonicalize, find_symmetries, is_isolated, map_bits, HotBitIter,
+};
+use full_search_lose2::rule::RuleVariant;
+use full_search_lose2::search::{distinct_boss_pairs, map_positions};
+use tokyodoves::{collections::BoardSet, game::GameRule, *};
+
+use super::{
+    is_win1_or_finished,
+    retrograde::{count_remaining, parents_of, Counter},
+    update_win_index,
+};
+use full_search_lose2::{
+    error::{Context, Error, Phase, Result, ResultExt},
+    storage,
+};
+
+use crate::{path_factory::PathFactory, pool, win_lookup::WinLookup};
+
+/// Number of pieces into which work is divided for each thread
+const PIECES_PER_THREAD: usize = 4;
+
+// =====================================================================
+//  Enumeration
+// =====================================================================
+// The `n`-th bit of a shape represents the square at the row `n / 4` and the column `n % 4`.
+
+/// Shapes of the field with numbers of doves in `doves` where no dove is isolated,
+/// one for each class under translations and symmetries
+fn shapes_of(doves: &RangeInclusive<usize>) -> Vec<u16> {
+    let mut shapes: Vec<u16> = (1..=u16::MAX)
+        .filter(|bits| doves.contains(&(bits.count_ones() as usize)))
+        .filter(|&bits| !is_isolated(bits))
+        .map(canonicalize)
+        .collect();
+    shapes.sort_unstable();
+    shapes.dedup();
+    shapes
+}
+
+/// Pushes hashes of boards with the rest of doves placed on `cells` in all the ways,
+/// except those finished or winning at once
+/// and those mapped onto smaller placements by any of `symmetries`.
+fn place_rest(
+    positions: &mut [[u16; 6]; 2],
+    cells: &[u16],
+    used: u16,
+    symmetries: &[&[usize; 16]],
+    rule: GameRule,
+    hashes: &mut Vec<u64>,
+) {
+    let Some((&cell, cells)) = cells.split_first() else {
+        if symmetries
+            .iter()
+            .any(|perm| map_positions(positions, perm) < *positions)
+        {
+            return;
+        }
+        let board = BoardBuilder::from_u16_bits(*positions).build_unchecked();
+        if !is_win1_or_finished(board, Color::Red, rule) {
+            hashes.push(board.to_invariant_u64(Color::Red));
+        }
+        return;
+    };
+    // Doves other than bosses, five of each color
+    for piece in (0..10).filter(|piece| used >> piece & 1 == 0) {
+        let (color, dove) = (piece / 5, piece % 5 + 1);
+        positions[color][dove] = cell;
+        place_rest(
+            positions,
+            cells,
+            used | 1 << piece,
+            symmetries,
+            rule,
+            hashes,
+        );
+        positions[color][dove] = 0;
+    }
+}
+
+/// Distinct hashes of boards of `shape`, except those finished or winning at once.
+///
+/// Boards mapped onto each other by a symmetry of the shape have the same hash,
+/// so only the smallest placement of each class is made as the full search does.
+fn boards_of(shape: u16, rule: GameRule) -> Vec<u64> {
+    let symmetries = find_symmetries(shape);
+    let mut hashes = Vec::new();
+    for (red_boss, green_boss) in distinct_boss_pairs(shape, &symmetries) {
+        let pair_symmetries: Vec<&[usize; 16]> = symmetries
+            .iter()
+            .filter(|perm| map_bits(red_boss, perm) == red_boss)
+            .filter(|perm| map_bits(green_boss, perm) == green_boss)
+            .collect();
+        let mut positions = [[red_boss, 0, 0, 0, 0, 0], [green_boss, 0, 0, 0, 0, 0]];
+        let rest: Vec<u16> = HotBitIter::new(shape & !(red_boss | green_boss)).collect();
+        place_rest(
+            &mut positions,
+            &rest,
+            0,
+            &pair_symmetries,
+            rule,
+            &mut hashes,
+        );
+    }
+    hashes.sort_unstable();
+    hashes.dedup();
+    hashes
+}
+
+// =====================================================================
+//  Retrograde Analysis
+// =====================================================================
+/// All boards in the range with the steps at which they are decided, where 0 means not yet
+struct Nodes {
+    hashes: Vec<u64>,
+    steps: Vec<u16>,
+}
+
+impl Nodes {
+    /// Index of a board, which fails if it is not enumerated
+    fn index(&self, hash: u64, context: &Context) -> Result<usize> {
+        self.hashes
+            .binary_search(&hash)
+            .map_err(|_| Error::InvalidInput {
+                context: context.clone(),
+                source: anyhow::anyhow!("{hash:#x} is reached but not enumerated"),
+            })
+    }
+}
+
+impl WinLookup for Nodes {
+    fn contains_hash(&self, hash: u64) -> bool {
+        self.hashes
+            .binary_search(&hash)
+            .is_ok_and(|i| self.steps[i] % 2 == 1)
+    }
+}
+
+/// Runs `job` on pieces of `items` and returns the results in order.
+fn run_pieces<T, U, F>(items: &[T], num_threads: usize, context: &Context, job: F) -> Result<Vec<U>>
+where
+    T: Sync,
+    U: Send,
+    F: Fn(&[T]) -> U + Sync,
+{
+    let pieces: Vec<&[T]> = items
+        .chunks(items.len().div_ceil(num_threads * PIECES_PER_THREAD).max(1))
+        .collect();
+    let sizes: Vec<u64> = pieces.iter().map(|piece| piece.len() as u64).collect();
+    pool::run(
+        &sizes,
+        num_threads,
+        |i| context.clone().shard(i),
+        |i| Ok(job(pieces[i])),
+    )
+}
+
+/// Whether all children of the board with `hash` are finished or winning at once
+fn is_lose2(hash: u64, rule: GameRule) -> bool {
+    use Color::*;
+    let b0 = BoardBuilder::from_u64(hash).build_unchecked();
+    b0.legal_actions(Red, true, true, *rule.is_remove_accepted())
+        .into_iter()
+        .all(|a1| is_win1_or_finished(b0.perform_unchecked_copied(a1), Green, rule))
+}
+
+/// Solves all boards with numbers of doves in `doves` in memory
+/// and saves the tables of all the steps as the backward analysis does,
+/// returning the number of the last step, which is empty.
+///
+/// Boards are enumerated shape by shape, and loses in 2 are those all of whose children
+/// are finished or winning at once.
+/// Then parents of new loses become wins and those of new wins have their counters decremented
+/// as in [`super::retrograde::retrograde`], until no board is found.
+/// Boards with doves out of `doves` are regarded as nonexistent, so are never wins
+/// as the backward analysis in the same range does.
+pub fn solve<P>(
+    factory: &PathFactory<P>,
+    doves: RangeInclusive<usize>,
+    num_threads: usize,
+    rule_variant: RuleVariant,
+) -> Result<usize>
+where
+    P: AsRef<Path>,
+{
+    let context = Context::new(Phase::Solve);
+    let rule = rule_variant.to_game_rule();
+    let dir = factory.namespace_dir();
+    if dir.exists() {
+        return Err(Error::InvalidArgument {
+            context,
+            message: format!("{dir:?} already exists"),
+        });
+    }
+
+    let shapes = shapes_of(&doves);
+    println!("Enumerating boards of {} shapes ...", shapes.len());
+    let found = run_pieces(&shapes, num_threads, &context, |shapes| {
+        shapes
+            .iter()
+            .flat_map(|&shape| boards_of(shape, rule))
+            .collect::<Vec<u64>>()
+    })?;
+    let mut hashes: Vec<u64> = found.into_iter().flatten().collect();
+    hashes.sort_unstable();
+    hashes.dedup();
+    println!("Enumerated {} boards", hashes.len());
+
+    let found = run_pieces(&hashes, num_threads, &context, |hashes| {
+        hashes
+            .iter()
+            .copied()
+            .filter(|&hash| is_lose2(hash, rule))
+            .collect::<Vec<u64>>()
+    })?;
+    let mut frontier: Vec<u64> = found.into_iter().flatten().collect();
+    let mut nodes = Nodes {
+        steps: vec![0; hashes.len()],
+        hashes,
+    };
+    let mut counters: Vec<Option<Counter>> = vec![None; nodes.hashes.len()];
+    let mut num_step = 2;
+    for hash in frontier.iter() {
+        let i = nodes.index(*hash, &context)?;
+        nodes.steps[i] = num_step as u16;
+    }
+    println!("Step {num_step}: {} boards", frontier.len());
+
+    while !frontier.is_empty() {
+        num_step += 1;
+        let context = context.clone().path(factory.num_dir(num_step));
+        let found = run_pieces(&frontier, num_threads, &context, |hashes| {
+            hashes
+                .iter()
+                .flat_map(|&hash| parents_of(hash, &doves, rule))
+                .collect::<Vec<u64>>()
+        })?;
+        let mut parents: Vec<u64> = found.into_iter().flatten().collect();
+        parents.sort_unstable();
+
+        let mut next = Vec::new();
+        if num_step % 2 == 1 {
+            // lose -> win
+            parents.dedup();
+            for parent in parents {
+                let i = nodes.index(parent, &context)?;
+                if nodes.steps[i] == 0 {
+                    nodes.steps[i] = num_step as u16;
+                    next.push(parent);
+                }
+            }
+        } else {
+            // win -> lose
+            let mut decrements: Vec<(usize, usize)> = Vec::new();
+            for parent in parents {
+                let i = nodes.index(parent, &context)?;
+                match decrements.last_mut() {
+                    Some((last, count)) if *last == i => *count += 1,
+                    _ if nodes.steps[i] == 0 => decrements.push((i, 1)),
+                    _ => {}
+                }
+            }
+            // Parents seen for the first time are counted after all wins so far are known
+            let uncounted: Vec<u64> = decrements
+                .iter()
+                .filter(|(i, _)| counters[*i].is_none())
+                .map(|(i, _)| nodes.hashes[*i])
+                .collect();
+            let counted = run_pieces(&uncounted, num_threads, &context, |hashes| {
+                hashes
+                    .iter()
+                    .map(|&hash| count_remaining(hash, &nodes, rule))
+                    .collect::<Vec<usize>>()
+            })?;
+            let mut counted = counted.into_iter().flatten();
+            for (i, count) in decrements {
+                let hash = nodes.hashes[i];
+                let remaining = match counters[i] {
+                    Some(remaining) => {
+                        (remaining as usize).checked_sub(count).ok_or_else(|| {
+                            Error::InvalidInput {
+                                context: context.clone(),
+                                source: anyhow::anyhow!(
+                                "counter of {hash:#x} is {remaining}, less than {count} new wins"
+                            ),
+                            }
+                        })?
+                    }
+                    None => counted.next().expect("counted for each uncounted parent"),
+                };
+                if remaining == 0 {
+                    counters[i] = None;
+                    nodes.steps[i] = num_step as u16;
+                    next.push(hash);
+                } else {
+                    let remaining =
+                        Counter::try_from(remaining).map_err(|_| Error::InvalidInput {
+                            context: context.clone(),
+                            source: anyhow::anyhow!("{hash:#x} has too many children: {remaining}"),
+                        })?;
+                    counters[i] = Some(remaining);
+                }
+            }
+        }
+        println!("Step {num_step}: {} boards", next.len());
+        frontier = next;
+    }
+
+    let mut tables: HashMap<(usize, usize), BoardSet> = HashMap::new();
+    for (&hash, &step) in nodes.hashes.iter().zip(nodes.steps.iter()) {
+        if step == 0 {
+            continue;
+        }
+        let num_doves = BoardBuilder::from_u64(hash)
+            .build_unchecked()
+            .count_doves_on_field();
+        tables
+            .entry((step as usize, num_doves))
+            .or_default()
+            .raw_mut()
+            .insert(hash);
+    }
+    drop(nodes);
+
+    for n in 2..=num_step {
+        let dir = factory.num_dir(n);
+        std::fs::create_dir_all(&dir).with_context(|| context.clone().path(&dir))?;
+        // Recorded before tables, without which a directory of tables is regarded as of the rule with removing
+        rule_variant
+            .record(&dir)
+            .with_context(|| context.clone().path(&dir))?;
+        for num_doves in doves.clone() {
+            let context = context.clone().doves(num_doves);
+            let path = factory.table_path(n, num_doves);
+            let set = tables.remove(&(n, num_doves)).unwrap_or_default();
+            println!("Saving {} boards to {path:?} ...", set.len());
+            storage::save_set(&set, &path).with_context(|| context.clone().path(&path))?;
+            factory
+                .save_manifest(n, num_doves, set.len())
+                .with_context(|| context.clone().path(&path))?;
+        }
+        factory
+            .record_namespace(n)
+            .with_context(|| context.clone().path(&dir))?;
+        if n % 2 == 1 {
+            for num_doves in doves.clone() {
+                update_win_index(factory, n, num_doves)?;
+            }
+        }
+    }
+    Ok(num_step)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use full_search_lose2::{bitboard::occupancy_of_hash, search::pack_lose2, testing::LOSE2_6};
+    use tokyodoves::collections::BoardSet;
+
+    #[test]
+    fn loses_in_2_agree_with_full_search_shape_by_shape() {
+        for rule_variant in [RuleVariant::Remove, RuleVariant::NoRemove] {
+            let rule = rule_variant.to_game_rule();
+            for hash in LOSE2_6 {
+                let shape = canonicalize(occupancy_of_hash(hash));
+                let mut pool = BoardSet::new();
+                pack_lose2(&mut pool, shape, rule);
+                let mut expected: Vec<u64> = pool.raw().iter().collect();
+                expected.sort_unstable();
+
+                let solved: Vec<u64> = boards_of(shape, rule)
+                    .into_iter()
+                    .filter(|&hash| is_lose2(hash, rule))
+                    .collect();
+                assert_eq!(solved, expected, "{rule_variant:?} on {shape:#06x}");
+                if rule_variant == RuleVariant::Remove {
+                    assert!(solved.contains(&hash));
+                }
+            }
+        }
+    }
+}
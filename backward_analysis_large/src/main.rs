@@ -1,151 +1,302 @@
+pub(crate) mod compact_table;
+pub(crate) mod config;
 pub(crate) mod core_methods;
 pub(crate) mod path_factory;
+pub(crate) mod pool;
+pub(crate) mod scheduler;
+pub(crate) mod sorted_table;
+pub(crate) mod table_reader;
+pub(crate) mod win_lookup;
 
 use clap::Parser;
+use config::{Algorithm, Config, PartialConfig};
+use full_search_lose2::{
+    error::{self, Context, Phase, ResultExt},
+    rule::RuleVariant,
+};
 use path_factory::*;
+use scheduler::{Graph, Task};
 use std::path::{Path, PathBuf};
-
-use crate::core_methods::trim_on_action;
+use tokyodoves::game::GameRule;
 
 // **********************************************************
 //  Building Blocks
 // **********************************************************
-fn x_to_x_common<P>(
-    factory: &PathFactory<P>,
-    num_from: usize,
-    num_processes: usize,
-    del_tmp_files: bool,
-) -> anyhow::Result<()>
-where
-    P: AsRef<Path>,
-{
-    let num_to = num_from + 1;
+/// Sets in memory take about this many times the bytes of their files
+const MEMORY_PER_FILE_BYTE: u64 = 3;
 
-    // --- BackStep ---
-    println!("### PHASE: BACKSTEP ###");
-    for num_doves in 2..=12 {
-        std::fs::create_dir_all(dove_dir(factory.backstepped(num_to), num_doves))?;
-    }
-
-    for num_doves in 2..=12 {
-        println!("=== num_doves={num_doves} ===");
-        let src_path = factory
-            .num_dir(num_from)
-            .join(format!("{num_doves:0>2}.tdl"));
-        let dst_dir = factory.backstepped(num_to);
-        core_methods::backstep(src_path, dst_dir, num_doves, num_processes, 400_000_000)?;
-    }
+/// Bytes taken by a board in memory
+const BYTES_PER_BOARD: u64 = 8;
 
-    // --- Redistribute ---
-    println!("### PHASE: REDISTRIBUTE ###");
-    for num_doves in 2..=12 {
-        println!("=== num_doves={num_doves} ===");
-        let src_dir = dove_dir(factory.backstepped(num_to), num_doves);
-        let dst_dir = dove_dir(factory.redistributed(num_to), num_doves);
-        std::fs::create_dir_all(&dst_dir)?;
-        core_methods::redistribute(&src_dir, dst_dir, num_processes)?;
-        if del_tmp_files {
-            std::fs::remove_dir_all(src_dir)?;
+/// Builds the graph of tasks computing step `num_to`.
+///
+/// Boards backstepped from those with `n` doves have `n - 1`, `n` or `n + 1` doves,
+/// and trimming on action for wins with `n` doves takes over what is done for `n - 1`.
+fn build_graph(num_to: usize, config: &Config) -> Graph {
+    use Phase::*;
+    let task = Task::new;
+    let mut graph = Graph::new();
+    // Tasks out of the range of doves are not computed at all, so nothing waits for them
+    let add = |graph: &mut Graph, t: Task, deps: &[Task]| {
+        let deps: Vec<Task> = deps
+            .iter()
+            .filter(|dep| graph.contains(dep))
+            .copied()
+            .collect();
+        graph.add(t, deps);
+    };
+    if num_to.is_multiple_of(2) && config.algorithm == Algorithm::Retrograde {
+        for num_doves in config.doves() {
+            graph.add(task(Retrograde, num_doves), []);
         }
+        return graph;
+    }
+    for num_doves in config.doves() {
+        graph.add(task(Backstep, num_doves), []);
     }
-    if del_tmp_files {
-        std::fs::remove_dir_all(factory.backstepped(num_to))?;
+    for num_doves in config.doves() {
+        let deps: Vec<Task> = (num_doves - 1..=num_doves + 1)
+            .map(|n| task(Backstep, n))
+            .collect();
+        add(&mut graph, task(TrimSimply, num_doves), &deps);
     }
 
-    // --- Trim Simple ---
-    println!("### PHASE: TRIM SIMPLE ###");
-    for num_doves in 2..=12 {
-        println!("=== num_doves={num_doves} ===");
-        let src_dir = dove_dir(factory.redistributed(num_to), num_doves);
-        let dst_dir = dove_dir(factory.trimmed_simply(num_to), num_doves);
-        std::fs::create_dir_all(&dst_dir)?;
-        let win_paths = factory.win_paths(num_from, num_doves);
-        core_methods::trim_simply(&src_dir, dst_dir, win_paths, num_processes, num_processes)?;
-        if del_tmp_files {
-            std::fs::remove_dir_all(src_dir)?;
+    match num_to % 2 {
+        // lose -> win
+        1 => {
+            for num_doves in config.doves() {
+                add(
+                    &mut graph,
+                    task(Gather, num_doves),
+                    &[task(TrimSimply, num_doves)],
+                );
+            }
+        }
+        // win -> lose
+        _ => {
+            // Boards in the range can move to those with one more or less doves
+            let num_doves_of_wins_min = (config.min_doves - 1).max(2);
+            let num_doves_of_wins_max = (config.max_doves + 1).min(12);
+            for n in num_doves_of_wins_min..=num_doves_of_wins_max {
+                let deps = [
+                    task(TrimOnAction, n - 1),
+                    task(TrimSimply, n),
+                    task(TrimSimply, n + 1),
+                ];
+                add(&mut graph, task(TrimOnAction, n), &deps);
+            }
+            for num_doves in config.doves() {
+                let deps = [task(TrimOnAction, (num_doves + 1).min(12))];
+                add(&mut graph, task(Gather, num_doves), &deps);
+            }
         }
     }
+    graph
+}
 
-    if del_tmp_files {
-        std::fs::remove_dir_all(factory.redistributed(num_to))?;
-    }
-    Ok(())
+/// Size of a file, where a missing file is empty
+fn file_size(path: impl AsRef<Path>) -> u64 {
+    std::fs::metadata(path).map(|m| m.len()).unwrap_or(0)
 }
 
-fn lose_to_win<P>(
+/// Bytes of the wins with `num_doves` doves loaded up to `num_step_ceil`,
+/// which are those of the win index alone if it covers all of them.
+/// Wins are not verified here, since the task verifies them when it loads them.
+fn win_bytes<P>(factory: &PathFactory<P>, num_step_ceil: usize, num_doves: usize) -> u64
+where
+    P: AsRef<Path>,
+{
+    factory
+        .unverified_win_paths(num_step_ceil, num_doves)
+        .iter()
+        .map(file_size)
+        .sum()
+}
+
+/// Sizes of tables in `dir` in descending order, where a missing directory has none
+fn table_sizes(dir: impl AsRef<Path>) -> Vec<u64> {
+    let mut sizes: Vec<u64> = std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|entry| table_reader::Encoding::of(entry.path()).is_some())
+        .filter_map(|entry| entry.metadata().ok())
+        .map(|metadata| metadata.len())
+        .collect();
+    sizes.sort_unstable_by(|a, b| b.cmp(a));
+    sizes
+}
+
+/// Estimates roughly the peak memory used by `task` from the sizes of its inputs.
+fn estimate_memory<P>(task: Task, factory: &PathFactory<P>, num_to: usize, config: &Config) -> u64
+where
+    P: AsRef<Path>,
+{
+    let num_from = num_to - 1;
+    let num_doves = task.num_doves;
+    let file_bytes: u64 = match task.phase {
+        Phase::Backstep => {
+            // Sources are streamed, so at most a batch and spilled outputs are in memory
+            let bound =
+                (config.max_chunk_size + 3 * config.spill_threshold) as u64 * BYTES_PER_BOARD;
+            let src_path = stored_path(factory.table_path(num_from, num_doves));
+            return (file_size(src_path) * MEMORY_PER_FILE_BYTE).min(bound);
+        }
+        Phase::TrimSimply => {
+            // As many shards as threads at a time
+            let src_dir = dove_dir(factory.backstepped(num_to), num_doves);
+            let mut sizes: Vec<u64> = (0..config.num_processes)
+                .map(|shard| table_sizes(shard_dir(&src_dir, shard)).iter().sum())
+                .collect();
+            sizes.sort_unstable_by(|a, b| b.cmp(a));
+            sizes.into_iter().take(config.num_threads).sum()
+        }
+        Phase::TrimOnAction => {
+            // Wins and all results of trimming
+            let wins = win_bytes(factory, num_from, num_doves);
+            let targets: u64 = [
+                dove_dir(factory.trimmed_move(num_to), num_doves - 1),
+                dove_dir(factory.trimmed_remove(num_to), num_doves),
+                dove_dir(factory.trimmed_simply(num_to), num_doves + 1),
+            ]
+            .iter()
+            .flat_map(table_sizes)
+            .sum();
+            wins + targets
+        }
+        Phase::Gather => {
+            let src_dir = match num_to % 2 {
+                1 => factory.trimmed_simply(num_to),
+                _ => factory.trimmed_put(num_to),
+            };
+            table_sizes(dove_dir(src_dir, num_doves)).iter().sum()
+        }
+        Phase::Retrograde => {
+            // Wins around and counters, which take about 10 bytes per board in a file
+            let wins: u64 = (num_doves - 1..=num_doves + 1)
+                .filter(|n| config.doves().contains(n))
+                .map(|n| win_bytes(factory, num_from, n))
+                .sum();
+            wins + file_size(factory.counters_path(num_doves))
+        }
+        Phase::Solve => unreachable!("the solver is never scheduled"),
+    };
+    file_bytes * MEMORY_PER_FILE_BYTE
+}
+
+/// Runs `task` of the step computing `num_from + 1`.
+fn run_task<P>(
+    task: Task,
     factory: &PathFactory<P>,
     num_from: usize,
-    num_processes: usize,
-    del_tmp_files: bool,
-) -> anyhow::Result<()>
+    config: &Config,
+    rule: GameRule,
+) -> error::Result<()>
 where
     P: AsRef<Path>,
 {
     let num_to = num_from + 1;
+    let num_doves = task.num_doves;
+    let num_processes = config.num_processes;
+    let del_tmp_files = config.del_tmp_files;
+    let context = || Context::new(task.phase).doves(num_doves);
 
-    // x_to_x_common
-    x_to_x_common(factory, num_from, num_processes, del_tmp_files)?;
-
-    // Gather
-    println!("### PHASE: GATHER ###");
-    std::fs::create_dir_all(factory.num_dir(num_to))?;
-    for num_doves in 2..=12 {
-        println!("=== num_doves={num_doves} ===");
-        core_methods::gather(
-            dove_dir(factory.trimmed_simply(num_to), num_doves),
-            factory.num_dir(num_to).join(format!("{num_doves:0>2}.tdl")),
-        )?;
-    }
-
-    if del_tmp_files {
-        std::fs::remove_dir_all(factory.num_tmp_dir(num_to))?;
+    match task.phase {
+        Phase::Backstep => {
+            let src_path = factory
+                .verified_table_path(num_from, num_doves)
+                .with_context(context)?;
+            let dst_dir = factory.backstepped(num_to);
+            core_methods::backstep(src_path, dst_dir, num_doves, config, rule)?;
+        }
+        Phase::TrimSimply => {
+            let src_dir = dove_dir(factory.backstepped(num_to), num_doves);
+            let dst_dir = dove_dir(factory.trimmed_simply(num_to), num_doves);
+            std::fs::create_dir_all(&dst_dir).with_context(|| context().path(&dst_dir))?;
+            let win_paths = factory
+                .win_paths(num_from, num_doves)
+                .with_context(context)?;
+            let sorted_win_paths = match config.merge_trim {
+                true => factory
+                    .sorted_win_paths(num_from, num_doves)
+                    .with_context(context)?,
+                false => None,
+            };
+            core_methods::trim_simply(
+                &src_dir,
+                dst_dir,
+                win_paths,
+                sorted_win_paths,
+                num_processes,
+                config.num_threads,
+                num_doves,
+            )?;
+            if del_tmp_files {
+                std::fs::remove_dir_all(&src_dir).with_context(|| context().path(&src_dir))?;
+            }
+        }
+        Phase::TrimOnAction => {
+            core_methods::trim_on_action(num_doves, num_to, factory, config, rule)?;
+        }
+        Phase::Retrograde => {
+            let num_boards =
+                core_methods::retrograde::retrograde(factory, num_to, num_doves, config, rule)?;
+            factory
+                .save_manifest(num_to, num_doves, num_boards)
+                .with_context(context)?;
+        }
+        Phase::Gather => {
+            let src_dir = match num_to % 2 {
+                1 => factory.trimmed_simply(num_to),
+                _ => factory.trimmed_put(num_to),
+            };
+            let num_boards = core_methods::gather(
+                dove_dir(src_dir, num_doves),
+                factory.table_path(num_to, num_doves),
+                num_doves,
+            )?;
+            factory
+                .save_manifest(num_to, num_doves, num_boards)
+                .with_context(context)?;
+            if num_to % 2 == 1 {
+                core_methods::update_win_index(factory, num_to, num_doves)?;
+            }
+        }
+        Phase::Solve => unreachable!("the solver is never scheduled"),
     }
     Ok(())
 }
 
-fn win_to_lose<P>(
+/// Computes step `num_from + 1` from the previous steps.
+fn compute_step<P>(
     factory: &PathFactory<P>,
     num_from: usize,
-    num_processes: usize,
-    del_tmp_files: bool,
-    nums_doves_to_split_win_if_possible: &[usize],
+    config: &Config,
+    rule: GameRule,
 ) -> anyhow::Result<()>
 where
-    P: AsRef<Path>,
+    P: AsRef<Path> + Sync,
 {
     let num_to = num_from + 1;
-
-    // x_to_x_common
-    x_to_x_common(factory, num_from, num_processes, del_tmp_files)?;
-
-    println!("### PHASE: TRIM ON ACTION ###");
-    for num_doves_of_wins in 2..=12 {
-        println!("[num_doves_of_wins = {num_doves_of_wins}]");
-        trim_on_action(
-            num_doves_of_wins,
-            num_to,
-            factory,
-            num_processes,
-            nums_doves_to_split_win_if_possible,
-        )?;
-    }
-    if del_tmp_files {
-        std::fs::remove_dir_all(factory.trimmed_simply(num_to))?;
-        std::fs::remove_dir_all(factory.trimmed_remove(num_to))?;
-        std::fs::remove_dir_all(factory.trimmed_move(num_to))?;
+    for num_doves in config.doves() {
+        let dir = dove_dir(factory.backstepped(num_to), num_doves);
+        for shard in 0..config.num_processes {
+            std::fs::create_dir_all(shard_dir(&dir, shard))?;
+        }
     }
-
-    // Gather
-    println!("### PHASE: GATHER ###");
     std::fs::create_dir_all(factory.num_dir(num_to))?;
-    for num_doves in 2..=12 {
-        println!("=== num_doves={num_doves} ===");
-        core_methods::gather(
-            dove_dir(factory.trimmed_put(num_to), num_doves),
-            factory.num_dir(num_to).join(format!("{num_doves:0>2}.tdl")),
-        )?;
-    }
-    if del_tmp_files {
+
+    let graph = build_graph(num_to, config);
+    println!("### {} TASKS ###", graph.len());
+    scheduler::run(
+        &graph,
+        config.max_tasks,
+        config.memory_budget,
+        |task| estimate_memory(task, factory, num_to, config),
+        |task| run_task(task, factory, num_from, config, rule),
+    )?;
+
+    if config.del_tmp_files {
         std::fs::remove_dir_all(factory.num_tmp_dir(num_to))?;
     }
     Ok(())
@@ -155,27 +306,37 @@ where
 //  Main Part
 // **********************************************************
 fn advance_one_step(
-    root: impl AsRef<Path>,
     num_from: usize,
-    num_processes: usize,
-    del_tmp_files: bool,
-    nums_doves_to_split_win_if_possible: &[usize],
+    config: &Config,
+    rule_variant: RuleVariant,
 ) -> anyhow::Result<()> {
-    let factory = PathFactory::new(root);
+    let factory = PathFactory::new(&config.data_root, Namespace::new(rule_variant));
+    if num_from < 2 {
+        return Err(anyhow::anyhow!("invalid num_from"));
+    }
+    if !factory.num_dir(num_from).exists() && factory.legacy_num_dir(num_from).exists() {
+        return Err(anyhow::anyhow!(
+            "{:?} is in the layout without namespaces; move the steps into {:?}",
+            factory.legacy_num_dir(num_from),
+            factory.namespace_dir()
+        ));
+    }
+
+    // Step 2 is imported from the full search, which records only the rule.
+    // Wins are checked again whenever their paths are built.
     match num_from {
-        0 | 1 => return Err(anyhow::anyhow!("invalid num_from")),
-        n => match n % 2 {
-            0 => lose_to_win(&factory, num_from, num_processes, del_tmp_files)?,
-            1 => win_to_lose(
-                &factory,
-                num_from,
-                num_processes,
-                del_tmp_files,
-                nums_doves_to_split_win_if_possible,
-            )?,
-            _ => unreachable!(),
-        },
+        2 => rule_variant.check(factory.num_dir(num_from))?,
+        _ => factory.check_namespace(num_from)?,
+    }
+    for num_step in (3..=num_from).step_by(2) {
+        factory.check_namespace(num_step)?;
     }
+
+    // The rule is recorded before any table, which is otherwise taken for the standard rule
+    // by tasks reading tables of the same step
+    rule_variant.record(factory.num_dir(num_from + 1))?;
+    compute_step(&factory, num_from, config, rule_variant.to_game_rule())?;
+    factory.record_namespace(num_from + 1)?;
     println!("Finished all process!");
     Ok(())
 }
@@ -185,35 +346,378 @@ fn advance_one_step(
     name = "Tokyodoves Backward Analyzer",
     author = "Smooth Pudding",
     version = "v0.1.0",
-    about = "Analyze the Tokyodoves Boards"
+    about = "Analyze the Tokyodoves Boards",
+    subcommand_negates_reqs = true
 )]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// Config file in TOML [default: $TOKYODOVES_CONFIG or ./backward_analysis.toml if exists]
+    #[clap(short = 'c', long)]
+    config: Option<PathBuf>,
+
+    /// Root directory of data, overriding `data_root` in config
     #[clap(short = 's', long)]
-    src_dir: Option<String>,
+    src_dir: Option<PathBuf>,
 
-    #[clap(short = 'n', long)]
-    num_doves: usize,
+    #[clap(short = 'n', long, required = true)]
+    num_doves: Option<usize>,
 
+    /// Number of files into which boards with each number of doves are distributed
     #[clap(short = 'p', long)]
-    num_processes: usize,
+    num_processes: Option<usize>,
+
+    /// Number of worker threads [default: number of available cores]
+    #[clap(short = 't', long)]
+    num_threads: Option<usize>,
+
+    /// Number of tasks running at once [default: 1]
+    #[clap(long)]
+    max_tasks: Option<usize>,
+
+    /// Bytes of memory which running tasks are estimated to use at most [default: unlimited]
+    #[clap(long)]
+    memory_budget: Option<u64>,
+
+    /// Maximum number of boards backstepped at once
+    #[clap(long)]
+    max_chunk_size: Option<usize>,
+
+    /// Number of backstepped boards with a number of doves kept in memory before saved
+    #[clap(long)]
+    spill_threshold: Option<usize>,
 
     #[clap(long = "split", num_args = 0..=11)]
-    split_nums_doves: Vec<usize>,
+    split_nums_doves: Option<Vec<usize>>,
+
+    /// Minimum number of doves to analyze
+    #[clap(long)]
+    min_doves: Option<usize>,
+
+    /// Maximum number of doves to analyze
+    #[clap(long)]
+    max_doves: Option<usize>,
 
     #[clap(long = "del_tmp_files")]
     del_tmp_files: Option<bool>,
+
+    /// Whether wins loaded in memory are looked up through a Bloom filter [default: false]
+    #[clap(long)]
+    prefilter: Option<bool>,
+
+    /// Whether backstepped boards are saved sorted and trimmed simply by merging with sorted wins [default: false]
+    #[clap(long)]
+    merge_trim: Option<bool>,
+
+    /// Whether backstepped boards are deduplicated by sorting runs on disk instead of sets in memory [default: false]
+    #[clap(long)]
+    external_sort: Option<bool>,
+
+    /// Algorithm of win-to-lose steps [default: trim]
+    #[clap(long, value_enum)]
+    algorithm: Option<Algorithm>,
+
+    /// Rule of the game, which must agree with the one recorded in the source steps
+    #[clap(long, value_enum, default_value_t = RuleVariant::Remove)]
+    rule: RuleVariant,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Convert tables from .tdl or .tds into the compact .tdc, or from .tdc into .tdl, replacing them
+    Convert {
+        /// Tables to convert, whose formats are told by their extensions
+        #[clap(required = true)]
+        paths: Vec<PathBuf>,
+    },
+
+    /// Solve boards with few doves in memory and save all the steps as the backward analysis does
+    Solve {
+        /// Root directory of data, where no step of the rule is saved yet
+        #[clap(short = 's', long)]
+        src_dir: PathBuf,
+
+        /// Minimum number of doves to analyze
+        #[clap(long, default_value_t = 2)]
+        min_doves: usize,
+
+        /// Maximum number of doves to analyze
+        #[clap(long)]
+        max_doves: usize,
+
+        /// Number of worker threads [default: number of available cores]
+        #[clap(short = 't', long)]
+        num_threads: Option<usize>,
+
+        #[clap(long, value_enum, default_value_t = RuleVariant::Remove)]
+        rule: RuleVariant,
+    },
+}
+
+/// Solves boards with doves in `min_doves..=max_doves` in memory.
+fn solve(
+    src_dir: PathBuf,
+    min_doves: usize,
+    max_doves: usize,
+    num_threads: Option<usize>,
+    rule_variant: RuleVariant,
+) -> anyhow::Result<()> {
+    if !(2..=12).contains(&min_doves) || !(min_doves..=12).contains(&max_doves) {
+        return Err(anyhow::anyhow!(
+            "numbers of doves must satisfy 2 <= min <= max <= 12"
+        ));
+    }
+    let num_threads = match num_threads {
+        Some(0) => return Err(anyhow::anyhow!("number of threads must be positive")),
+        Some(n) => n,
+        None => std::thread::available_parallelism()?.get(),
+    };
+    let factory = PathFactory::new(&src_dir, Namespace::new(rule_variant));
+    let num_last =
+        core_methods::solver::solve(&factory, min_doves..=max_doves, num_threads, rule_variant)?;
+    println!(
+        "Solved up to step {num_last} in {:?}",
+        factory.namespace_dir()
+    );
+    Ok(())
 }
 
 fn main() -> anyhow::Result<()> {
     let arg: Args = Args::parse();
-    let root = PathBuf::from(
-        arg.src_dir
-            .unwrap_or(r"C:\Users\t_ish\Documents\dev\github\TokyoDovesData".to_owned()),
-    );
-    let num_from = arg.num_doves;
-    let num_processes = arg.num_processes;
-    let split = arg.split_nums_doves;
-    let del_tmp_files = arg.del_tmp_files.unwrap_or(true);
-    advance_one_step(root, num_from, num_processes, del_tmp_files, &split)?;
+    match arg.command {
+        Some(Command::Convert { paths }) => {
+            for path in paths {
+                let converted = table_reader::convert(&path)?;
+                println!("Converted {path:?} into {converted:?}");
+            }
+            return Ok(());
+        }
+        Some(Command::Solve {
+            src_dir,
+            min_doves,
+            max_doves,
+            num_threads,
+            rule,
+        }) => return solve(src_dir, min_doves, max_doves, num_threads, rule),
+        None => {}
+    }
+    let num_from = arg
+        .num_doves
+        .expect("required unless a subcommand is given");
+
+    // Command line > environment variables > config file > defaults
+    let from_args = PartialConfig {
+        data_root: arg.src_dir,
+        num_processes: arg.num_processes,
+        num_threads: arg.num_threads,
+        max_tasks: arg.max_tasks,
+        memory_budget: arg.memory_budget,
+        max_chunk_size: arg.max_chunk_size,
+        spill_threshold: arg.spill_threshold,
+        split: arg.split_nums_doves,
+        min_doves: arg.min_doves,
+        max_doves: arg.max_doves,
+        del_tmp_files: arg.del_tmp_files,
+        prefilter: arg.prefilter,
+        merge_trim: arg.merge_trim,
+        external_sort: arg.external_sort,
+        algorithm: arg.algorithm,
+    };
+    let config = from_args
+        .or(PartialConfig::from_env()?)
+        .or(PartialConfig::from_file_or_default(arg.config.as_deref())?)
+        .resolve()?;
+    if config.doves() != (2..=12) {
+        println!(
+            "Warning: boards with doves out of {:?} are regarded as nonexistent, \
+             so some results near the bounds may be missed",
+            config.doves()
+        );
+    }
+
+    advance_one_step(num_from, &config, arg.rule)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use full_search_lose2::{
+        manifest::Manifest,
+        storage,
+        testing::{TempDir, LOSE2_6},
+    };
+    use std::ops::RangeInclusive;
+    use tokyodoves::{collections::BoardSet, BoardBuilder};
+
+    /// Saves step 2 as the full search does, where boards of lose in 2 are `lose2`.
+    fn import_lose2(root: &Path, doves: RangeInclusive<usize>, rule: RuleVariant, lose2: &[u64]) {
+        let factory = PathFactory::new(root, Namespace::new(rule));
+        // The rule is recorded first as the full search does,
+        // since a directory with tables but no rule is taken for the standard rule
+        rule.record(factory.num_dir(2)).unwrap();
+        for num_doves in doves {
+            let mut set = BoardSet::new();
+            for &hash in lose2 {
+                if count_doves(hash) == num_doves {
+                    set.raw_mut().insert(hash);
+                }
+            }
+            let path = factory.table_path(2, num_doves);
+            storage::save_set(&set, &path).unwrap();
+            Manifest::describe_imported(&path, 2, num_doves, rule, set.len())
+                .unwrap()
+                .save(&path)
+                .unwrap();
+        }
+    }
+
+    fn count_doves(hash: u64) -> usize {
+        BoardBuilder::from_u64(hash)
+            .build_unchecked()
+            .count_doves_on_field()
+    }
+
+    /// Hashes in a table in ascending order
+    fn read_sorted(path: &Path) -> Vec<u64> {
+        let mut reader = table_reader::TableReader::open(path).unwrap();
+        let mut hashes = Vec::new();
+        while let Some(hash) = reader.try_next().unwrap() {
+            hashes.push(hash);
+        }
+        hashes.sort_unstable();
+        hashes
+    }
+
+    fn config(root: &Path, doves: RangeInclusive<usize>, algorithm: Algorithm) -> Config {
+        PartialConfig {
+            data_root: Some(root.to_owned()),
+            num_processes: Some(2),
+            num_threads: Some(1),
+            min_doves: Some(*doves.start()),
+            max_doves: Some(*doves.end()),
+            algorithm: Some(algorithm),
+            ..Default::default()
+        }
+        .resolve()
+        .unwrap()
+    }
+
+    #[test]
+    fn narrowed_range_of_doves_is_analyzed() {
+        let rule = RuleVariant::Remove;
+        for algorithm in [Algorithm::Trim, Algorithm::Retrograde] {
+            let dir = TempDir::new();
+            import_lose2(dir.path(), 6..=6, rule, &LOSE2_6);
+            let config = config(dir.path(), 6..=6, algorithm);
+            for num_from in 2..=4 {
+                advance_one_step(num_from, &config, rule).unwrap();
+            }
+
+            let factory = PathFactory::new(dir.path(), Namespace::new(rule));
+            for num_step in 3..=5 {
+                let path = factory.table_path(num_step, 6);
+                Manifest::verify(&path, num_step, 6, rule, Some(Namespace::new(rule))).unwrap();
+                assert!(!factory.table_path(num_step, 5).exists());
+                assert!(!factory.table_path(num_step, 7).exists());
+            }
+            let wins = Manifest::load(factory.table_path(3, 6)).unwrap();
+            assert!(wins.num_boards > 0);
+        }
+    }
+
+    #[test]
+    fn graphs_depend_only_on_tasks_in_range() {
+        for doves in [2..=12, 2..=2, 5..=7, 12..=12] {
+            for algorithm in [Algorithm::Trim, Algorithm::Retrograde] {
+                let config = config(&std::env::temp_dir(), doves.clone(), algorithm);
+                for num_to in 3..=4 {
+                    // Adding a task depending on one not in the graph panics
+                    let graph = build_graph(num_to, &config);
+                    assert!(graph.len() >= config.doves().count());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn memory_is_estimated_without_verifying_wins() {
+        let rule = RuleVariant::Remove;
+        let dir = TempDir::new();
+        let factory = PathFactory::new(dir.path(), Namespace::new(rule));
+        std::fs::create_dir_all(factory.num_dir(3)).unwrap();
+        // A table without its checksum or manifest, which is never verified
+        std::fs::write(factory.table_path(3, 6), [0; 40]).unwrap();
+
+        assert!(factory.win_paths(3, 6).is_err());
+        assert_eq!(win_bytes(&factory, 3, 6), 40);
+    }
+
+    /// Solves boards with `doves` and runs the backward analysis of all the steps
+    /// from loses in 2 found by the solver by each algorithm to compare them,
+    /// returning the loses in 2 in ascending order.
+    fn compare_with_solver(doves: RangeInclusive<usize>, rule: RuleVariant) -> Vec<u64> {
+        let solved = TempDir::new();
+        let solved_factory = PathFactory::new(solved.path(), Namespace::new(rule));
+        let num_last =
+            core_methods::solver::solve(&solved_factory, doves.clone(), 1, rule).unwrap();
+        // The last step is empty, so no table may be saved
+        let read_solved = |num_step, num_doves| {
+            let path = solved_factory.table_path(num_step, num_doves);
+            if path.exists() {
+                read_sorted(&path)
+            } else {
+                Vec::new()
+            }
+        };
+        let mut lose2: Vec<u64> = doves.clone().flat_map(|n| read_solved(2, n)).collect();
+        lose2.sort_unstable();
+
+        for algorithm in [Algorithm::Trim, Algorithm::Retrograde] {
+            let dir = TempDir::new();
+            import_lose2(dir.path(), doves.clone(), rule, &lose2);
+            let config = config(dir.path(), doves.clone(), algorithm);
+            for num_from in 2..num_last.max(3) {
+                advance_one_step(num_from, &config, rule).unwrap();
+            }
+
+            let factory = PathFactory::new(dir.path(), Namespace::new(rule));
+            for num_step in 3..=num_last.max(3) {
+                for num_doves in doves.clone() {
+                    let expected = read_solved(num_step, num_doves);
+                    let analyzed = read_sorted(&factory.table_path(num_step, num_doves));
+                    let at = format!("{algorithm:?} at step {num_step} with {num_doves} doves");
+                    assert_eq!(analyzed.len(), expected.len(), "{at}");
+                    // Not to print thousands of hashes when they differ
+                    assert!(analyzed == expected, "{at}");
+                }
+            }
+        }
+        lose2
+    }
+
+    /// No board with 5 doves or fewer is lost in 2, so all the steps are empty,
+    /// and boards with 6 doves are compared shape by shape in the solver instead.
+    #[test]
+    fn backward_analysis_agrees_with_solver_with_few_doves() {
+        for rule in [RuleVariant::Remove, RuleVariant::NoRemove] {
+            let lose2 = compare_with_solver(4..=5, rule);
+            let mut found: Vec<u64> = (4..=5)
+                .flat_map(|n| {
+                    let found = full_search_lose2::search::find_lose2(n, rule.to_game_rule());
+                    found.raw().iter().collect::<Vec<u64>>()
+                })
+                .collect();
+            found.sort_unstable();
+            assert_eq!(lose2, found, "{rule:?}");
+        }
+    }
+
+    /// Compares all the steps with the solver, which is run by `cargo test --release -- --ignored`.
+    #[test]
+    #[ignore = "solves all boards with 6 doves, which takes minutes even in release"]
+    fn backward_analysis_agrees_with_solver() {
+        compare_with_solver(6..=6, RuleVariant::Remove);
+    }
+}
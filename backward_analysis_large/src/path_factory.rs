@@ -1,37 +1,50 @@
 use std::path::{Path, PathBuf};
 
+use crate::table_reader::{Encoding, COMPACT_EXTENSION};
+use full_search_lose2::manifest::Manifest;
+use full_search_lose2::storage;
+
+pub use full_search_lose2::rule::Namespace;
+
 // **********************************************************
 //  Path Rules
 // **********************************************************
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct PathFactory<P: AsRef<Path>> {
     root: P,
+    namespace: Namespace,
 }
 
 impl<P> PathFactory<P>
 where
     P: AsRef<Path>,
 {
-    pub fn new(root: P) -> Self {
-        Self { root }
+    pub fn new(root: P, namespace: Namespace) -> Self {
+        Self { root, namespace }
     }
 
-    pub fn num_dir(&self, num_step: usize) -> PathBuf {
+    /// Directory where all the steps in the namespace are stored
+    pub fn namespace_dir(&self) -> PathBuf {
+        self.root.as_ref().join(self.namespace.to_string())
+    }
+
+    /// Directory of a step in the layout before namespaces are introduced
+    pub fn legacy_num_dir(&self, num_step: usize) -> PathBuf {
         self.root.as_ref().join(format!("{num_step:0>4}"))
     }
 
+    pub fn num_dir(&self, num_step: usize) -> PathBuf {
+        self.namespace_dir().join(format!("{num_step:0>4}"))
+    }
+
     pub fn num_tmp_dir(&self, num_step: usize) -> PathBuf {
-        self.root.as_ref().join(format!("{num_step:0>4}_tmp"))
+        self.namespace_dir().join(format!("{num_step:0>4}_tmp"))
     }
 
     pub fn backstepped(&self, num_step: usize) -> PathBuf {
         self.num_tmp_dir(num_step).join("backstepped")
     }
 
-    pub fn redistributed(&self, num_step: usize) -> PathBuf {
-        self.num_tmp_dir(num_step).join("redistributed")
-    }
-
     pub fn trimmed_simply(&self, num_step: usize) -> PathBuf {
         self.num_tmp_dir(num_step).join("trimmed_simply")
     }
@@ -48,18 +61,198 @@ where
         self.num_tmp_dir(num_step).join("trimmed_remove")
     }
 
-    pub fn win_paths(&self, num_step_ceil: usize, num_doves: usize) -> Vec<PathBuf> {
-        (3..=num_step_ceil)
+    pub fn table_path(&self, num_step: usize, num_doves: usize) -> PathBuf {
+        self.num_dir(num_step).join(format!("{num_doves:0>2}.tdl"))
+    }
+
+    /// Directory of the indices of wins, which are sorted tables
+    pub fn win_table_dir(&self) -> PathBuf {
+        self.namespace_dir().join("win_sorted")
+    }
+
+    /// Sorted table of all wins with `num_doves` doves up to `num_step`
+    pub fn win_table_path(&self, num_step: usize, num_doves: usize) -> PathBuf {
+        self.win_table_dir()
+            .join(format!("{num_step:0>4}"))
+            .join(format!("{num_doves:0>2}.tds"))
+    }
+
+    /// Directory of the counters of retrograde analysis, one file for each number of doves
+    pub fn counters_dir(&self) -> PathBuf {
+        self.namespace_dir().join("counters")
+    }
+
+    pub fn counters_path(&self, num_doves: usize) -> PathBuf {
+        self.counters_dir().join(format!("{num_doves:0>2}.tdk"))
+    }
+
+    /// Saves the manifest of the sorted table of wins, which covers wins up to `num_step`.
+    pub fn save_win_table_manifest(
+        &self,
+        num_step: usize,
+        num_doves: usize,
+        num_boards: usize,
+    ) -> anyhow::Result<()> {
+        let path = self.win_table_path(num_step, num_doves);
+        Manifest::describe(&path, num_step, num_doves, self.namespace, num_boards)?.save(&path)
+    }
+
+    /// Removes the sorted table of wins up to `num_step`, which a later one covers.
+    pub fn remove_win_table(&self, num_step: usize, num_doves: usize) -> std::io::Result<()> {
+        let path = stored_path(self.win_table_path(num_step, num_doves));
+        storage::remove(&path)?;
+        match std::fs::remove_file(Manifest::path_of(&path)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        // The directory is left if tables with other numbers of doves remain
+        let _ = std::fs::remove_dir(self.win_table_dir().join(format!("{num_step:0>4}")));
+        Ok(())
+    }
+
+    /// Returns the path of the sorted table of wins if its manifest says
+    /// that it covers exactly the wins up to `num_step_ceil`, which is not verified yet.
+    fn win_table(&self, num_step_ceil: usize, num_doves: usize) -> Option<PathBuf> {
+        let last_win_step = num_step_ceil - (num_step_ceil + 1) % 2;
+        if last_win_step < 3 {
+            return None;
+        }
+        let path = stored_path(self.win_table_path(last_win_step, num_doves));
+        Manifest::load(&path)
+            .is_ok_and(|manifest| manifest.step == last_win_step)
+            .then_some(path)
+    }
+
+    /// Returns the path of the sorted table of wins if it covers exactly the wins up to `num_step_ceil`.
+    pub fn verified_win_table(
+        &self,
+        num_step_ceil: usize,
+        num_doves: usize,
+    ) -> anyhow::Result<Option<PathBuf>> {
+        let Some(path) = self.win_table(num_step_ceil, num_doves) else {
+            return Ok(None);
+        };
+        let last_win_step = num_step_ceil - (num_step_ceil + 1) % 2;
+        let namespace = Some(self.namespace);
+        Manifest::verify(
+            &path,
+            last_win_step,
+            num_doves,
+            self.namespace.rule,
+            namespace,
+        )?;
+        Ok(Some(path))
+    }
+
+    /// Returns paths of wins up to `num_step_ceil` in sorted formats,
+    /// or `None` if some of them are not sorted.
+    ///
+    /// The sorted table of wins is preferred, which is returned alone.
+    pub fn sorted_win_paths(
+        &self,
+        num_step_ceil: usize,
+        num_doves: usize,
+    ) -> anyhow::Result<Option<Vec<PathBuf>>> {
+        if let Some(path) = self.verified_win_table(num_step_ceil, num_doves)? {
+            return Ok(Some(vec![path]));
+        }
+        let paths = self.win_paths(num_step_ceil, num_doves)?;
+        let sorted = paths
+            .iter()
+            .all(|path| Encoding::of(path).is_some_and(Encoding::is_sorted));
+        Ok(sorted.then_some(paths))
+    }
+
+    /// Saves the manifest of a table, which must be already saved.
+    pub fn save_manifest(
+        &self,
+        num_step: usize,
+        num_doves: usize,
+        num_boards: usize,
+    ) -> anyhow::Result<()> {
+        let path = self.table_path(num_step, num_doves);
+        Manifest::describe(&path, num_step, num_doves, self.namespace, num_boards)?.save(&path)
+    }
+
+    /// Returns the path of a table after checking its manifest.
+    ///
+    /// Step 2 is imported from the full search, which has no namespace.
+    pub fn verified_table_path(
+        &self,
+        num_step: usize,
+        num_doves: usize,
+    ) -> anyhow::Result<PathBuf> {
+        let path = stored_path(self.table_path(num_step, num_doves));
+        let namespace = (num_step != 2).then_some(self.namespace);
+        Manifest::verify(&path, num_step, num_doves, self.namespace.rule, namespace)?;
+        Ok(path)
+    }
+
+    /// Records the namespace in the directory of a finished step.
+    pub fn record_namespace(&self, num_step: usize) -> std::io::Result<()> {
+        self.namespace.record(self.num_dir(num_step))
+    }
+
+    /// Checks that a step is finished in the namespace of `self`.
+    pub fn check_namespace(&self, num_step: usize) -> anyhow::Result<()> {
+        self.namespace.check(self.num_dir(num_step))
+    }
+
+    /// Returns paths of wins up to `num_step_ceil`,
+    /// which fails unless all of them are verified by their manifests.
+    ///
+    /// The sorted table of wins is returned alone if it covers exactly the wins up to `num_step_ceil`.
+    /// Otherwise, e.g. when an earlier step is computed again, the tables of all win steps are.
+    pub fn win_paths(
+        &self,
+        num_step_ceil: usize,
+        num_doves: usize,
+    ) -> anyhow::Result<Vec<PathBuf>> {
+        let last_win_step = num_step_ceil - (num_step_ceil + 1) % 2;
+        if last_win_step < 3 {
+            return Ok(Vec::new());
+        }
+        if let Some(path) = self.verified_win_table(num_step_ceil, num_doves)? {
+            return Ok(vec![path]);
+        }
+        (3..=last_win_step)
+            .step_by(2)
+            .map(|n| self.verified_table_path(n, num_doves))
+            .collect()
+    }
+
+    /// Returns paths of wins up to `num_step_ceil` like `win_paths` without verifying them,
+    /// which is enough to estimate how much is loaded.
+    pub fn unverified_win_paths(&self, num_step_ceil: usize, num_doves: usize) -> Vec<PathBuf> {
+        if let Some(path) = self.win_table(num_step_ceil, num_doves) {
+            return vec![path];
+        }
+        let last_win_step = num_step_ceil - (num_step_ceil + 1) % 2;
+        (3..=last_win_step)
             .step_by(2)
-            .map(|n| self.num_dir(n).join(format!("{num_doves:0>2}.tdl")))
+            .map(|n| stored_path(self.table_path(n, num_doves)))
             .collect()
     }
 }
 
+/// Returns `path` of a table, or the same table in the compact format if only it is stored.
+pub fn stored_path(path: PathBuf) -> PathBuf {
+    let compact = path.with_extension(COMPACT_EXTENSION);
+    match !path.exists() && compact.exists() {
+        true => compact,
+        false => path,
+    }
+}
+
 pub fn dove_dir(parent: impl AsRef<Path>, num_doves: usize) -> PathBuf {
     parent.as_ref().join(format!("{num_doves:0>2}"))
 }
 
+/// Directory of files which make up the shard `shard` together
+pub fn shard_dir(parent: impl AsRef<Path>, shard: usize) -> PathBuf {
+    parent.as_ref().join(format!("{shard:0>4}"))
+}
+
 pub fn distributed_path(parent: impl AsRef<Path>, file_idx: usize) -> PathBuf {
     parent.as_ref().join(format!("{file_idx:0>4}.tdl"))
 }
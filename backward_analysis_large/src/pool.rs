@@ -0,0 +1,152 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use full_search_lose2::error::{Context, Error, Result};
+
+/// Runs `job(i)` for each shard `i` in `0..sizes.len()` on at most `num_threads` threads
+/// and returns the results in order of shards.
+///
+/// Shards are picked up in descending order of `sizes`, so that a large one does not run alone at the end.
+/// Once a job fails, shards not yet picked up are skipped and the first error in order of shards is returned.
+/// A panic of `job(i)` is turned into an error with `context(i)`.
+pub fn run<T, F, C>(sizes: &[u64], num_threads: usize, context: C, job: F) -> Result<Vec<T>>
+where
+    T: Send,
+    F: Fn(usize) -> Result<T> + Sync,
+    C: Fn(usize) -> Context + Sync,
+{
+    let mut order: Vec<usize> = (0..sizes.len()).collect();
+    order.sort_by_key(|&i| std::cmp::Reverse(sizes[i]));
+
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let results: Vec<Mutex<Option<Result<T>>>> = sizes.iter().map(|_| Mutex::new(None)).collect();
+
+    std::thread::scope(|scope| {
+        for t in 0..num_threads.min(sizes.len()) {
+            let (order, next, failed, results) = (&order, &next, &failed, &results);
+            let (context, job) = (&context, &job);
+            scope.spawn(move || {
+                println!("[Thread {t}] started");
+                while !failed.load(Ordering::Relaxed) {
+                    let Some(&i) = order.get(next.fetch_add(1, Ordering::Relaxed)) else {
+                        break;
+                    };
+                    println!("[Thread {t}] shard {i} ...");
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| job(i)))
+                        .unwrap_or_else(|payload| Err(Error::from_panic(context(i), payload)));
+                    if result.is_err() {
+                        failed.store(true, Ordering::Relaxed);
+                    }
+                    *results[i].lock().unwrap() = Some(result);
+                    println!("[Thread {t}] shard {i} done");
+                }
+                println!("[Thread {t}] finished");
+            });
+        }
+    });
+
+    let mut values = Vec::with_capacity(sizes.len());
+    for slot in results {
+        match slot.into_inner().unwrap() {
+            Some(Ok(value)) => values.push(value),
+            Some(Err(e)) => return Err(e),
+            // Skipped after a failure, which is found later
+            None => {}
+        }
+    }
+    Ok(values)
+}
+
+/// Sizes of files used to order shards, where a missing file counts as empty.
+pub fn file_sizes(paths: &[impl AsRef<std::path::Path>]) -> Vec<u64> {
+    paths
+        .iter()
+        .map(|path| std::fs::metadata(path).map(|m| m.len()).unwrap_or(0))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Phase;
+
+    fn context(i: usize) -> Context {
+        Context::new(Phase::Backstep).shard(i)
+    }
+
+    fn invalid(i: usize) -> Error {
+        Error::InvalidArgument {
+            context: context(i),
+            message: format!("shard {i} fails"),
+        }
+    }
+
+    #[test]
+    fn results_are_in_order_of_shards() {
+        let sizes = [5, 1, 9, 3, 7];
+        for num_threads in [1, 2, 8] {
+            let values = run(&sizes, num_threads, context, |i| Ok(i * 10)).unwrap();
+            assert_eq!(values, vec![0, 10, 20, 30, 40]);
+        }
+        assert!(run(&[], 4, context, Ok).unwrap().is_empty());
+    }
+
+    #[test]
+    fn largest_shards_are_picked_up_first() {
+        let started = Mutex::new(Vec::new());
+        run(&[3, 10, 1, 7, 5], 1, context, |i| {
+            started.lock().unwrap().push(i);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(started.into_inner().unwrap(), vec![1, 3, 4, 0, 2]);
+    }
+
+    #[test]
+    fn shards_after_failure_are_skipped() {
+        let started = Mutex::new(Vec::new());
+        let result = run(&[3, 10, 1, 7, 5], 1, context, |i| {
+            started.lock().unwrap().push(i);
+            match i {
+                3 => Err(invalid(i)),
+                _ => Ok(()),
+            }
+        });
+        assert!(
+            matches!(result, Err(Error::InvalidArgument { message, .. }) if message == "shard 3 fails")
+        );
+        assert_eq!(started.into_inner().unwrap(), vec![1, 3]);
+    }
+
+    #[test]
+    fn first_error_in_order_of_shards_is_returned() {
+        // Shards 0 and 2 run at once and both fail, while shard 1 is skipped
+        let barrier = std::sync::Barrier::new(2);
+        let result = run(&[2, 1, 2], 2, context, |i| {
+            barrier.wait();
+            Err::<(), _>(invalid(i))
+        });
+        assert!(
+            matches!(result, Err(Error::InvalidArgument { message, .. }) if message == "shard 0 fails")
+        );
+    }
+
+    #[test]
+    fn panic_is_turned_into_error() {
+        let result = run(&[1, 2], 2, context, |i| match i {
+            1 => panic!("broken shard"),
+            _ => Ok(()),
+        });
+        let Err(Error::Panicked {
+            context: found,
+            message,
+        }) = result
+        else {
+            panic!("panic is not reported: {result:?}");
+        };
+        assert_eq!(message, "broken shard");
+        assert_eq!(found.to_string(), context(1).to_string());
+    }
+}
@@ -0,0 +1,339 @@
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+
+use full_search_lose2::error::{Context, Error, Phase, Result};
+
+/// A unit of work of a step, which is a phase applied to boards with a number of doves
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Task {
+    pub phase: Phase,
+    pub num_doves: usize,
+}
+
+impl Task {
+    pub fn new(phase: Phase, num_doves: usize) -> Self {
+        Self { phase, num_doves }
+    }
+
+    fn context(&self) -> Context {
+        Context::new(self.phase).doves(self.num_doves)
+    }
+}
+
+impl std::fmt::Display for Task {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (num_doves={})", self.phase, self.num_doves)
+    }
+}
+
+/// Tasks of a step with their dependencies
+#[derive(Debug, Clone, Default)]
+pub struct Graph {
+    tasks: Vec<Task>,
+    deps: HashMap<Task, Vec<Task>>,
+}
+
+impl Graph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `task` which must run after `deps`, all of which must be added already.
+    ///
+    /// Tasks are preferred in the order they are added when several are ready.
+    pub fn add(&mut self, task: Task, deps: impl IntoIterator<Item = Task>) {
+        let deps: Vec<Task> = deps.into_iter().collect();
+        debug_assert!(
+            deps.iter().all(|dep| self.contains(dep)),
+            "{task} depends on a task not in the graph"
+        );
+        if self.deps.insert(task, deps).is_none() {
+            self.tasks.push(task);
+        }
+    }
+
+    pub fn contains(&self, task: &Task) -> bool {
+        self.deps.contains_key(task)
+    }
+
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+}
+
+/// Runs all tasks in `graph` respecting their dependencies.
+///
+/// At most `max_tasks` tasks run at once, and a task starts only if `memory(task)`,
+/// estimated when it is ready, fits in `memory_budget` together with the running ones.
+/// A task is started anyway if nothing else runs, so that a large one is not stuck.
+/// Once a task fails, no more tasks start and the first error is returned
+/// after the running ones finish.
+pub fn run<M, F>(
+    graph: &Graph,
+    max_tasks: usize,
+    memory_budget: Option<u64>,
+    memory: M,
+    job: F,
+) -> Result<()>
+where
+    M: Fn(Task) -> u64,
+    F: Fn(Task) -> Result<()> + Sync,
+{
+    let mut num_waiting: HashMap<Task, usize> = HashMap::new();
+    let mut dependents: HashMap<Task, Vec<Task>> = HashMap::new();
+    for task in graph.tasks.iter() {
+        let deps = &graph.deps[task];
+        num_waiting.insert(*task, deps.len());
+        for dep in deps {
+            dependents.entry(*dep).or_default().push(*task);
+        }
+    }
+    let order: HashMap<Task, usize> = graph
+        .tasks
+        .iter()
+        .enumerate()
+        .map(|(i, t)| (*t, i))
+        .collect();
+    let mut ready: Vec<(Task, u64)> = Vec::new();
+    let make_ready = |ready: &mut Vec<(Task, u64)>, task: Task| {
+        ready.push((task, memory(task)));
+        ready.sort_by_key(|(task, _)| order[task]);
+    };
+    for task in graph.tasks.iter() {
+        if num_waiting[task] == 0 {
+            make_ready(&mut ready, *task);
+        }
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    let mut num_running = 0;
+    let mut reserved = 0;
+    let mut num_finished = 0;
+    let mut first_error = None;
+    std::thread::scope(|scope| loop {
+        while first_error.is_none() && num_running < max_tasks {
+            let fits = |&(_, m): &(Task, u64)| match memory_budget {
+                Some(budget) => num_running == 0 || reserved + m <= budget,
+                None => true,
+            };
+            let Some(idx) = ready.iter().position(fits) else {
+                break;
+            };
+            let (task, m) = ready.remove(idx);
+            num_running += 1;
+            reserved += m;
+
+            let (sender, job) = (sender.clone(), &job);
+            scope.spawn(move || {
+                println!("### {task} started (estimated memory: {m} bytes) ###");
+                let result = panic::catch_unwind(AssertUnwindSafe(|| job(task)))
+                    .unwrap_or_else(|payload| Err(Error::from_panic(task.context(), payload)));
+                println!("### {task} finished ###");
+                sender.send((task, m, result)).unwrap();
+            });
+        }
+
+        if num_running == 0 {
+            break;
+        }
+        let (task, m, result) = receiver.recv().unwrap();
+        num_running -= 1;
+        reserved -= m;
+        match result {
+            Ok(()) => {
+                num_finished += 1;
+                for dependent in dependents.get(&task).into_iter().flatten() {
+                    let n = num_waiting.get_mut(dependent).unwrap();
+                    *n -= 1;
+                    if *n == 0 {
+                        make_ready(&mut ready, *dependent);
+                    }
+                }
+            }
+            Err(e) => {
+                first_error.get_or_insert(e);
+            }
+        }
+    });
+
+    if let Some(e) = first_error {
+        return Err(e);
+    }
+    debug_assert_eq!(num_finished, graph.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    fn task(phase: Phase, num_doves: usize) -> Task {
+        Task::new(phase, num_doves)
+    }
+
+    /// Graph of the phases of a lose-to-win step with 4..=6 doves
+    fn step_graph() -> Graph {
+        let mut graph = Graph::new();
+        for n in 4..=6 {
+            graph.add(task(Phase::Backstep, n), []);
+        }
+        for n in 4..=6 {
+            let deps = (n - 1..=n + 1)
+                .filter(|m| (4..=6).contains(m))
+                .map(|m| task(Phase::Backstep, m));
+            graph.add(task(Phase::TrimSimply, n), deps);
+        }
+        for n in 4..=6 {
+            graph.add(task(Phase::Gather, n), [task(Phase::TrimSimply, n)]);
+        }
+        graph
+    }
+
+    #[test]
+    fn tasks_start_after_their_dependencies() {
+        let graph = step_graph();
+        assert_eq!(graph.len(), 9);
+        assert_eq!(graph.deps[&task(Phase::TrimSimply, 4)].len(), 2);
+
+        for max_tasks in [1, 2, 9] {
+            let finished = Mutex::new(Vec::new());
+            run(
+                &graph,
+                max_tasks,
+                None,
+                |_| 0,
+                |t| {
+                    let done = finished.lock().unwrap().clone();
+                    for dep in graph.deps[&t].iter() {
+                        assert!(done.contains(dep), "{t} started before {dep}");
+                    }
+                    std::thread::sleep(Duration::from_millis(2));
+                    finished.lock().unwrap().push(t);
+                    Ok(())
+                },
+            )
+            .unwrap();
+            assert_eq!(finished.into_inner().unwrap().len(), graph.len());
+        }
+    }
+
+    #[test]
+    fn ready_tasks_start_in_order_of_addition() {
+        let graph = step_graph();
+        let started = Mutex::new(Vec::new());
+        run(
+            &graph,
+            1,
+            None,
+            |_| 0,
+            |t| {
+                started.lock().unwrap().push(t);
+                Ok(())
+            },
+        )
+        .unwrap();
+        let started = started.into_inner().unwrap();
+        assert_eq!(started, graph.tasks);
+    }
+
+    #[test]
+    fn running_tasks_fit_in_memory_budget() {
+        let mut graph = Graph::new();
+        for n in 2..=12 {
+            graph.add(task(Phase::Backstep, n), []);
+        }
+        let memory = |t: Task| t.num_doves as u64;
+        let (reserved, peak) = (AtomicU64::new(0), AtomicU64::new(0));
+        run(&graph, 4, Some(20), memory, |t| {
+            let now = reserved.fetch_add(memory(t), Ordering::SeqCst) + memory(t);
+            peak.fetch_max(now, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(5));
+            reserved.fetch_sub(memory(t), Ordering::SeqCst);
+            Ok(())
+        })
+        .unwrap();
+        assert!(peak.into_inner() <= 20);
+    }
+
+    #[test]
+    fn task_over_budget_runs_alone() {
+        let mut graph = Graph::new();
+        for n in 2..=4 {
+            graph.add(task(Phase::Backstep, n), []);
+        }
+        let memory = |t: Task| if t.num_doves == 3 { 100 } else { 1 };
+        let running = Mutex::new(Vec::new());
+        let overlapped = AtomicU64::new(0);
+        run(&graph, 3, Some(10), memory, |t| {
+            {
+                let mut running = running.lock().unwrap();
+                running.push(t);
+                if running.len() > 1 && running.iter().any(|t| memory(*t) > 10) {
+                    overlapped.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+            std::thread::sleep(Duration::from_millis(5));
+            running.lock().unwrap().retain(|r| *r != t);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(overlapped.into_inner(), 0);
+    }
+
+    #[test]
+    fn failure_stops_dependents_and_is_returned() {
+        let graph = step_graph();
+        let started = Mutex::new(Vec::new());
+        let result = run(
+            &graph,
+            1,
+            None,
+            |_| 0,
+            |t| {
+                started.lock().unwrap().push(t);
+                match t == task(Phase::Backstep, 5) {
+                    true => Err(Error::InvalidArgument {
+                        context: t.context(),
+                        message: "broken".to_owned(),
+                    }),
+                    false => Ok(()),
+                }
+            },
+        );
+        assert!(
+            matches!(result, Err(Error::InvalidArgument { message, .. }) if message == "broken")
+        );
+        let started = started.into_inner().unwrap();
+        assert_eq!(
+            started,
+            vec![task(Phase::Backstep, 4), task(Phase::Backstep, 5)]
+        );
+    }
+
+    #[test]
+    fn panic_is_turned_into_error() {
+        let graph = step_graph();
+        let result = run(
+            &graph,
+            2,
+            None,
+            |_| 0,
+            |t| match t.phase {
+                Phase::TrimSimply => panic!("broken {t}"),
+                _ => Ok(()),
+            },
+        );
+        assert!(matches!(result, Err(Error::Panicked { .. })));
+    }
+
+    #[test]
+    #[should_panic(expected = "not in the graph")]
+    fn dependencies_out_of_graph_are_rejected() {
+        let mut graph = Graph::new();
+        graph.add(task(Phase::Gather, 2), [task(Phase::TrimSimply, 2)]);
+    }
+}
@@ -0,0 +1,290 @@
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use memmap2::Mmap;
+use tokyodoves::collections::BoardSet;
+
+use full_search_lose2::storage;
+
+/// Magic number at the head of a sorted table
+const MAGIC: &[u8; 8] = b"TDSORTED";
+
+/// Bytes of the magic number and the number of hashes
+const HEADER_LEN: usize = 16;
+
+/// Number of hashes between entries of the in-memory index
+const BLOCK_LEN: usize = 4096;
+
+/// Read-only table of hashes of boards sorted in ascending order.
+///
+/// The file consists of [`MAGIC`], the number of hashes and the hashes themselves,
+/// all of which are in little endian.
+/// It is memory-mapped rather than loaded, so a table larger than RAM can be probed,
+/// and only the first hash of every [`BLOCK_LEN`] hashes is kept in memory.
+#[derive(Debug)]
+pub struct SortedTable {
+    mmap: Mmap,
+    len: usize,
+    index: Vec<u64>,
+}
+
+impl SortedTable {
+    /// Saves hashes of boards in `set` as a sorted table atomically with its checksum.
+    pub fn save(set: &BoardSet, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let mut hashes: Vec<u64> = set.raw().iter().collect();
+        hashes.sort_unstable();
+        let mut iter = hashes.iter().copied();
+        save_stream(hashes.len(), || Ok(iter.next()), path)
+    }
+
+    /// Maps a table into memory after verifying its checksum.
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let file = storage::open_verified(path)?;
+        // SAFETY: tables are never modified in place, but replaced by renaming.
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        let invalid = |message: &str| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{path:?} is not a sorted table: {message}"),
+            )
+        };
+        if mmap.len() < HEADER_LEN || &mmap[..8] != MAGIC {
+            return Err(invalid("wrong header"));
+        }
+        let len = u64::from_le_bytes(mmap[8..16].try_into().unwrap()) as usize;
+        if mmap.len() != HEADER_LEN + 8 * len {
+            return Err(invalid("wrong length"));
+        }
+
+        let mut table = Self {
+            mmap,
+            len,
+            index: Vec::new(),
+        };
+        table.index = (0..len).step_by(BLOCK_LEN).map(|i| table.get(i)).collect();
+        Ok(table)
+    }
+
+    fn get(&self, i: usize) -> u64 {
+        let start = HEADER_LEN + 8 * i;
+        u64::from_le_bytes(self.mmap[start..start + 8].try_into().unwrap())
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Checks if `hash` is in the table by binary search in a block chosen by the index.
+    pub fn contains(&self, hash: u64) -> bool {
+        let num_blocks_before = self.index.partition_point(|&first| first <= hash);
+        let Some(block) = num_blocks_before.checked_sub(1) else {
+            return false;
+        };
+        let (mut lo, mut hi) = (block * BLOCK_LEN, ((block + 1) * BLOCK_LEN).min(self.len));
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            match self.get(mid).cmp(&hash) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => return true,
+            }
+        }
+        false
+    }
+}
+
+/// Saves `len` hashes given by `next` in ascending order as a sorted table
+/// atomically with its checksum, which needs no hashes in memory.
+pub fn save_stream<F>(len: usize, mut next: F, path: impl AsRef<Path>) -> std::io::Result<()>
+where
+    F: FnMut() -> std::io::Result<Option<u64>>,
+{
+    storage::save_with(path, |w| {
+        let mut w = BufWriter::new(w);
+        w.write_all(MAGIC)?;
+        w.write_all(&(len as u64).to_le_bytes())?;
+        let mut count = 0;
+        while let Some(hash) = next()? {
+            w.write_all(&hash.to_le_bytes())?;
+            count += 1;
+        }
+        if count != len {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{count} hashes are given to a sorted table of {len}"),
+            ));
+        }
+        w.flush()
+    })
+}
+
+/// Reader of hashes in a sorted table in ascending order, which is not mapped into memory
+#[derive(Debug)]
+pub struct SortedReader<R> {
+    reader: R,
+    remaining: usize,
+}
+
+impl<R: Read> SortedReader<R> {
+    pub fn new(mut reader: R) -> std::io::Result<Self> {
+        let mut header = [0; HEADER_LEN];
+        reader.read_exact(&mut header)?;
+        if &header[..8] != MAGIC {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "not a sorted table: wrong header",
+            ));
+        }
+        let remaining = u64::from_le_bytes(header[8..16].try_into().unwrap()) as usize;
+        Ok(Self { reader, remaining })
+    }
+
+    pub fn try_next(&mut self) -> std::io::Result<Option<u64>> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        let mut buf = [0; 8];
+        self.reader.read_exact(&mut buf)?;
+        self.remaining -= 1;
+        Ok(Some(u64::from_le_bytes(buf)))
+    }
+}
+
+/// Opens a sorted table for streaming after verifying its checksum.
+pub fn open_reader(
+    path: impl AsRef<Path>,
+) -> std::io::Result<SortedReader<BufReader<std::fs::File>>> {
+    SortedReader::new(BufReader::new(storage::open_verified(path)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use full_search_lose2::testing::TempDir;
+
+    /// Distinct odd hashes spread over the whole range in ascending order
+    fn hashes(len: usize) -> Vec<u64> {
+        let stride = (u64::MAX / (len as u64 + 1)) & !1;
+        (0..len as u64).map(|i| i * stride + 1).collect()
+    }
+
+    fn save(dir: &TempDir, hashes: &[u64]) -> std::path::PathBuf {
+        let path = dir.join("table.tds");
+        let mut iter = hashes.iter().copied();
+        save_stream(hashes.len(), || Ok(iter.next()), &path).unwrap();
+        path
+    }
+
+    fn read_all(path: &Path) -> Vec<u64> {
+        let mut reader = open_reader(path).unwrap();
+        let mut hashes = Vec::new();
+        while let Some(hash) = reader.try_next().unwrap() {
+            hashes.push(hash);
+        }
+        hashes
+    }
+
+    #[test]
+    fn saved_hashes_are_found() {
+        let dir = TempDir::new();
+        // Over two blocks, so that the last block is partial
+        let hashes = hashes(2 * BLOCK_LEN + 100);
+        let path = save(&dir, &hashes);
+
+        let table = SortedTable::open(&path).unwrap();
+        assert_eq!(table.len(), hashes.len());
+        assert!(hashes.iter().all(|&hash| table.contains(hash)));
+        // Hashes are odd, so their neighbours are not in the table
+        for &hash in hashes.iter() {
+            assert!(!table.contains(hash - 1));
+            assert!(!table.contains(hash.wrapping_add(1)));
+        }
+        assert!(!table.contains(0));
+        assert!(!table.contains(u64::MAX - 1));
+        assert_eq!(read_all(&path), hashes);
+    }
+
+    #[test]
+    fn first_and_last_hashes_of_blocks_are_found() {
+        let dir = TempDir::new();
+        let hashes = hashes(3 * BLOCK_LEN);
+        let path = save(&dir, &hashes);
+
+        let table = SortedTable::open(&path).unwrap();
+        for i in [
+            0,
+            BLOCK_LEN - 1,
+            BLOCK_LEN,
+            2 * BLOCK_LEN - 1,
+            3 * BLOCK_LEN - 1,
+        ] {
+            assert!(table.contains(hashes[i]), "hash #{i} is not found");
+        }
+    }
+
+    #[test]
+    fn empty_table_has_nothing() {
+        let dir = TempDir::new();
+        let path = save(&dir, &[]);
+
+        let table = SortedTable::open(&path).unwrap();
+        assert_eq!(table.len(), 0);
+        assert!(!table.contains(0));
+        assert!(!table.contains(u64::MAX));
+        assert!(read_all(&path).is_empty());
+    }
+
+    #[test]
+    fn single_entry_table_has_only_it() {
+        let dir = TempDir::new();
+        let path = save(&dir, &[42]);
+
+        let table = SortedTable::open(&path).unwrap();
+        assert_eq!(table.len(), 1);
+        assert!(table.contains(42));
+        assert!(!table.contains(41));
+        assert!(!table.contains(43));
+        assert_eq!(read_all(&path), vec![42]);
+    }
+
+    #[test]
+    fn set_is_saved_sorted() {
+        let dir = TempDir::new();
+        let path = dir.join("set.tds");
+        let set = full_search_lose2::testing::sample_set();
+        SortedTable::save(&set, &path).unwrap();
+
+        let mut expected: Vec<u64> = set.raw().iter().collect();
+        expected.sort_unstable();
+        assert_eq!(read_all(&path), expected);
+    }
+
+    #[test]
+    fn wrong_number_of_hashes_is_not_saved() {
+        let dir = TempDir::new();
+        let path = dir.join("table.tds");
+        let mut iter = [1, 2].into_iter();
+        assert!(save_stream(3, || Ok(iter.next()), &path).is_err());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn other_files_are_rejected() {
+        let dir = TempDir::new();
+        let path = dir.join("table.tds");
+        storage::save_with(&path, |w| w.write_all(b"TDCOUNTS\0\0\0\0\0\0\0\0")).unwrap();
+        assert!(SortedTable::open(&path).is_err());
+        assert!(open_reader(&path).is_err());
+
+        // The length in the header disagrees with the file
+        storage::save_with(&path, |w| {
+            w.write_all(MAGIC)?;
+            w.write_all(&2u64.to_le_bytes())?;
+            w.write_all(&1u64.to_le_bytes())
+        })
+        .unwrap();
+        assert!(SortedTable::open(&path).is_err());
+    }
+}
@@ -0,0 +1,357 @@
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use tokyodoves::collections::{BoardSet, Capacity, LazyRawBoardLoader};
+
+use crate::compact_table::{self, CompactReader};
+use crate::core_methods::merge;
+use crate::sorted_table::{self, SortedReader};
+use full_search_lose2::manifest::Manifest;
+use full_search_lose2::storage;
+
+/// Extension of tables in the format of tokyodoves
+pub const PLAIN_EXTENSION: &str = "tdl";
+
+/// Extension of tables in the compact format
+pub const COMPACT_EXTENSION: &str = "tdc";
+
+/// Extension of sorted tables
+pub const SORTED_EXTENSION: &str = "tds";
+
+/// Formats of stored tables, which are told by their extensions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Plain,
+    Compact,
+    Sorted,
+}
+
+impl Encoding {
+    pub fn of(path: impl AsRef<Path>) -> Option<Self> {
+        match path.as_ref().extension()?.to_str()? {
+            PLAIN_EXTENSION => Some(Self::Plain),
+            COMPACT_EXTENSION => Some(Self::Compact),
+            SORTED_EXTENSION => Some(Self::Sorted),
+            _ => None,
+        }
+    }
+
+    /// Whether hashes are stored in ascending order
+    pub fn is_sorted(self) -> bool {
+        matches!(self, Self::Compact | Self::Sorted)
+    }
+}
+
+fn unknown_encoding(path: &Path) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!(
+            "{path:?} is none of .{PLAIN_EXTENSION}, .{COMPACT_EXTENSION} and .{SORTED_EXTENSION} tables"
+        ),
+    )
+}
+
+/// Reader of hashes in a table of any format, one by one
+#[derive(Debug)]
+pub enum TableReader {
+    Plain(LazyRawBoardLoader<File>),
+    Compact(CompactReader<BufReader<File>>),
+    Sorted(SortedReader<BufReader<File>>),
+    SortedPlain(SortedPlainReader),
+}
+
+impl TableReader {
+    /// Opens a table after verifying its checksum.
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        match Encoding::of(path) {
+            Some(Encoding::Plain) => Ok(Self::Plain(LazyRawBoardLoader::new(
+                storage::open_verified(path)?,
+            ))),
+            Some(Encoding::Compact) => Ok(Self::Compact(compact_table::open_reader(path)?)),
+            Some(Encoding::Sorted) => Ok(Self::Sorted(sorted_table::open_reader(path)?)),
+            None => Err(unknown_encoding(path)),
+        }
+    }
+
+    /// Opens a table to read its hashes in ascending order after verifying its checksum,
+    /// where a table in the format of tokyodoves is read by [`SortedPlainReader`].
+    pub fn open_sorted(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        match Encoding::of(path) {
+            Some(Encoding::Plain) => Ok(Self::SortedPlain(SortedPlainReader::open(path)?)),
+            _ => Self::open(path),
+        }
+    }
+
+    pub fn try_next(&mut self) -> std::io::Result<Option<u64>> {
+        match self {
+            Self::Plain(loader) => loader.try_next(),
+            Self::Compact(reader) => reader.try_next(),
+            Self::Sorted(reader) => reader.try_next(),
+            Self::SortedPlain(reader) => reader.try_next(),
+        }
+    }
+}
+
+/// Reader of hashes in a table in the format of tokyodoves in ascending order.
+///
+/// The format stores the upper 32 bits of hashes once followed by the lower 32 bits of them,
+/// so only the offsets of those groups are kept in memory, and each group is sorted when it is read.
+#[derive(Debug)]
+pub struct SortedPlainReader {
+    reader: BufReader<File>,
+    /// Upper 32 bits and the offset of each group not read yet in descending order
+    groups: Vec<(u32, u64)>,
+    top: u32,
+    /// Lower 32 bits of hashes in the current group not read yet in descending order
+    bottoms: Vec<u32>,
+}
+
+impl SortedPlainReader {
+    /// Delimiter of groups in the format of tokyodoves
+    const DELIMITER: u32 = u32::MAX;
+
+    /// Opens a table after verifying its checksum and reads the offsets of its groups.
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let mut reader = BufReader::new(storage::open_verified(path)?);
+        let mut groups = Vec::new();
+        let mut offset = 0;
+        while let Some(top) = read_word(&mut reader)? {
+            offset += 4;
+            groups.push((top, offset));
+            loop {
+                let word = read_word(&mut reader)?.ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "table ends in a group of boards",
+                    )
+                })?;
+                offset += 4;
+                if word == Self::DELIMITER {
+                    break;
+                }
+            }
+        }
+        groups.sort_unstable_by(|a, b| b.cmp(a));
+        Ok(Self {
+            reader,
+            groups,
+            top: 0,
+            bottoms: Vec::new(),
+        })
+    }
+
+    pub fn try_next(&mut self) -> std::io::Result<Option<u64>> {
+        // Groups may be empty, though tokyodoves never saves them
+        while self.bottoms.is_empty() {
+            let Some(&(top, _)) = self.groups.last() else {
+                return Ok(None);
+            };
+            // Groups of the same upper bits are read together, if a table has any
+            while let Some((_, offset)) = self.groups.pop_if(|&mut (other, _)| other == top) {
+                self.read_group(offset)?;
+            }
+            self.bottoms.sort_unstable_by(|a, b| b.cmp(a));
+            self.top = top;
+        }
+        Ok(self
+            .bottoms
+            .pop()
+            .map(|bottom| ((self.top as u64) << 32) | bottom as u64))
+    }
+
+    fn read_group(&mut self, offset: u64) -> std::io::Result<()> {
+        self.reader.seek(SeekFrom::Start(offset))?;
+        while let Some(word) = read_word(&mut self.reader)? {
+            if word == Self::DELIMITER {
+                break;
+            }
+            self.bottoms.push(word);
+        }
+        Ok(())
+    }
+}
+
+/// Reads a word in big endian, or returns `None` at the end.
+fn read_word(reader: &mut impl Read) -> std::io::Result<Option<u32>> {
+    let mut buf = [0; 4];
+    match reader.read_exact(&mut buf) {
+        Ok(()) => Ok(Some(u32::from_be_bytes(buf))),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns the capacity required to load hashes in a table for which `filter` returns `true`.
+///
+/// Sorted tables are not counted, since a set grows anyway while they are loaded.
+pub fn required_capacity<F>(path: impl AsRef<Path>, filter: F) -> std::io::Result<Capacity>
+where
+    F: FnMut(&u64) -> bool,
+{
+    let path = path.as_ref();
+    match Encoding::of(path) {
+        Some(Encoding::Plain) => Ok(BoardSet::required_capacity_filter(
+            storage::open_verified(path)?,
+            filter,
+        )),
+        Some(Encoding::Compact | Encoding::Sorted) => Ok(Capacity::new()),
+        None => Err(unknown_encoding(path)),
+    }
+}
+
+/// Loads hashes in a table of any format for which `filter` returns `true` into `set`.
+pub fn load_filter<F>(
+    set: &mut BoardSet,
+    path: impl AsRef<Path>,
+    mut filter: F,
+) -> std::io::Result<()>
+where
+    F: FnMut(&u64) -> bool,
+{
+    let path = path.as_ref();
+    match Encoding::of(path) {
+        Some(Encoding::Plain) => set.load_filter(storage::open_verified(path)?, filter),
+        Some(Encoding::Compact | Encoding::Sorted) => {
+            let mut reader = TableReader::open(path)?;
+            while let Some(hash) = reader.try_next()? {
+                if filter(&hash) {
+                    set.raw_mut().insert(hash);
+                }
+            }
+            Ok(())
+        }
+        None => Err(unknown_encoding(path)),
+    }
+}
+
+/// Converts a table from `.tdl` or `.tds` into `.tdc`, or from `.tdc` into `.tdl`,
+/// and returns the new path.
+///
+/// The table is read three times, to count its hashes, to save them and to compare them with the new table,
+/// all in ascending order, so memory is used only for buffers and the offsets of groups in `.tdl`.
+/// The manifest of the table, if any, is updated to the new checksum before the old table is removed,
+/// since a manifest describes only one of them.
+pub fn convert(path: impl AsRef<Path>) -> anyhow::Result<PathBuf> {
+    let path = path.as_ref();
+    let Some(encoding) = Encoding::of(path) else {
+        return Err(unknown_encoding(path).into());
+    };
+    let mut len = 0;
+    let mut reader = TableReader::open(path)?;
+    while reader.try_next()?.is_some() {
+        len += 1;
+    }
+
+    let mut reader = TableReader::open_sorted(path)?;
+    let dst = match encoding {
+        Encoding::Plain | Encoding::Sorted => {
+            let dst = path.with_extension(COMPACT_EXTENSION);
+            compact_table::save_stream(len, || reader.try_next(), &dst)?;
+            dst
+        }
+        Encoding::Compact => {
+            let dst = path.with_extension(PLAIN_EXTENSION);
+            merge::save_plain_stream(|| reader.try_next(), &dst)?;
+            dst
+        }
+    };
+    let mut src = TableReader::open_sorted(path)?;
+    let mut converted = TableReader::open_sorted(&dst)?;
+    loop {
+        let hash = src.try_next()?;
+        if converted.try_next()? != hash {
+            return Err(anyhow::anyhow!(
+                "{dst:?} differs from {path:?} after conversion"
+            ));
+        }
+        if hash.is_none() {
+            break;
+        }
+    }
+
+    if Manifest::path_of(path).exists() {
+        let mut manifest = Manifest::load(path)?;
+        manifest.checksum = storage::recorded_checksum(&dst)?;
+        manifest.save(&dst)?;
+    }
+    storage::remove(path)?;
+    Ok(dst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use full_search_lose2::testing::{sample_set, TempDir};
+
+    /// Table of loses in 4 with 6 doves solved under the rule with removing
+    const LOSE4_6: &[u8] = include_bytes!("../testdata/lose4_6.tdc");
+
+    fn read_sorted(path: &Path) -> Vec<u64> {
+        let mut reader = TableReader::open_sorted(path).unwrap();
+        let mut hashes = Vec::new();
+        while let Some(hash) = reader.try_next().unwrap() {
+            hashes.push(hash);
+        }
+        hashes
+    }
+
+    fn file_len(path: &Path) -> u64 {
+        std::fs::metadata(path).unwrap().len()
+    }
+
+    #[test]
+    fn plain_table_is_read_in_ascending_order() {
+        let dir = TempDir::new();
+        let path = dir.join("table.tdl");
+        let mut set = sample_set();
+        // Boards sharing upper bits with others
+        for hash in [
+            0x0c53_0200_0405_00bf,
+            0x0c53_0200_0405_00b5,
+            0x0c53_0200_0000_0001,
+        ] {
+            set.raw_mut().insert(hash);
+        }
+        storage::save_set(&set, &path).unwrap();
+
+        let mut expected: Vec<u64> = set.raw().iter().collect();
+        expected.sort_unstable();
+        assert_eq!(read_sorted(&path), expected);
+    }
+
+    #[test]
+    fn real_table_is_compacted_over_three_times() {
+        let dir = TempDir::new();
+        let path = dir.join("06.tdc");
+        storage::save_with(&path, |w| w.write_all(LOSE4_6)).unwrap();
+        let hashes = read_sorted(&path);
+        assert!(hashes.len() > 3000);
+
+        let plain = convert(&path).unwrap();
+        assert_eq!(plain, dir.join("06.tdl"));
+        assert!(!path.exists());
+        assert_eq!(read_sorted(&plain), hashes);
+        assert!(file_len(&plain) >= 3 * LOSE4_6.len() as u64);
+
+        let compact = convert(&plain).unwrap();
+        assert_eq!(compact, path);
+        assert!(!plain.exists());
+        assert_eq!(std::fs::read(&compact).unwrap(), LOSE4_6);
+    }
+
+    #[test]
+    fn removed_table_is_verified_again() {
+        let dir = TempDir::new();
+        let path = dir.join("table.tdl");
+        storage::save_set(&sample_set(), &path).unwrap();
+        convert(&path).unwrap();
+
+        // A table which appears again at the same path is never trusted by its old checksum
+        std::fs::write(&path, [0; 12]).unwrap();
+        std::fs::write(storage::sum_path(&path), "fnv1a64:0000000000000000\n").unwrap();
+        assert!(storage::verify(&path).is_err());
+    }
+}
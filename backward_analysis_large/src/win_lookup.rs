@@ -0,0 +1,34 @@
+use tokyodoves::collections::BoardSet;
+
+use crate::compact_table::CompactTable;
+use crate::sorted_table::SortedTable;
+
+/// Set of wins which is only queried whether it contains a board
+pub trait WinLookup {
+    /// Checks if the board with the invariant hash `hash` is a win.
+    fn contains_hash(&self, hash: u64) -> bool;
+}
+
+impl WinLookup for BoardSet {
+    fn contains_hash(&self, hash: u64) -> bool {
+        self.raw().contains(&hash)
+    }
+}
+
+impl WinLookup for SortedTable {
+    fn contains_hash(&self, hash: u64) -> bool {
+        self.contains(hash)
+    }
+}
+
+impl WinLookup for CompactTable {
+    fn contains_hash(&self, hash: u64) -> bool {
+        self.contains(hash)
+    }
+}
+
+impl<W: WinLookup + ?Sized> WinLookup for Box<W> {
+    fn contains_hash(&self, hash: u64) -> bool {
+        (**self).contains_hash(hash)
+    }
+}
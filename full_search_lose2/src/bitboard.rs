@@ -0,0 +1,436 @@
+//! Utilities for bitboards of the 4x4 field.
+//!
+//! The `n`-th bit of a `u16` bitboard represents the square
+//! at the row `n / 4` and the column `n % 4`.
+//! The same indices are used for positions of doves in `u64` hashes
+//! created by `Board::to_u64` and `Board::to_invariant_u64`.
+
+// ****************************************************************
+//  Iterators
+// ****************************************************************
+/// An iterator over set bits, each of which is returned as a one-hot value.
+pub struct HotBitIter<T> {
+    bits: T,
+}
+
+impl<T> HotBitIter<T> {
+    pub fn new(bits: T) -> Self {
+        Self { bits }
+    }
+}
+
+/// An iterator over indices of set bits.
+pub struct BitIndexIter<T> {
+    bits: T,
+}
+
+impl<T> BitIndexIter<T> {
+    pub fn new(bits: T) -> Self {
+        Self { bits }
+    }
+}
+
+macro_rules! impl_bit_iterators {
+    ($($t:ty),*) => {
+        $(
+            impl Iterator for HotBitIter<$t> {
+                type Item = $t;
+                fn next(&mut self) -> Option<Self::Item> {
+                    if self.bits != 0 {
+                        let unit = 1 << self.bits.trailing_zeros();
+                        self.bits &= !unit;
+                        Some(unit)
+                    } else {
+                        None
+                    }
+                }
+            }
+
+            impl Iterator for BitIndexIter<$t> {
+                type Item = usize;
+                fn next(&mut self) -> Option<Self::Item> {
+                    if self.bits != 0 {
+                        let idx = self.bits.trailing_zeros();
+                        self.bits &= self.bits - 1;
+                        Some(idx as usize)
+                    } else {
+                        None
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_bit_iterators!(u16, u64);
+
+/// Returns the occupancy of the field encoded in a hash of a board.
+///
+/// `hash` is supposed to be a return value of `Board::to_u64`
+/// or `Board::to_invariant_u64`.
+pub fn occupancy_of_hash(hash: u64) -> u16 {
+    let mut bits = 0;
+    for ishift in BitIndexIter::new((hash >> 48) & 0xfff) {
+        bits |= 1 << ((hash >> (4 * ishift)) & 0xf);
+    }
+    bits
+}
+
+// ****************************************************************
+//  Adjacency
+// ****************************************************************
+/// Returns squares adjacent to `bits`, including diagonal ones.
+///
+/// Squares in `bits` are contained only if they are adjacent to other squares in `bits`.
+pub fn calc_adjacents(bits: u16) -> u16 {
+    let mut adj = (bits << 4) | (bits >> 4);
+    let center = adj | bits;
+    adj |= (center & 0xeeee) >> 1;
+    adj |= (center & 0x7777) << 1;
+    adj
+}
+
+/// Returns `true` if some square in `bits` has no adjacent squares in `bits`.
+pub fn is_isolated(bits: u16) -> bool {
+    bits & calc_adjacents(bits) != bits
+}
+
+/// Returns `true` if all squares in `bits` are connected via adjacent squares.
+pub fn is_connected(bits: u16) -> bool {
+    if bits == 0 {
+        return true;
+    }
+    let mut reached = bits & bits.wrapping_neg();
+    loop {
+        let next = (reached | calc_adjacents(reached)) & bits;
+        if next == reached {
+            return reached == bits;
+        }
+        reached = next;
+    }
+}
+
+/// Decomposes `bits` into squares surrounded on four sides and the others.
+///
+/// When the doves reach both of opposite edges, the edges are regarded as walls
+/// because the field cannot be extended in that direction.
+pub fn decompose_surrounded(bits: u16) -> (u16, u16) {
+    let edge_e = 0x1111;
+    let edge_w = 0x8888;
+    let edge_n = 0xf000;
+    let edge_s = 0x000f;
+    let is_wall_ew = (bits & edge_e) != 0 && (bits & edge_w) != 0;
+    let is_wall_ns = (bits & edge_n) != 0 && (bits & edge_s) != 0;
+
+    macro_rules! shift {
+        ($edge:expr, $is_wall:expr, $rot:ident, $rot_num:expr) => {{
+            if $is_wall {
+                bits | $edge
+            } else {
+                bits & !$edge
+            }
+            .$rot($rot_num)
+        }};
+    }
+
+    let bits_e = shift!(edge_e, is_wall_ew, rotate_right, 1);
+    let bits_w = shift!(edge_w, is_wall_ew, rotate_left, 1);
+    let bits_n = shift!(edge_n, is_wall_ns, rotate_left, 4);
+    let bits_s = shift!(edge_s, is_wall_ns, rotate_right, 4);
+
+    let surrounded = bits & bits_n & bits_e & bits_w & bits_s;
+    let not_surrounded = bits & !surrounded;
+    (surrounded, not_surrounded)
+}
+
+// ****************************************************************
+//  Symmetries
+// ****************************************************************
+/// The minimum rectangle containing squares
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rectangle {
+    pub hmin: usize,
+    pub hmax: usize,
+    pub vmin: usize,
+    pub vmax: usize,
+}
+
+impl Rectangle {
+    /// Returns the minimum rectangle containing `bits`, which must not be zero.
+    pub fn of(bits: u16) -> Self {
+        let (mut hmin, mut hmax, mut vmin, mut vmax) = (3, 0, 3, 0);
+        for n in BitIndexIter::new(bits) {
+            let (h, v) = (n % 4, n / 4);
+            hmin = hmin.min(h);
+            hmax = hmax.max(h);
+            vmin = vmin.min(v);
+            vmax = vmax.max(v);
+        }
+        Self {
+            hmin,
+            hmax,
+            vmin,
+            vmax,
+        }
+    }
+
+    pub fn hsize(&self) -> usize {
+        self.hmax - self.hmin + 1
+    }
+
+    pub fn vsize(&self) -> usize {
+        self.vmax - self.vmin + 1
+    }
+}
+
+/// Translates `bits` so that its minimum rectangle touches the top and left edges.
+pub fn align(bits: u16) -> u16 {
+    if bits == 0 {
+        return 0;
+    }
+    let rect = Rectangle::of(bits);
+    bits >> (rect.hmin + 4 * rect.vmin)
+}
+
+/// Eight congruent transformations of a rectangle
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Symmetry {
+    Identity,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+    FlipH,
+    FlipV,
+    Transpose,
+    AntiTranspose,
+}
+
+impl Symmetry {
+    pub const ALL: [Symmetry; 8] = [
+        Symmetry::Identity,
+        Symmetry::Rotate90,
+        Symmetry::Rotate180,
+        Symmetry::Rotate270,
+        Symmetry::FlipH,
+        Symmetry::FlipV,
+        Symmetry::Transpose,
+        Symmetry::AntiTranspose,
+    ];
+
+    /// Returns a permutation of squares which maps a `vsize` x `hsize` rectangle
+    /// at the top-left corner to its image, also placed at the top-left corner.
+    ///
+    /// Only the squares inside the rectangle are meaningful.
+    pub fn permutation(self, vsize: usize, hsize: usize) -> [usize; 16] {
+        use Symmetry::*;
+        let mut perm: [usize; 16] = std::array::from_fn(|n| n);
+        for v in 0..vsize {
+            for h in 0..hsize {
+                let (v_new, h_new) = match self {
+                    Identity => (v, h),
+                    Rotate90 => (h, vsize - 1 - v),
+                    Rotate180 => (vsize - 1 - v, hsize - 1 - h),
+                    Rotate270 => (hsize - 1 - h, v),
+                    FlipH => (v, hsize - 1 - h),
+                    FlipV => (vsize - 1 - v, h),
+                    Transpose => (h, v),
+                    AntiTranspose => (hsize - 1 - h, vsize - 1 - v),
+                };
+                perm[h + 4 * v] = h_new + 4 * v_new;
+            }
+        }
+        perm
+    }
+
+    /// Maps `bits` and aligns the image to the top-left corner.
+    pub fn apply(self, bits: u16) -> u16 {
+        if bits == 0 {
+            return 0;
+        }
+        let aligned = align(bits);
+        let rect = Rectangle::of(aligned);
+        map_bits(aligned, &self.permutation(rect.vsize(), rect.hsize()))
+    }
+}
+
+/// Maps each square of `bits` by `perm`.
+pub fn map_bits(bits: u16, perm: &[usize; 16]) -> u16 {
+    let mut mapped = 0;
+    for n in BitIndexIter::new(bits) {
+        mapped |= 1 << perm[n];
+    }
+    mapped
+}
+
+/// Returns the minimum among the images of `bits` under all the symmetries.
+pub fn canonicalize(bits: u16) -> u16 {
+    Symmetry::ALL
+        .iter()
+        .map(|sym| sym.apply(bits))
+        .min()
+        .unwrap()
+}
+
+/// Returns permutations of squares which map `bits` onto itself.
+///
+/// `bits` is supposed to be aligned to the top-left corner,
+/// as the return value of `canonicalize` is.
+/// The identity is always contained.
+pub fn find_symmetries(bits: u16) -> Vec<[usize; 16]> {
+    let rect = Rectangle::of(bits);
+    Symmetry::ALL
+        .iter()
+        .map(|sym| sym.permutation(rect.vsize(), rect.hsize()))
+        .filter(|perm| map_bits(bits, perm) == bits)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::str::FromStr;
+    use tokyodoves::{Board, BoardBuilder, Color};
+
+    fn from_rows(rows: [&str; 4]) -> u16 {
+        let mut bits = 0;
+        for (v, row) in rows.iter().enumerate() {
+            for (h, c) in row.chars().enumerate() {
+                if c == '#' {
+                    bits |= 1 << (h + 4 * v);
+                }
+            }
+        }
+        bits
+    }
+
+    #[test]
+    fn iterators() {
+        let bits: u16 = 0b1010_0000_0000_0110;
+        assert_eq!(
+            HotBitIter::new(bits).collect::<Vec<_>>(),
+            vec![0b10, 0b100, 1 << 13, 1 << 15]
+        );
+        assert_eq!(
+            BitIndexIter::new(bits).collect::<Vec<_>>(),
+            vec![1, 2, 13, 15]
+        );
+
+        let hash: u64 = (1 << 63) | (1 << 40) | 1;
+        assert_eq!(HotBitIter::new(hash).count(), 3);
+        assert_eq!(BitIndexIter::new(hash).collect::<Vec<_>>(), vec![0, 40, 63]);
+        assert_eq!(HotBitIter::new(0_u64).next(), None);
+    }
+
+    #[test]
+    fn occupancy() {
+        // B at 4 and b at 0
+        assert_eq!(occupancy_of_hash(Board::new().to_u64()), 0b1_0001);
+
+        let board = BoardBuilder::from_str("bB; H; y").unwrap().build().unwrap();
+        let expected = from_rows(["##..", ".#..", ".#..", "...."]);
+        assert_eq!(occupancy_of_hash(board.to_u64()), expected);
+        let invariant = occupancy_of_hash(board.to_invariant_u64(Color::Red));
+        assert_eq!(canonicalize(invariant), canonicalize(expected));
+    }
+
+    #[test]
+    fn adjacency() {
+        let bits = from_rows(["....", ".#..", "....", "...."]);
+        assert_eq!(
+            calc_adjacents(bits),
+            from_rows(["###.", "#.#.", "###.", "...."])
+        );
+
+        let corner = from_rows(["#...", "....", "....", "...."]);
+        assert_eq!(
+            calc_adjacents(corner),
+            from_rows([".#..", "##..", "....", "...."])
+        );
+
+        // no wrap-around between the east and west edges
+        let edge = from_rows(["...#", "....", "....", "...."]);
+        assert_eq!(
+            calc_adjacents(edge),
+            from_rows(["..#.", "..##", "....", "...."])
+        );
+    }
+
+    #[test]
+    fn connectivity() {
+        let diagonal = from_rows(["#...", ".#..", "..#.", "...#"]);
+        assert!(is_connected(diagonal));
+        assert!(!is_isolated(diagonal));
+
+        let two_pairs = from_rows(["##..", "....", "..##", "...."]);
+        assert!(!is_connected(two_pairs));
+        assert!(!is_isolated(two_pairs));
+
+        let single = from_rows(["#...", "....", "...#", "...."]);
+        assert!(!is_connected(single));
+        assert!(is_isolated(single));
+
+        assert!(is_connected(0));
+        assert!(is_connected(0xffff));
+    }
+
+    #[test]
+    fn surrounded() {
+        let plus = from_rows([".#..", "###.", ".#..", "...."]);
+        let center = from_rows(["....", ".#..", "....", "...."]);
+        assert_eq!(decompose_surrounded(plus), (center, plus & !center));
+
+        // the doves reach both the east and the west edges, which become walls
+        let row = from_rows(["....", "####", "....", "...."]);
+        assert_eq!(decompose_surrounded(row), (0, row));
+        let bar = from_rows(["#...", "####", "#...", "...."]);
+        let surrounded = from_rows(["....", "#...", "....", "...."]);
+        assert_eq!(decompose_surrounded(bar), (surrounded, bar & !surrounded));
+    }
+
+    #[test]
+    fn symmetries() {
+        let l_shape = from_rows(["#...", "#...", "##..", "...."]);
+        let images: HashSet<u16> = Symmetry::ALL.iter().map(|s| s.apply(l_shape)).collect();
+        assert_eq!(images.len(), 8);
+        for image in images.iter() {
+            assert_eq!(align(*image), *image);
+            assert_eq!(image.count_ones(), 4);
+            assert_eq!(canonicalize(*image), canonicalize(l_shape));
+        }
+
+        assert_eq!(
+            Symmetry::Rotate90.apply(from_rows(["###.", "....", "....", "...."])),
+            from_rows(["#...", "#...", "#...", "...."])
+        );
+        assert_eq!(
+            Symmetry::FlipH.apply(from_rows(["....", "..##", "..#.", "...."])),
+            from_rows(["##..", ".#..", "....", "...."])
+        );
+
+        for sym in Symmetry::ALL {
+            for bits in [l_shape, from_rows(["##..", ".##.", "..#.", "...."])] {
+                let rotated = Symmetry::Rotate90.apply(sym.apply(bits));
+                assert!(Symmetry::ALL.iter().any(|s| s.apply(bits) == rotated));
+            }
+        }
+    }
+
+    #[test]
+    fn stabilizers() {
+        let square = canonicalize(from_rows(["....", ".##.", ".##.", "...."]));
+        assert_eq!(find_symmetries(square).len(), 8);
+
+        let bar = canonicalize(from_rows(["....", "###.", "....", "...."]));
+        assert_eq!(find_symmetries(bar).len(), 4);
+
+        let l_shape = canonicalize(from_rows(["#...", "#...", "##..", "...."]));
+        assert_eq!(find_symmetries(l_shape), vec![std::array::from_fn(|n| n)]);
+
+        for perm in find_symmetries(canonicalize(from_rows(["#.#.", "###.", "....", "...."]))) {
+            let mut sorted = perm;
+            sorted.sort_unstable();
+            assert_eq!(sorted, std::array::from_fn(|n| n));
+        }
+    }
+}
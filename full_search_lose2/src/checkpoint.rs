@@ -0,0 +1,221 @@
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokyodoves::collections::*;
+
+use crate::ShapeRecord;
+
+const COMPLETED_FILE_NAME: &str = "completed.txt";
+const PART_PREFIX: &str = "part_";
+
+/// A directory where partial results of a search are saved.
+///
+/// It contains `part_NNNNNN.tdl` files, each of which is a flushed pool of a thread,
+/// and `completed.txt`, which lists shapes whose results are already in some part file.
+/// A part file is always written before its shapes are listed,
+/// so that a crash never marks a shape as completed without its result.
+pub struct Checkpoint {
+    dir: PathBuf,
+    completed: Mutex<File>,
+    next_part: AtomicUsize,
+}
+
+impl Checkpoint {
+    /// Opens `dir` as a checkpoint directory.
+    ///
+    /// If `resume` is `false`, `dir` must not exist yet,
+    /// so that results of an interrupted search are never discarded silently.
+    pub fn open(dir: impl AsRef<Path>, resume: bool) -> std::io::Result<Self> {
+        let dir = dir.as_ref().to_owned();
+        if !resume && dir.exists() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("checkpoint {dir:?} already exists; resume or remove it"),
+            ));
+        }
+        std::fs::create_dir_all(&dir)?;
+
+        let mut next_part = 0;
+        for path in Self::part_paths(&dir)? {
+            let idx = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.strip_prefix(PART_PREFIX))
+                .and_then(|s| s.parse::<usize>().ok());
+            if let Some(idx) = idx {
+                next_part = next_part.max(idx + 1);
+            }
+        }
+
+        let completed_path = dir.join(COMPLETED_FILE_NAME);
+        let completed = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&completed_path)?;
+        // A line cut off by a crash is dropped so that the next line is not appended to it
+        let text = std::fs::read(&completed_path)?;
+        let len_complete = text.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+        if len_complete < text.len() {
+            completed.set_len(len_complete as u64)?;
+        }
+        Ok(Self {
+            dir,
+            completed: Mutex::new(completed),
+            next_part: AtomicUsize::new(next_part),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Returns records of shapes listed as completed.
+    ///
+    /// Only the last line may be cut off, by a crash while it is appended,
+    /// and it is ignored then. Any other line must be complete,
+    /// so that a list written in another format is never misread.
+    pub fn completed_shapes(&self) -> std::io::Result<Vec<ShapeRecord>> {
+        let text = std::fs::read_to_string(self.dir.join(COMPLETED_FILE_NAME))?;
+        let mut shapes = Vec::new();
+        let mut found = HashSet::new();
+        for line in text.split_inclusive('\n') {
+            let Some(line) = line.strip_suffix('\n') else {
+                break;
+            };
+            let record = parse_record(line).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "invalid line {line:?} in {:?}, which may be written by an older version; \
+                         remove the checkpoint and search again",
+                        self.dir.join(COMPLETED_FILE_NAME)
+                    ),
+                )
+            })?;
+            if found.insert(record.bits) {
+                shapes.push(record);
+            }
+        }
+        Ok(shapes)
+    }
+
+    /// Saves `pool` as a new part file, records `shapes` as completed and clears `pool`.
+    pub fn flush(&self, pool: &mut BoardSet, shapes: &[ShapeRecord]) -> std::io::Result<()> {
+        let idx = self.next_part.fetch_add(1, Ordering::Relaxed);
+        let path = self.dir.join(format!("{PART_PREFIX}{idx:0>6}.tdl"));
+        let tmp_path = path.with_extension("tdl.tmp");
+        let file = File::create(&tmp_path)?;
+        pool.save(&file)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, &path)?;
+
+        let mut lines = String::new();
+        for record in shapes {
+            lines += &format!(
+                "{:04x} {} {:.3}\n",
+                record.bits,
+                record.num_lose2,
+                record.elapsed.as_secs_f64()
+            );
+        }
+        let mut completed = self.completed.lock().unwrap();
+        completed.write_all(lines.as_bytes())?;
+        completed.sync_all()?;
+
+        pool.clear();
+        Ok(())
+    }
+
+    /// Loads all part files into a single set.
+    pub fn load_parts(&self) -> std::io::Result<BoardSet> {
+        let paths = Self::part_paths(&self.dir)?;
+        let mut capacity = Capacity::new();
+        for path in paths.iter() {
+            capacity += BoardSet::required_capacity(File::open(path)?);
+        }
+        let mut set = BoardSet::with_capacity(capacity);
+        for path in paths.iter() {
+            set.load(File::open(path)?)?;
+        }
+        Ok(set)
+    }
+
+    /// Removes the checkpoint directory, which is no longer needed
+    /// once the final result is saved.
+    pub fn remove(self) -> std::io::Result<()> {
+        drop(self.completed);
+        std::fs::remove_dir_all(&self.dir)
+    }
+
+    fn part_paths(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let is_part = path
+                .file_name()
+                .and_then(|s| s.to_str())
+                .is_some_and(|s| s.starts_with(PART_PREFIX) && s.ends_with(".tdl"));
+            if is_part {
+                paths.push(path);
+            }
+        }
+        Ok(paths)
+    }
+}
+
+/// Parses a line of `completed.txt`, which is `<bits> <num_lose2> <seconds>`.
+fn parse_record(line: &str) -> Option<ShapeRecord> {
+    let mut items = line.split_whitespace();
+    let (Some(bits), Some(num_lose2), Some(secs), None) =
+        (items.next(), items.next(), items.next(), items.next())
+    else {
+        return None;
+    };
+    Some(ShapeRecord {
+        bits: u16::from_str_radix(bits, 16).ok()?,
+        num_lose2: num_lose2.parse().ok()?,
+        elapsed: Duration::try_from_secs_f64(secs.parse().ok()?).ok()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checkpoint_with(name: &str, completed: &str) -> Checkpoint {
+        let dir = std::env::temp_dir().join(format!("checkpoint-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(COMPLETED_FILE_NAME), completed).unwrap();
+        Checkpoint::open(dir, true).unwrap()
+    }
+
+    #[test]
+    fn cut_off_line_is_dropped() {
+        let checkpoint = checkpoint_with("cut-off", "0a3f 12 1.500\n0b00 3");
+        checkpoint.flush(&mut BoardSet::new(), &[]).unwrap();
+        let record = ShapeRecord {
+            bits: 0x0c00,
+            num_lose2: 4,
+            elapsed: Duration::ZERO,
+        };
+        checkpoint.flush(&mut BoardSet::new(), &[record]).unwrap();
+
+        let shapes = checkpoint.completed_shapes().unwrap();
+        let shapes: Vec<_> = shapes.iter().map(|r| (r.bits, r.num_lose2)).collect();
+        assert_eq!(shapes, [(0x0a3f, 12), (0x0c00, 4)]);
+        checkpoint.remove().unwrap();
+    }
+
+    #[test]
+    fn lines_in_old_format_are_rejected() {
+        let checkpoint = checkpoint_with("old-format", "0a3f 1.500\n");
+        let err = checkpoint.completed_shapes().err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        checkpoint.remove().unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+/// Name of the config file read from the current directory
+/// when neither `--config` nor `TOKYODOVES_CONFIG` is given
+pub const DEFAULT_CONFIG_FILE_NAME: &str = "backward_analysis.toml";
+
+/// Default number of boards read at once by backstep
+pub const DEFAULT_MAX_CHUNK_SIZE: usize = 400_000_000;
+
+/// Reads a config file in TOML.
+pub fn from_file<T>(path: impl AsRef<Path>) -> anyhow::Result<T>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("cannot read config {path:?}: {e}"))?;
+    toml::from_str(&text).map_err(|e| anyhow::anyhow!("invalid config {path:?}: {e}"))
+}
+
+/// Reads the config file specified by `path`, `TOKYODOVES_CONFIG` or the default name
+/// in this order of precedence.
+/// If none of them is given and the default file does not exist, nothing is specified.
+pub fn from_file_or_default<T>(path: Option<&Path>) -> anyhow::Result<T>
+where
+    T: serde::de::DeserializeOwned + Default,
+{
+    if let Some(path) = path {
+        return from_file(path);
+    }
+    if let Some(path) = std::env::var_os("TOKYODOVES_CONFIG") {
+        return from_file(path);
+    }
+    let path = Path::new(DEFAULT_CONFIG_FILE_NAME);
+    if path.exists() {
+        return from_file(path);
+    }
+    Ok(T::default())
+}
+
+/// Reads an environment variable, which is `None` if not present.
+pub fn var(name: &str) -> anyhow::Result<Option<String>> {
+    match std::env::var(name) {
+        Ok(value) => Ok(Some(value)),
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(e) => Err(anyhow::anyhow!("invalid {name}: {e}")),
+    }
+}
+
+/// Reads and parses an environment variable, which is `None` if not present.
+pub fn parse<T>(name: &str) -> anyhow::Result<Option<T>>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    var(name)?
+        .map(|value| {
+            value
+                .trim()
+                .parse()
+                .map_err(|e| anyhow::anyhow!("invalid {name}={value:?}: {e}"))
+        })
+        .transpose()
+}
+
+/// Reads an environment variable of a comma-separated list of numbers.
+pub fn parse_list(name: &str) -> anyhow::Result<Option<Vec<usize>>> {
+    var(name)?
+        .map(|value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(|s| {
+                    s.parse()
+                        .map_err(|e| anyhow::anyhow!("invalid {name}={value:?}: {e}"))
+                })
+                .collect()
+        })
+        .transpose()
+}
+
+/// Validates the data root, which must be an existing directory.
+pub fn resolve_data_root(data_root: Option<PathBuf>) -> anyhow::Result<PathBuf> {
+    let Some(data_root) = data_root else {
+        return Err(anyhow::anyhow!(
+            "data root is not specified; use --src-dir, TOKYODOVES_DATA_ROOT or `data_root` in config"
+        ));
+    };
+    if !data_root.is_dir() {
+        return Err(anyhow::anyhow!(
+            "data root {data_root:?} is not a directory"
+        ));
+    }
+    Ok(data_root)
+}
+
+/// Fills a number defaulting to the available parallelism and checks that it is positive.
+pub fn resolve_parallelism(value: Option<usize>, what: &str) -> anyhow::Result<usize> {
+    let value = match value {
+        Some(n) => n,
+        None => std::thread::available_parallelism()?.get(),
+    };
+    resolve_positive(Some(value), value, what)
+}
+
+/// Fills a number with `default` and checks that it is positive.
+pub fn resolve_positive(value: Option<usize>, default: usize, what: &str) -> anyhow::Result<usize> {
+    let value = value.unwrap_or(default);
+    if value == 0 {
+        return Err(anyhow::anyhow!("{what} must be positive"));
+    }
+    Ok(value)
+}
+
+/// Fills the range of numbers of doves with `2..=12` and validates it.
+pub fn resolve_doves(
+    min_doves: Option<usize>,
+    max_doves: Option<usize>,
+) -> anyhow::Result<RangeInclusive<usize>> {
+    let min_doves = min_doves.unwrap_or(2);
+    let max_doves = max_doves.unwrap_or(12);
+    if !(2..=12).contains(&min_doves) || !(min_doves..=12).contains(&max_doves) {
+        return Err(anyhow::anyhow!(
+            "numbers of doves must satisfy 2 <= min <= max <= 12, but min={min_doves}, max={max_doves}"
+        ));
+    }
+    Ok(min_doves..=max_doves)
+}
+
+/// Validates numbers of doves whose data is split.
+pub fn resolve_split(split: Option<Vec<usize>>) -> anyhow::Result<Vec<usize>> {
+    let split = split.unwrap_or_default();
+    if let Some(n) = split.iter().find(|n| !(2..=12).contains(*n)) {
+        return Err(anyhow::anyhow!("invalid number of doves to split: {n}"));
+    }
+    Ok(split)
+}
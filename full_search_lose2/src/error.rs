@@ -0,0 +1,164 @@
+use std::path::{Path, PathBuf};
+use std::thread::JoinHandle;
+
+/// Phases of a step of the backward analysis
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Phase {
+    Backstep,
+    TrimSimply,
+    TrimOnAction,
+    Gather,
+    Retrograde,
+    Solve,
+}
+
+impl std::fmt::Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Backstep => "backstep",
+            Self::TrimSimply => "trim simply",
+            Self::TrimOnAction => "trim on action",
+            Self::Gather => "gather",
+            Self::Retrograde => "retrograde",
+            Self::Solve => "solve",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Where an error occurs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Context {
+    pub phase: Phase,
+    pub num_doves: Option<usize>,
+    pub shard: Option<usize>,
+    pub path: Option<PathBuf>,
+}
+
+impl Context {
+    pub fn new(phase: Phase) -> Self {
+        Self {
+            phase,
+            num_doves: None,
+            shard: None,
+            path: None,
+        }
+    }
+
+    pub fn doves(mut self, num_doves: usize) -> Self {
+        self.num_doves = Some(num_doves);
+        self
+    }
+
+    pub fn shard(mut self, shard: usize) -> Self {
+        self.shard = Some(shard);
+        self
+    }
+
+    pub fn path(mut self, path: impl AsRef<Path>) -> Self {
+        self.path = Some(path.as_ref().to_owned());
+        self
+    }
+}
+
+impl std::fmt::Display for Context {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "in {}", self.phase)?;
+        let mut details = Vec::new();
+        if let Some(num_doves) = self.num_doves {
+            details.push(format!("#doves={num_doves}"));
+        }
+        if let Some(shard) = self.shard {
+            details.push(format!("shard={shard}"));
+        }
+        if let Some(path) = &self.path {
+            details.push(format!("path={path:?}"));
+        }
+        if !details.is_empty() {
+            write!(f, " ({})", details.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Errors of the phases of the backward analysis
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("I/O error {context}")]
+    Io {
+        context: Context,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("invalid input {context}")]
+    InvalidInput {
+        context: Context,
+        #[source]
+        source: anyhow::Error,
+    },
+
+    #[error("invalid argument {context}: {message}")]
+    InvalidArgument { context: Context, message: String },
+
+    #[error("worker thread panicked {context}: {message}")]
+    Panicked { context: Context, message: String },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Attaches a context to errors of other types.
+pub trait ResultExt<T> {
+    fn with_context(self, context: impl FnOnce() -> Context) -> Result<T>;
+}
+
+impl<T> ResultExt<T> for std::io::Result<T> {
+    fn with_context(self, context: impl FnOnce() -> Context) -> Result<T> {
+        self.map_err(|source| Error::Io {
+            context: context(),
+            source,
+        })
+    }
+}
+
+impl<T> ResultExt<T> for anyhow::Result<T> {
+    fn with_context(self, context: impl FnOnce() -> Context) -> Result<T> {
+        self.map_err(|source| Error::InvalidInput {
+            context: context(),
+            source,
+        })
+    }
+}
+
+impl Error {
+    /// Turns the payload of a panic into an error with `context`.
+    pub fn from_panic(context: Context, payload: Box<dyn std::any::Any + Send>) -> Self {
+        let message = if let Some(s) = payload.downcast_ref::<&str>() {
+            s.to_string()
+        } else if let Some(s) = payload.downcast_ref::<String>() {
+            s.clone()
+        } else {
+            "unknown panic".to_owned()
+        };
+        Self::Panicked { context, message }
+    }
+}
+
+/// Joins all worker threads before returning the first error.
+///
+/// A panic of the `i`-th thread is turned into an error with `context(i)`.
+pub fn join_all<T>(
+    handles: Vec<JoinHandle<Result<T>>>,
+    context: impl Fn(usize) -> Context,
+) -> Result<Vec<T>> {
+    let results: Vec<Result<T>> = handles
+        .into_iter()
+        .enumerate()
+        .map(|(i, handle)| {
+            handle
+                .join()
+                .unwrap_or_else(|payload| Err(Error::from_panic(context(i), payload)))
+        })
+        .collect();
+    results.into_iter().collect()
+}
@@ -0,0 +1,9 @@
+pub mod bitboard;
+pub mod config;
+pub mod error;
+pub mod manifest;
+pub mod rule;
+pub mod search;
+pub mod storage;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
@@ -1,226 +1,135 @@
-use itertools::{self, iproduct, Itertools};
-use std::collections::HashSet;
+mod checkpoint;
+
+use checkpoint::Checkpoint;
+use full_search_lose2::{bitboard::*, manifest::Manifest, rule::RuleVariant, search::*, storage};
+use itertools::{self, Itertools};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::thread;
-use tokyodoves::strum::IntoEnumIterator;
-use tokyodoves::{analysis::*, collections::*, game::*, *};
+use std::time::{Duration, Instant};
+use tokyodoves::{collections::*, game::*};
 
-struct HotBitIter<T> {
-    bits: T,
-}
-
-impl<T> HotBitIter<T> {
-    fn new(bits: T) -> Self {
-        Self { bits }
-    }
-}
-
-impl Iterator for HotBitIter<u16> {
-    type Item = u16;
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.bits != 0 {
-            let unit = 1 << self.bits.trailing_zeros();
-            self.bits &= !unit;
-            Some(unit)
-        } else {
-            None
-        }
-    }
-}
-
-fn decompose_surrounded(bits: u16) -> (u16, u16) {
-    let edge_e = 0x1111;
-    let edge_w = 0x8888;
-    let edge_n = 0xf000;
-    let edge_s = 0x000f;
-    let is_wall_ew = (bits & edge_e) != 0 && (bits & edge_w) != 0;
-    let is_wall_ns = (bits & edge_n) != 0 && (bits & edge_s) != 0;
-
-    macro_rules! shift {
-        ($edge:expr, $is_wall:expr, $rot:ident, $rot_num:expr) => {{
-            if $is_wall {
-                bits | $edge
-            } else {
-                bits & !$edge
-            }
-            .$rot($rot_num)
-        }};
-    }
-
-    let bits_e = shift!(edge_e, is_wall_ew, rotate_right, 1);
-    let bits_w = shift!(edge_w, is_wall_ew, rotate_left, 1);
-    let bits_n = shift!(edge_n, is_wall_ns, rotate_left, 4);
-    let bits_s = shift!(edge_s, is_wall_ns, rotate_right, 4);
-
-    let surrounded = bits & bits_n & bits_e & bits_w & bits_s;
-    let not_surrounded = bits & !surrounded;
-    (surrounded, not_surrounded)
-}
-
-fn boss_may_die(board: &Board, player: Color) -> bool {
-    for action in board.legal_actions(player, false, true, false) {
-        if !matches!(action, Action::Move(_, Dove::B, _)) {
-            continue;
-        }
-        let b = board.perform_unchecked_copied(action);
-        if b.liberty_of_boss(player) >= 2 {
-            return false;
-        }
-    }
-    true
-}
-
-fn pack_lose2(pool: &mut BoardSet, bits: u16, rule: GameRule) {
-    fn _color_to_index(color: Color) -> usize {
-        use Color::*;
-        match color {
-            Red => 0,
-            Green => 1,
-        }
-    }
-
-    fn _dove_to_index(dove: Dove) -> usize {
-        use Dove::*;
-        match dove {
-            B => 0,
-            A => 1,
-            Y => 2,
-            M => 3,
-            T => 4,
-            H => 5,
-        }
-    }
-
-    let lose2 = BoardValue::lose(2).unwrap();
-    let (_, not_surrounded) = decompose_surrounded(bits);
-    let num_res = bits.count_ones() as usize - 2;
-    for bosses in HotBitIter::new(not_surrounded).permutations(2) {
-        let rb = bosses[0];
-        let gb = bosses[1];
-        let positions_base = [[rb, 0, 0, 0, 0, 0], [gb, 0, 0, 0, 0, 0]];
-        let others: Vec<u16> = HotBitIter::new(bits & !(rb | gb)).collect();
-
-        let mut needs_to_check_necessary_condition = true;
-        for cd in iproduct!(Color::iter(), Dove::iter().skip(1)).permutations(num_res) {
-            let mut positions = positions_base;
-            for ((c, d), &pos) in cd.into_iter().zip(others.iter()) {
-                let ic = _color_to_index(c);
-                let id = _dove_to_index(d);
-                positions[ic][id] = pos;
-            }
+const NUM_SLOWEST_SHAPES_SHOWN: usize = 10;
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(600);
 
-            let board = BoardBuilder::from_u16_bits(positions).build_unchecked();
-            if needs_to_check_necessary_condition {
-                if boss_may_die(&board, Color::Red) {
-                    needs_to_check_necessary_condition = false;
-                } else {
-                    break;
-                }
-            }
-
-            if matches!(
-                compare_board_value(board, lose2, Color::Red, rule),
-                Ok(std::cmp::Ordering::Equal)
-            ) {
-                pool.raw_mut().insert(board.to_invariant_u64(Color::Red));
-            }
-        }
-    }
+/// Result of `pack_lose2` on a shape
+pub struct ShapeRecord {
+    pub bits: u16,
+    pub num_lose2: usize,
+    pub elapsed: Duration,
 }
 
-fn get_canonical_bits(nums: &[usize]) -> u16 {
-    fn _calc_rectangle(nums: &[usize]) -> (usize, usize, usize, usize) {
-        let (mut hmin, mut hmax, mut vmin, mut vmax) = (3, 0, 3, 0);
-        for n in nums {
-            let (h, v) = (n % 4, n / 4);
-            hmin = hmin.min(h);
-            hmax = hmax.max(h);
-            vmin = vmin.min(v);
-            vmax = vmax.max(v);
-        }
-        (hmin, hmax, vmin, vmax)
-    }
-
-    let (hmin, hmax, vmin, vmax) = _calc_rectangle(nums);
-    let idx_shift = hmin + 4 * vmin;
-    let aligned: Vec<usize> = nums.iter().map(|n| n - idx_shift).collect();
-    let (hsize, vsize) = (hmax - hmin + 1, vmax - vmin + 1);
-
-    fn _nums_to_bits(nums: impl Iterator<Item = usize>) -> u16 {
-        let mut bits = 0;
-        for n in nums {
-            bits |= 1_u16 << n;
-        }
-        bits
+impl std::fmt::Display for ShapeRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:0>16b}: {:.3}s, #lose2={}",
+            self.bits,
+            self.elapsed.as_secs_f64(),
+            self.num_lose2
+        )
     }
-
-    let mapper = PositionMapper::try_create(vsize, hsize).unwrap();
-    (0..8)
-        .map(|idx| _nums_to_bits(aligned.iter().map(|n| mapper.map(idx, *n))))
-        .min()
-        .unwrap()
 }
 
-fn find_all_bits(num_doves: usize) -> HashSet<u16> {
-    fn _calc_adjacents(bits: u16) -> u16 {
-        let mut adj = (bits << 4) | (bits >> 4);
-        let center = adj | bits;
-        adj |= (center & 0xeeee) >> 1;
-        adj |= (center & 0x7777) << 1;
-        adj
-    }
-
-    fn _is_isolated(bits: u16) -> bool {
-        bits & _calc_adjacents(bits) != bits
-    }
-
-    let mut all_bits = HashSet::new();
-    for v_idx in (0..16).combinations(num_doves) {
-        let bits = get_canonical_bits(&v_idx);
-        if _is_isolated(bits) {
-            continue;
-        }
-        all_bits.insert(bits);
-    }
-    all_bits
-}
-
-fn find_all_lose2(num_doves: usize, rule: GameRule, num_thread: usize) -> BoardSet {
+fn find_all_lose2(
+    num_doves: usize,
+    rule: GameRule,
+    num_thread: usize,
+    checkpoint: Arc<Checkpoint>,
+) -> std::io::Result<(BoardSet, Vec<ShapeRecord>)> {
     println!(
         "[Thread Main] #doves={}, #threads={}",
         num_doves, num_thread
     );
 
-    let all_bits = Arc::new(find_all_bits(num_doves).into_iter().collect_vec());
-    let len_pack = (all_bits.len() + num_thread - 1) / num_thread;
+    let completed = checkpoint.completed_shapes()?;
+    let completed_bits: HashSet<u16> = completed.iter().map(|record| record.bits).collect();
+    if !completed.is_empty() {
+        println!(
+            "[Thread Main] Resumed from {:?}: {} shapes completed",
+            checkpoint.dir(),
+            completed.len()
+        );
+    }
+
+    // The most expensive shapes go first so that no thread is left
+    // with a heavy shape at the end.
+    let all_bits = find_all_bits(num_doves)
+        .into_iter()
+        .filter(|bits| !completed_bits.contains(bits))
+        .map(|bits| (estimate_cost(bits), bits))
+        .sorted_unstable_by(|x, y| y.cmp(x))
+        .map(|(_, bits)| bits)
+        .collect_vec();
+    let all_bits = Arc::new(all_bits);
+    let num_total = all_bits.len();
+    let next_idx = Arc::new(AtomicUsize::new(0));
+    let num_finished = Arc::new(AtomicUsize::new(0));
+    println!("[Thread Main] Total={num_total}");
 
     let mut handlers = Vec::with_capacity(num_thread);
     for i in 0..num_thread {
         let all_bits = Arc::clone(&all_bits);
-        handlers.push(thread::spawn(move || {
+        let next_idx = Arc::clone(&next_idx);
+        let num_finished = Arc::clone(&num_finished);
+        let checkpoint = Arc::clone(&checkpoint);
+        handlers.push(thread::spawn(move || -> std::io::Result<_> {
             let mut pool = BoardSet::new();
-            let begin = all_bits.len().min(i * len_pack);
-            let end = all_bits.len().min((i + 1) * len_pack);
-            let num_total = end - begin;
-
-            println!("[Thread {i}] started! Total={num_total}");
-
-            for (count, bits) in all_bits[begin..end].iter().enumerate() {
-                pack_lose2(&mut pool, *bits, rule);
-                if (count + 1) % 10 == 0 || count + 1 == num_total {
+            let mut records = Vec::new();
+            let mut num_flushed = 0;
+            let mut last_flush = Instant::now();
+            println!("[Thread {i}] started!");
+
+            loop {
+                let idx = next_idx.fetch_add(1, Ordering::Relaxed);
+                let Some(&bits) = all_bits.get(idx) else {
+                    break;
+                };
+                let start = Instant::now();
+                let len_before = pool.len();
+                pack_lose2(&mut pool, bits, rule);
+                records.push(ShapeRecord {
+                    bits,
+                    num_lose2: pool.len() - len_before,
+                    elapsed: start.elapsed(),
+                });
+
+                let count = num_finished.fetch_add(1, Ordering::Relaxed) + 1;
+                if count.is_multiple_of(10) || count == num_total {
                     println!(
-                        "[Thread {i}] {} from {num_total} ({}%)",
-                        count + 1,
-                        ((count + 1) as f32) / (num_total as f32) * 100_f32
+                        "[Thread {i}] {count} from {num_total} ({}%)",
+                        (count as f32) / (num_total as f32) * 100_f32
                     );
                 }
+
+                if last_flush.elapsed() >= CHECKPOINT_INTERVAL {
+                    checkpoint.flush(&mut pool, &records[num_flushed..])?;
+                    num_flushed = records.len();
+                    last_flush = Instant::now();
+                    println!("[Thread {i}] flushed to checkpoint");
+                }
             }
             println!("[Thread {i}] finished!");
-            pool
+            Ok((pool, records))
         }))
     }
 
-    let results: Vec<BoardSet> = handlers.into_iter().map(|x| x.join().unwrap()).collect();
+    let mut results = Vec::with_capacity(num_thread);
+    let mut records = completed;
+    for handler in handlers {
+        let (pool, records_local) = handler.join().unwrap()?;
+        results.push(pool);
+        records.extend(records_local);
+    }
+    results.push(checkpoint.load_parts()?);
+
+    records.sort_unstable_by_key(|record| std::cmp::Reverse(record.elapsed));
+    println!("[Thread Main] Slowest shapes:");
+    for record in records.iter().take(NUM_SLOWEST_SHAPES_SHOWN) {
+        println!("{record}");
+    }
 
     let mut capacity = Capacity::new();
     for set in results.iter() {
@@ -233,28 +142,208 @@ fn find_all_lose2(num_doves: usize, rule: GameRule, num_thread: usize) -> BoardS
     }
     println!("[Thread Main] Concatenated");
     println!("Total={}", lose2_set.len());
-    lose2_set
+    Ok((lose2_set, records))
+}
+
+/// Rebuilds records of shapes from a saved set of lose in 2,
+/// where the time spent on each shape is no longer known and left zero.
+fn records_of(lose2_set: &BoardSet, num_doves: usize) -> Vec<ShapeRecord> {
+    let mut counts: HashMap<u16, usize> = find_all_bits(num_doves)
+        .into_iter()
+        .map(|bits| (bits, 0))
+        .collect();
+    for hash in lose2_set.raw().iter() {
+        *counts
+            .entry(canonicalize(occupancy_of_hash(hash)))
+            .or_default() += 1;
+    }
+    counts
+        .into_iter()
+        .map(|(bits, num_lose2)| ShapeRecord {
+            bits,
+            num_lose2,
+            elapsed: Duration::ZERO,
+        })
+        .collect()
+}
+
+/// Saves the catalogue of shapes as a CSV file.
+///
+/// Each row describes a canonical shape with
+/// - its size of the orbit under the symmetries,
+/// - the number of boss pairs and those distinct under the symmetries,
+/// - the number of boards of lose in 2 found for it.
+fn save_catalogue(path: &Path, records: &[ShapeRecord]) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+    writeln!(
+        writer,
+        "shape,orbit_size,boss_pairs,distinct_boss_pairs,lose2,seconds"
+    )?;
+    for record in records.iter().sorted_unstable_by_key(|record| record.bits) {
+        let bits = record.bits;
+        let (_, not_surrounded) = decompose_surrounded(bits);
+        let num_not_surrounded = not_surrounded.count_ones() as usize;
+        writeln!(
+            writer,
+            "{:0>16b},{},{},{},{},{:.3}",
+            bits,
+            8 / find_symmetries(bits).len(),
+            num_not_surrounded * num_not_surrounded.saturating_sub(1),
+            estimate_cost(bits),
+            record.num_lose2,
+            record.elapsed.as_secs_f64()
+        )?;
+    }
+    writer.flush()
 }
 
 // ****************************************************************
 //  Main
 // ****************************************************************
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() < 4 {
-        panic!("give more than 3 arguments");
-    }
-    let num_doves: usize = args[1].parse()?;
-    let num_thread: usize = args[2].parse()?;
-    let path = std::path::Path::new(args[3].as_str());
-    let rule = GameRule::new(true);
+#[derive(clap::Parser)]
+#[clap(
+    name = "Tokyodoves Full Searcher",
+    author = "Smooth Pudding",
+    version = "v0.1.0",
+    about = "Find all boards of lose in 2 by searching forward"
+)]
+struct Args {
+    /// Minimum number of doves on the field
+    #[clap(long, default_value_t = 2)]
+    min_doves: usize,
+
+    /// Maximum number of doves on the field
+    #[clap(long, default_value_t = 12)]
+    max_doves: usize,
+
+    /// Number of threads [default: available parallelism]
+    #[clap(short = 't', long)]
+    num_threads: Option<usize>,
+
+    #[clap(short = 'o', long, default_value = "./full_search_output")]
+    output_dir: PathBuf,
+
+    /// Name of output files, where `{}` is replaced by the 2-digit number of doves
+    #[clap(long, default_value = "{}.tdl")]
+    file_name: String,
+
+    /// Directory of checkpoints [default: <OUTPUT_DIR>/checkpoint]
+    #[clap(long)]
+    checkpoint_dir: Option<PathBuf>,
+
+    /// Resume interrupted searches and skip numbers of doves already saved
+    #[clap(long)]
+    resume: bool,
+
+    /// Export the catalogue of shapes next to each output as a CSV file
+    #[clap(long)]
+    catalogue: bool,
+
+    /// Rule of the game, which is recorded in the output directory
+    #[clap(long, value_enum, default_value_t = RuleVariant::Remove)]
+    rule: RuleVariant,
+}
 
-    let fs = std::fs::File::create(path)?;
+/// Boards of lose in 2 are the second step of the backward analysis
+const LOSE2_STEP: usize = 2;
+
+fn run_one(
+    num_doves: usize,
+    rule: RuleVariant,
+    num_thread: usize,
+    path: &Path,
+    checkpoint_dir: &Path,
+    resume: bool,
+    catalogue: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if resume && path.exists() && !checkpoint_dir.exists() {
+        println!("Skipped: {path:?} already exists");
+        let catalogue_path = path.with_extension("csv");
+        if catalogue && !catalogue_path.exists() {
+            let lose2_set = BoardSet::new_from_file(path)?;
+            save_catalogue(&catalogue_path, &records_of(&lose2_set, num_doves))?;
+            println!("Saved catalogue to {:?}", catalogue_path);
+        }
+        return Ok(());
+    }
 
-    let lose2_set = find_all_lose2(num_doves, rule, num_thread);
+    let checkpoint = Arc::new(Checkpoint::open(checkpoint_dir, resume)?);
+    let (lose2_set, records) = find_all_lose2(
+        num_doves,
+        rule.to_game_rule(),
+        num_thread,
+        Arc::clone(&checkpoint),
+    )?;
 
     // *** SAVE ***
-    lose2_set.save(fs)?;
+    storage::save_set(&lose2_set, path)?;
+    Manifest::describe_imported(path, LOSE2_STEP, num_doves, rule, lose2_set.len())?.save(path)?;
     println!("Saved to {:?}", path);
+    if catalogue {
+        let catalogue_path = path.with_extension("csv");
+        save_catalogue(&catalogue_path, &records)?;
+        println!("Saved catalogue to {:?}", catalogue_path);
+    }
+
+    Arc::into_inner(checkpoint)
+        .expect("all threads are joined")
+        .remove()?;
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    use clap::Parser;
+
+    let args = Args::parse();
+    if !(2..=12).contains(&args.min_doves) || !(args.min_doves..=12).contains(&args.max_doves) {
+        return Err("numbers of doves must satisfy 2 <= min <= max <= 12".into());
+    }
+    if !args.file_name.contains("{}") {
+        return Err("file name must contain `{}`".into());
+    }
+    let num_thread = match args.num_threads {
+        Some(0) => return Err("number of threads must be positive".into()),
+        Some(n) => n,
+        None => thread::available_parallelism()?.get(),
+    };
+    let checkpoint_root = args
+        .checkpoint_dir
+        .unwrap_or_else(|| args.output_dir.join("checkpoint"));
+    args.rule.record(&args.output_dir)?;
+
+    let start_all = Instant::now();
+    let mut elapsed_all = Vec::new();
+    for num_doves in args.min_doves..=args.max_doves {
+        println!("Start: #doves={num_doves}");
+        let start = Instant::now();
+        let file_name = args.file_name.replace("{}", &format!("{num_doves:0>2}"));
+        run_one(
+            num_doves,
+            args.rule,
+            num_thread,
+            &args.output_dir.join(file_name),
+            &checkpoint_root.join(format!("{num_doves:0>2}")),
+            args.resume,
+            args.catalogue,
+        )?;
+        let elapsed = start.elapsed();
+        println!(
+            "Finished: #doves={num_doves} ({:.3}s)",
+            elapsed.as_secs_f64()
+        );
+        elapsed_all.push((num_doves, elapsed));
+    }
+
+    if checkpoint_root.exists() && std::fs::read_dir(&checkpoint_root)?.next().is_none() {
+        std::fs::remove_dir(&checkpoint_root)?;
+    }
+
+    println!("All Finished");
+    for (num_doves, elapsed) in elapsed_all {
+        println!("#doves={num_doves:>2}: {:.3}s", elapsed.as_secs_f64());
+    }
+    println!("Total: {:.3}s", start_all.elapsed().as_secs_f64());
     Ok(())
 }
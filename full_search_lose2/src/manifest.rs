@@ -0,0 +1,276 @@
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::rule::{Namespace, RuleVariant};
+use crate::storage;
+
+/// Version of the manifest format
+pub const MANIFEST_FORMAT: u32 = 1;
+
+/// Version of tokyodoves which defines the hashes of boards.
+/// Every crate pins exactly this version in `Cargo.toml`.
+pub const TOKYODOVES_VERSION: &str = "0.1.7";
+
+/// Boards are saved as invariant hashes seen from the player to move next
+pub const PERSPECTIVE: &str = "next-player";
+
+/// Kinds of tables, which alternate step by step
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TableKind {
+    Win,
+    Lose,
+}
+
+impl TableKind {
+    pub fn of_step(num_step: usize) -> Self {
+        match num_step % 2 {
+            0 => Self::Lose,
+            _ => Self::Win,
+        }
+    }
+}
+
+/// Metadata of a table file, saved next to it as `NN.manifest.toml`.
+///
+/// A table is never loaded unless its manifest agrees with what the loader expects.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Manifest {
+    pub format: u32,
+    pub step: usize,
+    pub kind: TableKind,
+    pub num_doves: usize,
+    pub rule: String,
+    /// `None` for tables imported from the full search
+    pub namespace: Option<String>,
+    pub perspective: String,
+    pub tokyodoves_version: String,
+    pub run_id: String,
+    pub num_boards: usize,
+    pub checksum: String,
+}
+
+impl Manifest {
+    pub fn path_of(table: impl AsRef<Path>) -> PathBuf {
+        table.as_ref().with_extension("manifest.toml")
+    }
+
+    /// Describes `table`, which must be already saved, as an output of this run.
+    pub fn describe(
+        table: impl AsRef<Path>,
+        num_step: usize,
+        num_doves: usize,
+        namespace: Namespace,
+        num_boards: usize,
+    ) -> std::io::Result<Self> {
+        let mut manifest =
+            Self::describe_imported(table, num_step, num_doves, namespace.rule, num_boards)?;
+        manifest.namespace = Some(namespace.to_string());
+        Ok(manifest)
+    }
+
+    /// Describes `table`, which must be already saved, as a table without a namespace
+    /// such as those of the full search.
+    pub fn describe_imported(
+        table: impl AsRef<Path>,
+        num_step: usize,
+        num_doves: usize,
+        rule: RuleVariant,
+        num_boards: usize,
+    ) -> std::io::Result<Self> {
+        Ok(Self {
+            format: MANIFEST_FORMAT,
+            step: num_step,
+            kind: TableKind::of_step(num_step),
+            num_doves,
+            rule: rule.name().to_owned(),
+            namespace: None,
+            perspective: PERSPECTIVE.to_owned(),
+            tokyodoves_version: TOKYODOVES_VERSION.to_owned(),
+            run_id: run_id().to_owned(),
+            num_boards,
+            checksum: storage::recorded_checksum(table)?,
+        })
+    }
+
+    pub fn save(&self, table: impl AsRef<Path>) -> anyhow::Result<()> {
+        let text = toml::to_string(self)?;
+        storage::write_atomic(Self::path_of(table), text)?;
+        Ok(())
+    }
+
+    pub fn load(table: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = Self::path_of(table);
+        let text = std::fs::read_to_string(&path)
+            .map_err(|e| anyhow::anyhow!("cannot read manifest {path:?}: {e}"))?;
+        toml::from_str(&text).map_err(|e| anyhow::anyhow!("invalid manifest {path:?}: {e}"))
+    }
+
+    /// Checks that `table` is the one expected and unchanged since its manifest is saved.
+    ///
+    /// `namespace` is not checked if it is `None`,
+    /// which is the case for tables imported from the full search.
+    pub fn verify(
+        table: impl AsRef<Path>,
+        num_step: usize,
+        num_doves: usize,
+        rule: RuleVariant,
+        namespace: Option<Namespace>,
+    ) -> anyhow::Result<Self> {
+        let table = table.as_ref();
+        let manifest = Self::load(table)?;
+
+        let mut mismatches = Vec::new();
+        let mut expect = |name: &str, found: String, expected: String| {
+            if found != expected {
+                mismatches.push(format!("{name}: found {found}, expected {expected}"));
+            }
+        };
+        expect(
+            "format",
+            manifest.format.to_string(),
+            MANIFEST_FORMAT.to_string(),
+        );
+        expect("step", manifest.step.to_string(), num_step.to_string());
+        expect(
+            "kind",
+            format!("{:?}", manifest.kind),
+            format!("{:?}", TableKind::of_step(num_step)),
+        );
+        expect(
+            "num_doves",
+            manifest.num_doves.to_string(),
+            num_doves.to_string(),
+        );
+        expect("rule", manifest.rule.clone(), rule.name().to_owned());
+        if let Some(namespace) = namespace {
+            expect(
+                "namespace",
+                manifest.namespace.clone().unwrap_or_default(),
+                namespace.to_string(),
+            );
+        }
+        expect(
+            "perspective",
+            manifest.perspective.clone(),
+            PERSPECTIVE.to_owned(),
+        );
+        expect(
+            "tokyodoves_version",
+            manifest.tokyodoves_version.clone(),
+            TOKYODOVES_VERSION.to_owned(),
+        );
+        if !mismatches.is_empty() {
+            return Err(anyhow::anyhow!(
+                "{table:?} is not the table expected:\n  {}",
+                mismatches.join("\n  ")
+            ));
+        }
+
+        storage::verify(table)?;
+        if storage::recorded_checksum(table)? != manifest.checksum {
+            return Err(anyhow::anyhow!(
+                "{table:?} is modified after its manifest is saved"
+            ));
+        }
+        Ok(manifest)
+    }
+}
+
+/// Identifier of this run, shared by all manifests saved in it
+pub fn run_id() -> &'static str {
+    static RUN_ID: OnceLock<String> = OnceLock::new();
+    RUN_ID.get_or_init(|| {
+        let secs = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        format!("{secs:x}-{:x}", std::process::id())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rule::ALGORITHM_VERSION;
+    use crate::testing::{sample_set, TempDir};
+
+    fn save_table(dir: &TempDir, num_step: usize, namespace: Option<Namespace>) -> PathBuf {
+        let path = dir.join("05.tdl");
+        let set = sample_set();
+        storage::save_set(&set, &path).unwrap();
+        let manifest = match namespace {
+            Some(namespace) => Manifest::describe(&path, num_step, 5, namespace, set.len()),
+            None => Manifest::describe_imported(&path, num_step, 5, RuleVariant::Remove, set.len()),
+        };
+        manifest.unwrap().save(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn saved_manifest_is_verified() {
+        let dir = TempDir::new();
+        let namespace = Namespace::new(RuleVariant::Remove);
+        let path = save_table(&dir, 3, Some(namespace));
+
+        let manifest = Manifest::verify(&path, 3, 5, RuleVariant::Remove, Some(namespace)).unwrap();
+        assert_eq!(manifest, Manifest::load(&path).unwrap());
+        assert_eq!(manifest.kind, TableKind::Win);
+        assert_eq!(manifest.namespace, Some(namespace.to_string()));
+        assert_eq!(manifest.num_boards, sample_set().len());
+    }
+
+    #[test]
+    fn imported_table_has_no_namespace() {
+        let dir = TempDir::new();
+        let path = save_table(&dir, 2, None);
+
+        let manifest = Manifest::verify(&path, 2, 5, RuleVariant::Remove, None).unwrap();
+        assert_eq!(manifest.namespace, None);
+        assert_eq!(manifest.kind, TableKind::Lose);
+        let namespace = Some(Namespace::new(RuleVariant::Remove));
+        assert!(Manifest::verify(&path, 2, 5, RuleVariant::Remove, namespace).is_err());
+    }
+
+    #[test]
+    fn unexpected_table_is_rejected() {
+        let dir = TempDir::new();
+        let namespace = Namespace::new(RuleVariant::Remove);
+        let path = save_table(&dir, 3, Some(namespace));
+
+        assert!(Manifest::verify(&path, 5, 5, RuleVariant::Remove, Some(namespace)).is_err());
+        assert!(Manifest::verify(&path, 3, 6, RuleVariant::Remove, Some(namespace)).is_err());
+        assert!(Manifest::verify(&path, 3, 5, RuleVariant::NoRemove, None).is_err());
+        let other = Namespace {
+            version: ALGORITHM_VERSION + 1,
+            ..namespace
+        };
+        assert!(Manifest::verify(&path, 3, 5, RuleVariant::Remove, Some(other)).is_err());
+    }
+
+    #[test]
+    fn table_replaced_after_manifest_is_rejected() {
+        let dir = TempDir::new();
+        let namespace = Namespace::new(RuleVariant::Remove);
+        let path = save_table(&dir, 3, Some(namespace));
+
+        // Saved consistently with its own checksum, but not the table described
+        storage::save_set(&tokyodoves::collections::BoardSet::new(), &path).unwrap();
+        assert!(Manifest::verify(&path, 3, 5, RuleVariant::Remove, Some(namespace)).is_err());
+    }
+
+    #[test]
+    fn namespace_is_recorded_and_checked() {
+        let dir = TempDir::new();
+        let namespace = Namespace::new(RuleVariant::Remove);
+        let step_dir = dir.join("0003");
+
+        std::fs::create_dir_all(&step_dir).unwrap();
+        assert!(namespace.check(&step_dir).is_err());
+        namespace.record(&step_dir).unwrap();
+        namespace.check(&step_dir).unwrap();
+        assert!(Namespace::new(RuleVariant::NoRemove)
+            .check(&step_dir)
+            .is_err());
+    }
+}
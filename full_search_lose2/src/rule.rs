@@ -0,0 +1,140 @@
+use std::path::Path;
+use tokyodoves::game::GameRule;
+
+use crate::storage;
+
+/// Name of the file recording the rule which the data in a directory is built with
+pub const RULE_FILE_NAME: &str = "rule.txt";
+
+/// Version of the algorithm, which is bumped whenever a change may alter the results
+pub const ALGORITHM_VERSION: u32 = 1;
+
+/// Name of the file recording the namespace of a step directory
+pub const NAMESPACE_FILE_NAME: &str = "namespace.txt";
+
+/// Variants of the rule of Tokyodoves
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, clap::ValueEnum)]
+pub enum RuleVariant {
+    /// Standard rule, where a dove can be removed from the field
+    Remove,
+    /// Variant where no dove can be removed from the field
+    NoRemove,
+}
+
+impl RuleVariant {
+    pub fn to_game_rule(self) -> GameRule {
+        GameRule::new(matches!(self, Self::Remove))
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Remove => "remove",
+            Self::NoRemove => "no-remove",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "remove" => Some(Self::Remove),
+            "no-remove" => Some(Self::NoRemove),
+            _ => None,
+        }
+    }
+
+    /// Returns the rule recorded in `dir`.
+    ///
+    /// A directory with `.tdl` files but without the record is made
+    /// before the rule became configurable, so it is regarded as `Remove`.
+    /// `None` is returned if `dir` has neither of them.
+    pub fn recorded_in(dir: impl AsRef<Path>) -> std::io::Result<Option<Self>> {
+        let dir = dir.as_ref();
+        let path = dir.join(RULE_FILE_NAME);
+        if path.exists() {
+            let text = std::fs::read_to_string(&path)?;
+            return Self::from_name(text.trim()).map(Some).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("unknown rule {:?} in {path:?}", text.trim()),
+                )
+            });
+        }
+        if !dir.is_dir() {
+            return Ok(None);
+        }
+        for entry in std::fs::read_dir(dir)? {
+            if entry?.path().extension().is_some_and(|ext| ext == "tdl") {
+                return Ok(Some(Self::Remove));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Checks that the data in `dir` is built with `self`.
+    pub fn check(self, dir: impl AsRef<Path>) -> std::io::Result<()> {
+        let dir = dir.as_ref();
+        match Self::recorded_in(dir)? {
+            Some(recorded) if recorded != self => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "{dir:?} is built with rule `{}`, not `{}`",
+                    recorded.name(),
+                    self.name()
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks `dir` like `check` and records `self` in it.
+    pub fn record(self, dir: impl AsRef<Path>) -> std::io::Result<()> {
+        let dir = dir.as_ref();
+        self.check(dir)?;
+        std::fs::create_dir_all(dir)?;
+        std::fs::write(dir.join(RULE_FILE_NAME), format!("{}\n", self.name()))
+    }
+}
+
+/// Set of conditions under which tables are computed.
+///
+/// Tables in different namespaces are stored in different directories
+/// and never used together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Namespace {
+    pub rule: RuleVariant,
+    pub version: u32,
+}
+
+impl Namespace {
+    pub fn new(rule: RuleVariant) -> Self {
+        Self {
+            rule,
+            version: ALGORITHM_VERSION,
+        }
+    }
+
+    /// Records `self` in the directory of a finished step.
+    pub fn record(self, dir: impl AsRef<Path>) -> std::io::Result<()> {
+        storage::write_atomic(dir.as_ref().join(NAMESPACE_FILE_NAME), format!("{self}\n"))
+    }
+
+    /// Checks that the step in `dir` is finished in the namespace `self`.
+    pub fn check(self, dir: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = dir.as_ref().join(NAMESPACE_FILE_NAME);
+        let recorded = std::fs::read_to_string(&path)
+            .map_err(|e| anyhow::anyhow!("cannot read namespace {path:?}: {e}"))?;
+        if recorded.trim() != self.to_string() {
+            return Err(anyhow::anyhow!(
+                "{:?} belongs to namespace `{}`, not `{self}`",
+                dir.as_ref(),
+                recorded.trim()
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Namespace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-v{}", self.rule.name(), self.version)
+    }
+}
@@ -0,0 +1,152 @@
+//! Search of boards of lose in 2 shape by shape, which is the second step of the backward analysis.
+
+use itertools::{iproduct, Itertools};
+use std::collections::HashSet;
+use tokyodoves::strum::IntoEnumIterator;
+use tokyodoves::{analysis::*, collections::*, game::*, *};
+
+use crate::bitboard::*;
+
+/// Whether no move of the boss of `player` leaves it two or more liberties,
+/// which is necessary for `player` to lose in 2 and depends only on the squares occupied.
+pub fn boss_may_die(board: &Board, player: Color) -> bool {
+    for action in board.legal_actions(player, false, true, false) {
+        if !matches!(action, Action::Move(_, Dove::B, _)) {
+            continue;
+        }
+        let b = board.perform_unchecked_copied(action);
+        if b.liberty_of_boss(player) >= 2 {
+            return false;
+        }
+    }
+    true
+}
+
+/// Returns pairs of positions of red and green bosses.
+///
+/// Boss pairs mapped onto each other by a symmetry of the shape
+/// yield the same invariant hashes, so only the smallest one is returned.
+pub fn distinct_boss_pairs(
+    not_surrounded: u16,
+    symmetries: &[[usize; 16]],
+) -> impl Iterator<Item = (u16, u16)> + '_ {
+    HotBitIter::new(not_surrounded)
+        .permutations(2)
+        .map(|bosses| (bosses[0], bosses[1]))
+        .filter(|&(rb, gb)| {
+            symmetries
+                .iter()
+                .all(|perm| (map_bits(rb, perm), map_bits(gb, perm)) >= (rb, gb))
+        })
+}
+
+/// Estimates the cost of `pack_lose2` by the number of boss pairs to be evaluated.
+pub fn estimate_cost(bits: u16) -> usize {
+    let (_, not_surrounded) = decompose_surrounded(bits);
+    distinct_boss_pairs(not_surrounded, &find_symmetries(bits)).count()
+}
+
+/// Inserts invariant hashes of boards of lose in 2 with red to move on the shape `bits` into `pool`.
+pub fn pack_lose2(pool: &mut BoardSet, bits: u16, rule: GameRule) {
+    fn _color_to_index(color: Color) -> usize {
+        use Color::*;
+        match color {
+            Red => 0,
+            Green => 1,
+        }
+    }
+
+    fn _dove_to_index(dove: Dove) -> usize {
+        use Dove::*;
+        match dove {
+            B => 0,
+            A => 1,
+            Y => 2,
+            M => 3,
+            T => 4,
+            H => 5,
+        }
+    }
+
+    let lose2 = BoardValue::lose(2).unwrap();
+    let (_, not_surrounded) = decompose_surrounded(bits);
+    let num_res = bits.count_ones() as usize - 2;
+    let symmetries = find_symmetries(bits);
+    for (rb, gb) in distinct_boss_pairs(not_surrounded, &symmetries) {
+        let pair_symmetries: Vec<&[usize; 16]> = symmetries
+            .iter()
+            .filter(|perm| map_bits(rb, perm) == rb && map_bits(gb, perm) == gb)
+            .collect();
+
+        let positions_base = [[rb, 0, 0, 0, 0, 0], [gb, 0, 0, 0, 0, 0]];
+        let others: Vec<u16> = HotBitIter::new(bits & !(rb | gb)).collect();
+
+        let mut needs_to_check_necessary_condition = true;
+        for cd in iproduct!(Color::iter(), Dove::iter().skip(1)).permutations(num_res) {
+            let mut positions = positions_base;
+            for ((c, d), &pos) in cd.into_iter().zip(others.iter()) {
+                let ic = _color_to_index(c);
+                let id = _dove_to_index(d);
+                positions[ic][id] = pos;
+            }
+
+            let board = BoardBuilder::from_u16_bits(positions).build_unchecked();
+            if needs_to_check_necessary_condition {
+                if boss_may_die(&board, Color::Red) {
+                    needs_to_check_necessary_condition = false;
+                } else {
+                    break;
+                }
+            }
+
+            // Skip placements which are symmetric images of a smaller one
+            if pair_symmetries
+                .iter()
+                .any(|perm| map_positions(&positions, perm) < positions)
+            {
+                continue;
+            }
+
+            if matches!(
+                compare_board_value(board, lose2, Color::Red, rule),
+                Ok(std::cmp::Ordering::Equal)
+            ) {
+                pool.raw_mut().insert(board.to_invariant_u64(Color::Red));
+            }
+        }
+    }
+}
+
+/// Maps positions of all doves by `perm`.
+pub fn map_positions(positions: &[[u16; 6]; 2], perm: &[usize; 16]) -> [[u16; 6]; 2] {
+    let mut mapped = [[0; 6]; 2];
+    for (mapped_c, positions_c) in mapped.iter_mut().zip(positions.iter()) {
+        for (m, &p) in mapped_c.iter_mut().zip(positions_c.iter()) {
+            *m = map_bits(p, perm);
+        }
+    }
+    mapped
+}
+
+/// Returns canonical shapes of `num_doves` squares where no square is isolated.
+pub fn find_all_bits(num_doves: usize) -> HashSet<u16> {
+    let mut all_bits = HashSet::new();
+    for v_idx in (0..16).combinations(num_doves) {
+        let bits = v_idx.into_iter().fold(0, |bits, n| bits | (1 << n));
+        if is_isolated(bits) {
+            continue;
+        }
+        all_bits.insert(canonicalize(bits));
+    }
+    all_bits
+}
+
+/// Finds all boards of lose in 2 with `num_doves` doves in a single thread,
+/// which the full search does in parallel with checkpoints.
+pub fn find_lose2(num_doves: usize, rule: GameRule) -> BoardSet {
+    let mut pool = BoardSet::new();
+    for bits in find_all_bits(num_doves) {
+        pack_lose2(&mut pool, bits, rule);
+    }
+    pool
+}
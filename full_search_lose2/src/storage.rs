@@ -0,0 +1,301 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use tokyodoves::collections::*;
+
+// **********************************************************
+//  Checksum
+// **********************************************************
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// FNV-1a hash of a stream of bytes
+#[derive(Debug, Clone, Copy)]
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Self(FNV_OFFSET)
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    fn finish(self) -> String {
+        format!("fnv1a64:{:016x}", self.0)
+    }
+}
+
+/// Writer computing the checksum of bytes written through it
+struct ChecksumWriter<W> {
+    inner: W,
+    hasher: Fnv1a,
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Computes the checksum of the contents of a file.
+pub fn checksum(path: impl AsRef<Path>) -> std::io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut buf = vec![0; 1 << 20];
+    let mut hasher = Fnv1a::new();
+    loop {
+        let len = file.read(&mut buf)?;
+        if len == 0 {
+            return Ok(hasher.finish());
+        }
+        hasher.update(&buf[..len]);
+    }
+}
+
+/// Path of the file recording the checksum of `path`
+pub fn sum_path(path: impl AsRef<Path>) -> PathBuf {
+    let mut name = path.as_ref().as_os_str().to_owned();
+    name.push(".sum");
+    PathBuf::from(name)
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
+// **********************************************************
+//  Writing
+// **********************************************************
+/// Writes a file under a temporary name, fsyncs it and renames it,
+/// so that `path` never has partial contents.
+/// The temporary file is removed if writing fails.
+/// The checksum of the contents is returned.
+fn write_atomic_with<F>(path: &Path, write: F) -> std::io::Result<String>
+where
+    F: FnOnce(&mut ChecksumWriter<&File>) -> std::io::Result<()>,
+{
+    let tmp = tmp_path(path);
+    let result = (|| {
+        let file = File::create(&tmp)?;
+        let mut writer = ChecksumWriter {
+            inner: &file,
+            hasher: Fnv1a::new(),
+        };
+        write(&mut writer)?;
+        writer.flush()?;
+        let sum = writer.hasher.finish();
+        file.sync_all()?;
+        drop(file);
+        std::fs::rename(&tmp, path)?;
+        Ok(sum)
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// Writes small contents such as a manifest atomically.
+pub fn write_atomic(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> std::io::Result<()> {
+    write_atomic_with(path.as_ref(), |w| w.write_all(contents.as_ref()))?;
+    Ok(())
+}
+
+/// Records the checksum of `path` after the file itself is in place.
+/// A crash in between leaves a file without its checksum, which readers refuse.
+fn record_checksum(path: &Path, sum: &str) -> std::io::Result<()> {
+    forget_verified(path);
+    write_atomic(sum_path(path), format!("{sum}\n"))
+}
+
+/// Saves a file written by `write` atomically and records its checksum.
+pub fn save_with<F>(path: impl AsRef<Path>, write: F) -> std::io::Result<()>
+where
+    F: FnOnce(&mut dyn Write) -> std::io::Result<()>,
+{
+    let path = path.as_ref();
+    forget_verified(path);
+    let sum = write_atomic_with(path, |w| write(w))?;
+    record_checksum(path, &sum)
+}
+
+/// Saves `set` atomically and records its checksum.
+pub fn save_set(set: &BoardSet, path: impl AsRef<Path>) -> std::io::Result<()> {
+    save_with(path, |w| set.save(w))
+}
+
+/// Copies a verified file atomically together with its checksum.
+pub fn copy(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> std::io::Result<()> {
+    let (src, dst) = (src.as_ref(), dst.as_ref());
+    let mut reader = open_verified(src)?;
+    forget_verified(dst);
+    let sum = write_atomic_with(dst, |w| std::io::copy(&mut reader, w).map(|_| ()))?;
+    record_checksum(dst, &sum)
+}
+
+/// Removes a file together with its checksum, which is not an error if they do not exist.
+pub fn remove(path: impl AsRef<Path>) -> std::io::Result<()> {
+    let path = path.as_ref();
+    forget_verified(path);
+    for path in [path.to_owned(), sum_path(path)] {
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// **********************************************************
+//  Reading
+// **********************************************************
+/// Paths verified in this process, which are not verified again
+fn verified() -> &'static Mutex<HashSet<PathBuf>> {
+    static VERIFIED: OnceLock<Mutex<HashSet<PathBuf>>> = OnceLock::new();
+    VERIFIED.get_or_init(Default::default)
+}
+
+fn forget_verified(path: &Path) {
+    verified().lock().unwrap().remove(path);
+}
+
+/// Returns the recorded checksum of `path`.
+pub fn recorded_checksum(path: impl AsRef<Path>) -> std::io::Result<String> {
+    let sum_path = sum_path(path);
+    let text = std::fs::read_to_string(&sum_path).map_err(|e| {
+        std::io::Error::new(e.kind(), format!("cannot read checksum {sum_path:?}: {e}"))
+    })?;
+    Ok(text.trim().to_owned())
+}
+
+/// Checks that the contents of `path` agree with its recorded checksum.
+pub fn verify(path: impl AsRef<Path>) -> std::io::Result<()> {
+    let path = path.as_ref();
+    if verified().lock().unwrap().contains(path) {
+        return Ok(());
+    }
+    let recorded = recorded_checksum(path)?;
+    let actual = checksum(path)?;
+    if actual != recorded {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("checksum of {path:?} is {actual}, but {recorded} is recorded"),
+        ));
+    }
+    verified().lock().unwrap().insert(path.to_owned());
+    Ok(())
+}
+
+/// Opens `path` after verifying its checksum.
+pub fn open_verified(path: impl AsRef<Path>) -> std::io::Result<File> {
+    verify(&path)?;
+    File::open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{sample_set, TempDir};
+
+    fn load(path: &Path) -> std::io::Result<BoardSet> {
+        let mut set = BoardSet::new();
+        set.load(open_verified(path)?)?;
+        Ok(set)
+    }
+
+    #[test]
+    fn saved_set_is_loaded_after_verification() {
+        let dir = TempDir::new();
+        let path = dir.join("set.tdl");
+        let set = sample_set();
+        save_set(&set, &path).unwrap();
+
+        assert_eq!(recorded_checksum(&path).unwrap(), checksum(&path).unwrap());
+        let loaded = load(&path).unwrap();
+        assert_eq!(loaded.len(), set.len());
+        assert!(set.iter().all(|board| loaded.contains(&board)));
+        assert!(!tmp_path(&path).exists());
+    }
+
+    #[test]
+    fn copy_keeps_contents_and_checksum() {
+        let dir = TempDir::new();
+        let (src, dst) = (dir.join("src.tdl"), dir.join("dst.tdl"));
+        save_set(&sample_set(), &src).unwrap();
+        copy(&src, &dst).unwrap();
+
+        assert_eq!(std::fs::read(&src).unwrap(), std::fs::read(&dst).unwrap());
+        assert_eq!(recorded_checksum(&dst).unwrap(), checksum(&src).unwrap());
+    }
+
+    #[test]
+    fn corrupted_payload_is_rejected() {
+        let dir = TempDir::new();
+        let path = dir.join("set.tdl");
+        save_set(&sample_set(), &path).unwrap();
+
+        let mut bytes = std::fs::read(&path).unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        std::fs::write(&path, bytes).unwrap();
+
+        let err = verify(&path).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(load(&path).is_err());
+    }
+
+    #[test]
+    fn corrupted_or_missing_checksum_is_rejected() {
+        let dir = TempDir::new();
+        let path = dir.join("set.tdl");
+        save_set(&sample_set(), &path).unwrap();
+
+        std::fs::write(sum_path(&path), "fnv1a64:0000000000000000\n").unwrap();
+        assert_eq!(
+            verify(&path).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+
+        std::fs::remove_file(sum_path(&path)).unwrap();
+        assert_eq!(
+            verify(&path).unwrap_err().kind(),
+            std::io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn failed_write_leaves_no_partial_file() {
+        let dir = TempDir::new();
+        let path = dir.join("set.tdl");
+        let fail = |w: &mut dyn Write| {
+            w.write_all(b"partial")?;
+            Err(std::io::Error::other("interrupted"))
+        };
+
+        assert!(save_with(&path, fail).is_err());
+        assert!(!path.exists());
+        assert!(!sum_path(&path).exists());
+        assert!(!tmp_path(&path).exists());
+
+        // A file already in place is kept together with its checksum
+        let set = sample_set();
+        save_set(&set, &path).unwrap();
+        let before = std::fs::read(&path).unwrap();
+        assert!(save_with(&path, fail).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+        assert!(!tmp_path(&path).exists());
+        assert_eq!(load(&path).unwrap().len(), set.len());
+    }
+}
@@ -0,0 +1,55 @@
+//! Helpers of tests, which other crates enable by the feature `testing`
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokyodoves::collections::BoardSet;
+use tokyodoves::{Board, BoardBuilder};
+
+/// Invariant hashes of some boards of lose in 2 with 6 doves
+pub const LOSE2_6: [u64; 3] = [0x0c530200040500bf, 0x0c530200040f00b5, 0x0cd40a00520f0400];
+
+/// Directory removed when dropped
+#[derive(Debug)]
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "tokyodoves-test-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join(&self, name: impl AsRef<Path>) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Default for TempDir {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A few boards to be saved
+pub fn sample_set() -> BoardSet {
+    let mut set = BoardSet::new();
+    set.insert(Board::new());
+    set.insert(BoardBuilder::from_str(" b;B; a").unwrap().build().unwrap());
+    set
+}